@@ -9,4 +9,10 @@ pub struct BasisGraph {
     pub name: String,
     pub description: String,
     pub structure: String,
+    /// Set when the document or its summary reads like instructions to the
+    /// LLM
+    #[serde(default)]
+    pub suspicious_content: bool,
+    #[serde(default)]
+    pub needs_review: bool,
 }
@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 
 use crate::prelude::*;
+use crate::config::{CONFIG};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BasisNetwork {
@@ -9,6 +10,16 @@ pub struct BasisNetwork {
     pub description: String,
     pub relationship: NetworkRelationship,
     pub subgraph_hash: String,
+    /// Share of the sampled LLM answers that agreed on the relationship
+    #[serde(default = "get_default_confidence")]
+    pub confidence: f64,
+    /// Set when too few samples agreed for the relationship to be trusted
+    #[serde(default)]
+    pub needs_review: bool,
+    /// Set when the fragments the relationship was decided from read like
+    /// instructions to the LLM
+    #[serde(default)]
+    pub suspicious_content: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -33,6 +44,25 @@ impl BasisNetwork {
             description: "Null network".to_string(),
             subgraph_hash: subgraph_hash.to_string().clone(),
             relationship: NetworkRelationship::Null,
+            confidence: get_default_confidence(),
+            needs_review: false,
+            suspicious_content: false,
+        }
+    }
+
+    pub fn with_confidence(self, confidence: f64) -> Self {
+        BasisNetwork {
+            confidence,
+            needs_review: confidence < read_lock!(CONFIG).llm.review_confidence_threshold,
+            ..self
+        }
+    }
+
+    pub fn with_suspicious_content(self, suspicious_content: bool) -> Self {
+        BasisNetwork {
+            needs_review: self.needs_review || suspicious_content,
+            suspicious_content,
+            ..self
         }
     }
 
@@ -40,3 +70,7 @@ impl BasisNetwork {
         matches!(self.relationship, NetworkRelationship::Null)
     }
 }
+
+fn get_default_confidence() -> f64 {
+    1.0
+}
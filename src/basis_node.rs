@@ -10,4 +10,10 @@ pub struct BasisNode {
     pub lineage: Lineage,
     pub description: String,
     pub transformations: Vec<FieldTransformation>,
+    /// Fields eliminated by a decision that needs review, kept so that a
+    /// reviewer can find them
+    #[serde(default)]
+    pub eliminations: Vec<FieldTransformation>,
+    #[serde(default)]
+    pub profile_id: Option<ID>,
 }
@@ -16,6 +16,53 @@ pub struct LlmConfig {
     pub llm_provider: LlmProvider,
     pub max_concurrency: usize,
     pub example_snippet_count: usize,
+    #[serde(default = "get_default_generate_profiles")]
+    pub generate_profiles: bool,
+    #[serde(default = "get_default_profile_sample_length")]
+    pub profile_sample_length: usize,
+    #[serde(default = "get_default_batch_field_decisions")]
+    pub batch_field_decisions: bool,
+    // Requests are only rate limited when a limit is set
+    #[serde(default)]
+    pub requests_per_minute: Option<u64>,
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>,
+    #[serde(default = "get_default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "get_default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "get_default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub max_cost: Option<f64>,
+    #[serde(default = "get_default_prompt_token_cost")]
+    pub prompt_token_cost: f64,
+    #[serde(default = "get_default_completion_token_cost")]
+    pub completion_token_cost: f64,
+    #[serde(default = "get_default_budget_behavior")]
+    pub budget_behavior: BudgetBehavior,
+    #[serde(default)]
+    pub prompt_template_dir: Option<String>,
+    #[serde(default = "get_default_vote_samples")]
+    pub vote_samples: usize,
+    #[serde(default = "get_default_vote_temperature")]
+    pub vote_temperature: f64,
+    #[serde(default = "get_default_review_confidence_threshold")]
+    pub review_confidence_threshold: f64,
+    #[serde(default = "get_default_summary_chunk_length")]
+    pub summary_chunk_length: usize,
+    #[serde(default = "get_default_summary_max_chunks")]
+    pub summary_max_chunks: usize,
+}
+
+/// What happens once the token or cost budget is used up. Either way no
+/// further requests are sent; degrading keeps the results obtained so far.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum BudgetBehavior {
+    Abort,
+    Degrade,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,11 +79,262 @@ impl Default for DevConfig {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    #[serde(default = "get_default_node_command")]
+    pub node_command: String,
+    #[serde(default = "get_default_python_command")]
+    pub python_command: String,
+    #[serde(default = "get_default_awk_command")]
+    pub awk_command: String,
+    #[serde(default = "get_default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "get_default_quickjs_time_limit_ms")]
+    pub quickjs_time_limit_ms: u64,
+    #[serde(default = "get_default_quickjs_memory_limit")]
+    pub quickjs_memory_limit: usize,
+    #[serde(default = "get_default_quickjs_stack_size")]
+    pub quickjs_stack_size: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            node_command: get_default_node_command(),
+            python_command: get_default_python_command(),
+            awk_command: get_default_awk_command(),
+            timeout_ms: get_default_timeout_ms(),
+            quickjs_time_limit_ms: get_default_quickjs_time_limit_ms(),
+            quickjs_memory_limit: get_default_quickjs_memory_limit(),
+            quickjs_stack_size: get_default_quickjs_stack_size(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SimilarityMeasure {
+    Jaccard,
+    Weighted,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchingConfig {
+    #[serde(default = "get_default_similarity_measure")]
+    pub similarity_measure: SimilarityMeasure,
+    #[serde(default)]
+    pub class_token_features: bool,
+    #[serde(default = "get_default_similarity_threshold")]
+    pub similarity_threshold: f64,
+    #[serde(default = "get_default_candidate_count")]
+    pub candidate_count: usize,
+    #[serde(default = "get_default_minhash_permutations")]
+    pub minhash_permutations: usize,
+    #[serde(default = "get_default_lsh_bands")]
+    pub lsh_bands: usize,
+    #[serde(default = "get_default_drift_threshold")]
+    pub drift_threshold: f64,
+    #[serde(default)]
+    pub migrate_basis_nodes: bool,
+    #[serde(default = "get_default_migration_minimum_ancestry")]
+    pub migration_minimum_ancestry: usize,
+    #[serde(default = "get_default_recursion_similarity_threshold")]
+    pub recursion_similarity_threshold: f64,
+}
+
+impl Default for MatchingConfig {
+    fn default() -> Self {
+        MatchingConfig {
+            similarity_measure: get_default_similarity_measure(),
+            class_token_features: false,
+            similarity_threshold: get_default_similarity_threshold(),
+            candidate_count: get_default_candidate_count(),
+            minhash_permutations: get_default_minhash_permutations(),
+            lsh_bands: get_default_lsh_bands(),
+            drift_threshold: get_default_drift_threshold(),
+            migrate_basis_nodes: false,
+            migration_minimum_ancestry: get_default_migration_minimum_ancestry(),
+            recursion_similarity_threshold: get_default_recursion_similarity_threshold(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnippetConfig {
+    #[serde(default = "get_default_snippet_token_budget")]
+    pub token_budget: usize,
+    #[serde(default = "get_default_snippet_max_ancestors")]
+    pub max_ancestors: usize,
+    #[serde(default = "get_default_diverse_examples")]
+    pub diverse_examples: bool,
+    #[serde(default = "get_default_fragment_token_budget")]
+    pub fragment_token_budget: usize,
+}
+
+impl Default for SnippetConfig {
+    fn default() -> Self {
+        SnippetConfig {
+            token_budget: get_default_snippet_token_budget(),
+            max_ancestors: get_default_snippet_max_ancestors(),
+            diverse_examples: get_default_diverse_examples(),
+            fragment_token_budget: get_default_fragment_token_budget(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub llm: LlmConfig,
     #[serde(default)]
     pub dev: DevConfig,
+    #[serde(default)]
+    pub runtimes: RuntimeConfig,
+    #[serde(default)]
+    pub matching: MatchingConfig,
+    #[serde(default)]
+    pub snippets: SnippetConfig,
+}
+
+fn get_default_generate_profiles() -> bool {
+    true
+}
+
+fn get_default_profile_sample_length() -> usize {
+    4000
+}
+
+fn get_default_batch_field_decisions() -> bool {
+    true
+}
+
+fn get_default_max_retries() -> u32 {
+    5
+}
+
+fn get_default_retry_base_delay_ms() -> u64 {
+    1000
+}
+
+fn get_default_retry_max_delay_ms() -> u64 {
+    60_000
+}
+
+// Dollars per million tokens
+fn get_default_prompt_token_cost() -> f64 {
+    2.5
+}
+
+fn get_default_completion_token_cost() -> f64 {
+    10.0
+}
+
+fn get_default_budget_behavior() -> BudgetBehavior {
+    BudgetBehavior::Abort
+}
+
+// A single sample decides on its own, without voting
+fn get_default_vote_samples() -> usize {
+    1
+}
+
+fn get_default_vote_temperature() -> f64 {
+    0.7
+}
+
+// Decisions that fewer than three in four samples agree on are flagged
+fn get_default_review_confidence_threshold() -> f64 {
+    0.75
+}
+
+// Characters of the condensed document summarized in one request
+fn get_default_summary_chunk_length() -> usize {
+    3000
+}
+
+fn get_default_summary_max_chunks() -> usize {
+    8
+}
+
+fn get_default_similarity_measure() -> SimilarityMeasure {
+    SimilarityMeasure::Weighted
+}
+
+fn get_default_similarity_threshold() -> f64 {
+    0.8
+}
+
+fn get_default_candidate_count() -> usize {
+    5
+}
+
+fn get_default_minhash_permutations() -> usize {
+    128
+}
+
+// 32 bands of 4 rows put most pairs above a similarity of 0.5 in a shared
+// bucket, leaving the configured threshold to be applied on exact similarity
+fn get_default_lsh_bands() -> usize {
+    32
+}
+
+fn get_default_drift_threshold() -> f64 {
+    0.5
+}
+
+// A node and its parent must be unchanged for a basis node to be migrated
+fn get_default_migration_minimum_ancestry() -> usize {
+    2
+}
+
+// A comment whose header and body repeat in a reply, but whose replies do
+// not, shares half of its child subgraphs with the reply
+fn get_default_recursion_similarity_threshold() -> f64 {
+    0.5
+}
+
+// Roughly the twenty nodes snippets used to be capped at
+fn get_default_snippet_token_budget() -> usize {
+    400
+}
+
+fn get_default_snippet_max_ancestors() -> usize {
+    3
+}
+
+fn get_default_diverse_examples() -> bool {
+    true
+}
+
+// Roughly the 2000 characters fragments used to be truncated to
+fn get_default_fragment_token_budget() -> usize {
+    500
+}
+
+fn get_default_node_command() -> String {
+    "node".to_string()
+}
+
+fn get_default_python_command() -> String {
+    "python3".to_string()
+}
+
+fn get_default_awk_command() -> String {
+    "awk".to_string()
+}
+
+fn get_default_timeout_ms() -> u64 {
+    5000
+}
+
+fn get_default_quickjs_time_limit_ms() -> u64 {
+    1000
+}
+
+fn get_default_quickjs_memory_limit() -> usize {
+    32 * 1024 * 1024
+}
+
+fn get_default_quickjs_stack_size() -> usize {
+    1024 * 1024
 }
 
 fn get_default_debug_dir() -> String {
@@ -54,8 +352,30 @@ impl Config {
                 llm_provider: LlmProvider::OpenAI,
                 max_concurrency: 1,
                 example_snippet_count: 3,
+                generate_profiles: get_default_generate_profiles(),
+                profile_sample_length: get_default_profile_sample_length(),
+                batch_field_decisions: get_default_batch_field_decisions(),
+                requests_per_minute: None,
+                tokens_per_minute: None,
+                max_retries: get_default_max_retries(),
+                retry_base_delay_ms: get_default_retry_base_delay_ms(),
+                retry_max_delay_ms: get_default_retry_max_delay_ms(),
+                max_tokens: None,
+                max_cost: None,
+                prompt_token_cost: get_default_prompt_token_cost(),
+                completion_token_cost: get_default_completion_token_cost(),
+                budget_behavior: get_default_budget_behavior(),
+                prompt_template_dir: None,
+                vote_samples: get_default_vote_samples(),
+                vote_temperature: get_default_vote_temperature(),
+                review_confidence_threshold: get_default_review_confidence_threshold(),
+                summary_chunk_length: get_default_summary_chunk_length(),
+                summary_max_chunks: get_default_summary_max_chunks(),
             },
             dev: DevConfig::default(),
+            runtimes: RuntimeConfig::default(),
+            matching: MatchingConfig::default(),
+            snippets: SnippetConfig::default(),
         }
     }
 
@@ -9,6 +9,8 @@ use crate::document_node::DocumentNode;
 use crate::meta_context::MetaContext;
 use crate::provider::Provider;
 use crate::json_node::JsonNode;
+use crate::config::{CONFIG};
+use crate::llm::estimate_tokens;
 
 pub type ContextID = ID;
 
@@ -46,15 +48,14 @@ impl Context {
 
             let context = contexts
                 .get(&read_lock!(current).id)
-                .unwrap()
+                .ok_or(Errors::ContextsNotProvided)?
                 .clone();
 
             let data_node = &context.data_node;
 
             let maybe_basis_node = {
                 let lock = read_lock!(meta_context);
-                lock.get_basis_node_by_lineage(&context.lineage)
-                    .expect("Could not get basis node by lineage")
+                lock.get_basis_node_by_lineage(&context.lineage)?
             };
 
             if let Some(basis_node) = maybe_basis_node {
@@ -62,9 +63,14 @@ impl Context {
                 let json_nodes: Vec<JsonNode> = basis_node.transformations
                     .clone()
                     .into_iter()
-                    .map(|transformation| {
-                        transformation.transform(Arc::clone(&data_node))
-                            .expect("Could not transform data node field")
+                    .filter_map(|transformation| {
+                        match transformation.transform(Arc::clone(data_node)) {
+                            Ok(json_node) => Some(json_node),
+                            Err(err) => {
+                                log::warn!("Could not transform data node field {}: {}", transformation.field, err);
+                                None
+                            }
+                        }
                     })
                     .collect();
 
@@ -91,25 +97,31 @@ impl Context {
         if result.is_empty() {
             Ok(String::new())
         } else {
-            let json_string = serde_json::to_string(&result).expect("Could not convert to json string");
+            let json_string = serde_json::to_string(&result)?;
             Ok(json_string)
         }
     }
 
-    pub fn generate_snippet(&self, meta_context: Arc<RwLock<MetaContext>>) -> String {
+    pub fn generate_snippet(&self, meta_context: Arc<RwLock<MetaContext>>) -> Result<String, Errors> {
         log::trace!("In generate_snippet");
 
-        let mut neighbour_ids = HashSet::new();
-        let graph_node = self.graph_node.clone();
-        
-        Self::traverse_for_neighbours(
-            Arc::clone(&graph_node),
-            &mut neighbour_ids
-        );
+        let (token_budget, max_ancestors) = {
+            let lock = read_lock!(CONFIG);
+            (lock.snippets.token_budget, lock.snippets.max_ancestors)
+        };
 
+        let graph_node = self.graph_node.clone();
         let mut snippet = String::new();
         let lock = read_lock!(meta_context);
-        let graph_root = lock.graph_root.clone().unwrap();
+        let graph_root = lock.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?;
+        let contexts = lock.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
+
+        let neighbour_ids = Self::select_neighbours(
+            contexts,
+            Arc::clone(&graph_node),
+            token_budget,
+            max_ancestors
+        )?;
 
         Self::traverse_for_snippet(
             Arc::clone(&meta_context),
@@ -117,9 +129,9 @@ impl Context {
             &neighbour_ids,
             &read_lock!(graph_node).id,
             &mut snippet
-        );
+        )?;
 
-        snippet
+        Ok(snippet)
     }
 
     fn traverse_for_snippet(
@@ -128,12 +140,12 @@ impl Context {
         neighbour_ids: &HashSet<GraphNodeID>,
         target_id: &GraphNodeID,
         snippet: &mut String,
-    ) {
+    ) -> Result<(), Errors> {
         let meta_context_lock = read_lock!(meta_context);
         let lock = read_lock!(current_node);
         let current_id = lock.id.clone();
-        let contexts = meta_context_lock.contexts.as_ref().unwrap();
-        let current_context = contexts.get(&current_id).unwrap();
+        let contexts = meta_context_lock.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
+        let current_context = contexts.get(&current_id).ok_or(Errors::ContextsNotProvided)?;
         let document_node = current_context.document_node.clone();
 
         let should_render = if current_id == *target_id {
@@ -160,7 +172,7 @@ impl Context {
                 neighbour_ids,
                 target_id,
                 snippet,
-            );
+            )?;
         }
 
         if should_render {
@@ -168,37 +180,170 @@ impl Context {
 
             snippet.push_str(b.as_deref().unwrap_or(""));
         }
+
+        Ok(())
     }
 
-    fn traverse_for_neighbours(
-        start_node: Arc<RwLock<GraphNode>>,
-        visited: &mut HashSet<GraphNodeID>,
-    ) {
-        let mut queue: VecDeque<Arc<RwLock<GraphNode>>> = VecDeque::new();
-        queue.push_back(Arc::clone(&start_node));
+    /// Chooses the nodes rendered around a target within a token budget.
+    /// The target is always included, then its nearest ancestors, then its
+    /// siblings nearest first along with their content. Whatever budget is
+    /// left goes to the siblings of each ancestor in turn, nearest first, and
+    /// then to the surrounding nodes breadth first.
+    fn select_neighbours(
+        contexts: &HashMap<ID, Arc<Context>>,
+        target_node: Arc<RwLock<GraphNode>>,
+        token_budget: usize,
+        max_ancestors: usize,
+    ) -> Result<HashSet<GraphNodeID>, Errors> {
+        let mut selected: HashSet<GraphNodeID> = HashSet::new();
+        let target_cost = Self::estimate_node_tokens(contexts, &target_node)?;
+        let mut remaining = token_budget.saturating_sub(target_cost);
+
+        selected.insert(read_lock!(target_node).id.clone());
+
+        let mut ancestors: Vec<Arc<RwLock<GraphNode>>> = Vec::new();
+        let mut ancestor = read_lock!(target_node).parents.first().cloned();
+
+        while let Some(node) = ancestor {
+            if ancestors.len() >= max_ancestors {
+                break;
+            }
 
-        while let Some(node) = queue.pop_front() {
-            let lock = read_lock!(node);
-            let graph_node_id = lock.id.clone();
+            ancestor = read_lock!(node).parents.first().cloned();
+            ancestors.push(node);
+        }
 
-            if visited.contains(&graph_node_id) {
-                continue;
+        for ancestor in ancestors.iter() {
+            Self::select_within_budget(contexts, ancestor, &mut selected, &mut remaining)?;
+        }
+
+        // Only the siblings that fit have their content looked at
+        let mut local_nodes = vec![Arc::clone(&target_node)];
+
+        for sibling in Self::get_nearest_siblings(&target_node) {
+            if Self::select_within_budget(contexts, &sibling, &mut selected, &mut remaining)? {
+                local_nodes.push(sibling);
             }
+        }
+
+        Self::select_breadth_first(contexts, local_nodes, false, token_budget, &mut selected, &mut remaining)?;
 
-            visited.insert(graph_node_id.clone());
+        for ancestor in ancestors.iter() {
+            for sibling in Self::get_nearest_siblings(ancestor) {
+                if remaining == 0 {
+                    break;
+                }
 
-            if visited.len() > 20 {
-                return;
+                if Self::select_within_budget(contexts, &sibling, &mut selected, &mut remaining)? {
+                    Self::select_breadth_first(contexts, vec![sibling], false, token_budget, &mut selected, &mut remaining)?;
+                }
             }
+        }
+
+        Self::select_breadth_first(contexts, vec![target_node], true, token_budget, &mut selected, &mut remaining)?;
+
+        Ok(selected)
+    }
+
+    /// The other children of a node's first parent, nearest to it first
+    fn get_nearest_siblings(node: &Arc<RwLock<GraphNode>>) -> Vec<Arc<RwLock<GraphNode>>> {
+        let parent = match read_lock!(node).parents.first() {
+            Some(parent) => Arc::clone(parent),
+            None => return Vec::new(),
+        };
+
+        let children = read_lock!(parent).children.clone();
+        let position = children
+            .iter()
+            .position(|child| Arc::ptr_eq(child, node))
+            .unwrap_or(0);
+
+        let mut siblings: Vec<(usize, Arc<RwLock<GraphNode>>)> = children
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| *index != position)
+            .map(|(index, sibling)| (index.abs_diff(position), sibling))
+            .collect();
+        siblings.sort_by_key(|(distance, _)| *distance);
+
+        siblings.into_iter().map(|(_, sibling)| sibling).collect()
+    }
 
-            for child in lock.children.iter() {
-                queue.push_back(Arc::clone(child));
+    fn select_breadth_first(
+        contexts: &HashMap<ID, Arc<Context>>,
+        start_nodes: Vec<Arc<RwLock<GraphNode>>>,
+        include_parents: bool,
+        token_budget: usize,
+        selected: &mut HashSet<GraphNodeID>,
+        remaining: &mut usize,
+    ) -> Result<(), Errors> {
+        let mut visited: HashSet<GraphNodeID> = HashSet::new();
+        let mut queue: VecDeque<Arc<RwLock<GraphNode>>> = start_nodes.into_iter().collect();
+
+        // Every node costs at least a token, so no more nodes than the budget
+        // need to be looked at
+        while let Some(node) = queue.pop_front() {
+            if *remaining == 0 || visited.len() > token_budget {
+                break;
             }
 
-            for parent in lock.parents.iter() {
-                queue.push_back(Arc::clone(parent));
+            let (graph_node_id, neighbours) = {
+                let lock = read_lock!(node);
+                let mut neighbours: Vec<_> = lock.children.clone();
+
+                if include_parents {
+                    neighbours.extend(lock.parents.iter().cloned());
+                }
+
+                (lock.id.clone(), neighbours)
+            };
+
+            if !visited.insert(graph_node_id) {
+                continue;
             }
+
+            Self::select_within_budget(contexts, &node, selected, remaining)?;
+
+            queue.extend(neighbours);
         }
+
+        Ok(())
+    }
+
+    fn select_within_budget(
+        contexts: &HashMap<ID, Arc<Context>>,
+        node: &Arc<RwLock<GraphNode>>,
+        selected: &mut HashSet<GraphNodeID>,
+        remaining: &mut usize,
+    ) -> Result<bool, Errors> {
+        let graph_node_id = read_lock!(node).id.clone();
+
+        if selected.contains(&graph_node_id) {
+            return Ok(true);
+        }
+
+        let cost = Self::estimate_node_tokens(contexts, node)?;
+
+        if cost > *remaining {
+            return Ok(false);
+        }
+
+        *remaining -= cost;
+        selected.insert(graph_node_id);
+
+        Ok(true)
+    }
+
+    /// Tokens a node adds to a snippet, leaving out its children
+    fn estimate_node_tokens(
+        contexts: &HashMap<ID, Arc<Context>>,
+        node: &Arc<RwLock<GraphNode>>,
+    ) -> Result<usize, Errors> {
+        let graph_node_id = read_lock!(node).id.clone();
+        let context = contexts.get(&graph_node_id).ok_or(Errors::ContextsNotProvided)?;
+        let (a, b) = read_lock!(context.document_node).to_string_components();
+
+        Ok(estimate_tokens(&a) + b.as_deref().map_or(0, estimate_tokens))
     }
 
     fn mark_text(text: &str) -> String {
@@ -16,11 +16,11 @@ pub struct ContextGroup {
 }
 
 impl ContextGroup {
-    pub fn from_meta_context(meta_context: Arc<RwLock<MetaContext>>) -> Vec<Self> {
+    pub fn from_meta_context(meta_context: Arc<RwLock<MetaContext>>) -> Result<Vec<Self>, Errors> {
         log::trace!("In from_meta_context");
 
         let lock = read_lock!(meta_context);
-        let contexts = lock.contexts.as_ref().unwrap();
+        let contexts = lock.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
 
         let mut context_groups: HashMap<Lineage, Vec<Arc<Context>>> = HashMap::new();
         let mut seen_context_ids: HashSet<ID> = HashSet::new();
@@ -34,25 +34,137 @@ impl ContextGroup {
             }
         }
 
-        let example_snippet_count = read_lock!(CONFIG).llm.example_snippet_count;
+        let (example_snippet_count, diverse_examples) = {
+            let lock = read_lock!(CONFIG);
+            (lock.llm.example_snippet_count, lock.snippets.diverse_examples)
+        };
 
         context_groups
             .into_iter()
             .map(|(lineage, contexts)| {
-                let fields = contexts.first().unwrap().data_node.fields.clone();
-                let snippets: Vec<String> = contexts
+                let fields = contexts.first().ok_or(Errors::ContextsNotProvided)?.data_node.fields.clone();
+                let examples = if diverse_examples {
+                    select_diverse_examples(&contexts, example_snippet_count)
+                } else {
+                    contexts.iter().take(example_snippet_count).cloned().collect()
+                };
+                let snippets: Vec<String> = examples
                     .iter()
-                    .take(example_snippet_count)
                     .map(|context| context.generate_snippet(Arc::clone(&meta_context)))
-                    .collect();
+                    .collect::<Result<_, _>>()?;
 
-                ContextGroup {
+                Ok(ContextGroup {
                     lineage,
                     fields,
                     contexts,
                     snippets
-                }
+                })
             })
             .collect()
     }
 }
+
+/// Picks examples one at a time, each time taking the context that shows the
+/// most field values not seen in the examples so far, so that the examples
+/// cover as much of the variation between values as they can
+fn select_diverse_examples(contexts: &[Arc<Context>], count: usize) -> Vec<Arc<Context>> {
+    let mut remaining: Vec<Arc<Context>> = contexts.to_vec();
+    let mut examples: Vec<Arc<Context>> = Vec::new();
+    let mut seen_values: HashSet<(String, String)> = HashSet::new();
+
+    while examples.len() < count && !remaining.is_empty() {
+        let (best, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(index, context)| {
+                let unseen = context.data_node.fields
+                    .iter()
+                    .filter(|(field, value)| !seen_values.contains(&((*field).clone(), (*value).clone())))
+                    .count();
+
+                (index, unseen)
+            })
+            .fold((0, 0), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+
+        let example = remaining.remove(best);
+
+        seen_values.extend(
+            example.data_node.fields
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+        );
+        examples.push(example);
+    }
+
+    examples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmltree::XMLNode;
+
+    use crate::data_node::DataNode;
+    use crate::document_node::DocumentNode;
+    use crate::graph_node::GraphNode;
+
+    fn context(fields: &[(&str, &str)]) -> Arc<Context> {
+        let data_node = Arc::new(DataNode {
+            id: ID::new(),
+            hash: Hash::new(),
+            lineage: Lineage::new(),
+            fields: fields.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect(),
+            description: String::new(),
+        });
+
+        Arc::new(Context {
+            id: ID::new(),
+            lineage: Lineage::new(),
+            document_node: Arc::new(RwLock::new(DocumentNode::new(XMLNode::Text(String::new())))),
+            graph_node: Arc::new(RwLock::new(GraphNode::from_data_node(Arc::clone(&data_node), Vec::new()))),
+            data_node,
+        })
+    }
+
+    fn positions(examples: &[Arc<Context>], contexts: &[Arc<Context>]) -> Vec<usize> {
+        examples
+            .iter()
+            .map(|example| contexts.iter().position(|context| context.id == example.id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn select_diverse_examples_skips_repeated_values() {
+        let contexts = vec![
+            context(&[("text", "Lamp"), ("href", "/lamp")]),
+            context(&[("text", "Lamp"), ("href", "/lamp")]),
+            context(&[("text", "Chair"), ("href", "/lamp")]),
+            context(&[("text", "Desk"), ("href", "/desk")]),
+        ];
+
+        let examples = select_diverse_examples(&contexts, 3);
+
+        assert_eq!(positions(&examples, &contexts), vec![0, 3, 2]);
+    }
+
+    #[test]
+    fn select_diverse_examples_prefers_more_unseen_values() {
+        let contexts = vec![
+            context(&[("text", "Lamp")]),
+            context(&[("text", "Chair"), ("title", "Wooden chair")]),
+        ];
+
+        let examples = select_diverse_examples(&contexts, 1);
+
+        assert_eq!(positions(&examples, &contexts), vec![1]);
+    }
+
+    #[test]
+    fn select_diverse_examples_is_bounded_by_count_and_contexts() {
+        let contexts = vec![context(&[("text", "Lamp")]), context(&[("text", "Lamp")])];
+
+        assert_eq!(select_diverse_examples(&contexts, 5).len(), 2);
+        assert!(select_diverse_examples(&contexts, 0).is_empty());
+        assert!(select_diverse_examples(&[], 3).is_empty());
+    }
+}
@@ -22,21 +22,21 @@ impl DataNode {
         fields: DataNodeFields,
         description: String,
         parent_lineage: &Lineage,
-    ) -> Self {
-        let hash: Hash = hash_transformation.transform(fields.clone());
+    ) -> Result<Self, Errors> {
+        let hash: Hash = hash_transformation.transform(fields.clone())?;
         let lineage = parent_lineage.with_hash(hash.clone());
         let meaningful_data: DataNodeFields = fields
             .into_iter()
             .filter(|(key, _)| meaningful_fields.contains(key))
             .collect();
 
-        DataNode {
+        Ok(DataNode {
             id: ID::new(),
             hash,
             fields: meaningful_data,
             lineage,
             description,
-        }
+        })
     }
 
     pub fn get_hash(&self) -> Hash {
@@ -3,13 +3,17 @@ use xmltree::{Element};
 use html5ever::parse_document;
 use html5ever::tendril::TendrilSink;
 use markup5ever_rcdom::{Handle, NodeData, RcDom};
-use std::collections::{HashSet, HashMap};
+use std::collections::{HashMap};
 use std::sync::Arc;
+use url::Url;
 
 use crate::prelude::*;
+use crate::config::{CONFIG};
 use crate::document_node::{DocumentNode};
 use crate::provider::Provider;
 use crate::profile::Profile;
+use crate::drift::ProfileDrift;
+use crate::token_usage::TokenUsage;
 use crate::hash::{
     Hash,
 };
@@ -80,63 +84,121 @@ impl Document {
                 }
             }
         } else {
-            unimplemented!()
+            Err(Errors::UnexpectedDocumentType)
         }
     }
 
     pub async fn perform_analysis<P: Provider>(
         &mut self,
-        provider: Arc<P>
-    ) -> Result<Profile, Errors> {
+        provider: Arc<P>,
+        token_usage: Arc<TokenUsage>,
+    ) -> Result<(Profile, Option<ProfileDrift>), Errors> {
         log::trace!("In document/perform_analysis");
 
-        if let Some(dom) = self.to_dom() {
+        if let Some(features) = self.get_features() {
             log::info!("It seems to be possible to parse this document as XML");
 
             self.document_type = DocumentType::Xml;
 
-            let mut features: HashSet<String> = HashSet::new();
-
-            get_xml_features(
-                &dom.document,
-                &mut String::from(""),
-                &mut features,
-            );
-
-            let features: HashSet<Hash> = features.iter().map(|feature| {
-                let mut hash = Hash::new();
-                hash.push(feature).finalize().clear_items();
-                hash.clone()
-            }).collect();
-
             if let Some(profile) = provider.get_profile(&features).await? {
                 log::info!("Found a profile");
 
                 if profile.xml_element_transformation.is_none() {
                     log::info!("Profile provided but xml transformation missing");
-                    unimplemented!();
+                    return Err(Errors::IncompleteProfile("XML element transformation is missing".to_string()));
                 }
 
                 if profile.hash_transformation.is_none() {
                     log::info!("Profile provided but hash transformation is missing");
-                    unimplemented!();
+                    return Err(Errors::IncompleteProfile("hash transformation is missing".to_string()));
                 }
 
-                Ok(profile)
+                Ok((profile, None))
             } else {
                 log::info!("Profile not provided, we will create a new one");
 
-                let profile = Profile::create_profile(&features).await?;
+                let group = self.get_group();
+                let candidate_count = read_lock!(CONFIG).matching.candidate_count;
+                let candidates = provider.get_profile_candidates(&features, candidate_count).await?;
+                let drift = ProfileDrift::detect(&candidates, &group);
+
+                let mut profile = Profile::create_profile(self, &features, token_usage).await?;
+                profile.group = group;
+
+                if let Some(drift) = &drift {
+                    profile.version = drift.previous_version + 1;
+                    profile.previous_version = Some(drift.previous_profile_id.clone());
+                    profile.group = profile.group.or_else(|| drift.group.clone());
+                }
 
                 provider.save_profile(&profile).await?;
 
-                Ok(profile)
+                Ok((profile, drift))
             }
         } else {
              Err(Errors::UnexpectedDocumentType)
         }
     }
 
+    /// Group key for profiles learned from this document, taken from the host
+    /// of its origin
+    pub fn get_group(&self) -> Option<String> {
+        let origin = self.metadata.origin.as_ref()?;
+        let url = Url::parse(origin).ok()?;
+        let host = url.host_str()?.to_lowercase();
+
+        Some(host.trim_start_matches("www.").to_string())
+    }
+
+    /// Hashed structural features used to match the document against saved
+    /// profiles with their weights, or None if the document cannot be parsed
+    /// as XML
+    pub fn get_features(&self) -> Option<HashMap<Hash, f64>> {
+        log::trace!("In document/get_features");
+
+        let features = self.get_feature_paths()?;
+
+        Some(features
+            .into_iter()
+            .map(|(feature, weight)| (Document::get_feature_hash(&feature), weight))
+            .collect())
+    }
+
+    /// Element, attribute and text paths occurring in the document, weighted
+    /// by how often they occur and how deep they are. Frequent and deeply
+    /// nested paths describe the content of a page type better than the
+    /// shallow chrome shared by every page of a site.
+    pub fn get_feature_paths(&self) -> Option<HashMap<String, f64>> {
+        log::trace!("In document/get_feature_paths");
+
+        let dom = self.to_dom()?;
+        let class_tokens = read_lock!(CONFIG).matching.class_token_features;
+        let mut features: HashMap<String, usize> = HashMap::new();
+
+        get_xml_features(
+            &dom.document,
+            &mut String::from(""),
+            &mut features,
+            class_tokens,
+        );
+
+        Some(features
+            .into_iter()
+            .map(|(feature, count)| {
+                let depth = feature.matches('/').count() as f64;
+                let weight = (1.0 + (count as f64).ln()) * depth;
+
+                (feature, weight)
+            })
+            .collect())
+    }
+
+    pub fn get_feature_hash(feature: &str) -> Hash {
+        let mut hash = Hash::new();
+        hash.push(feature).finalize().clear_items();
+        hash.clone()
+    }
+
     fn to_dom(&self) -> Option<RcDom> {
         let sanitized = self.data.replace("\n", "");
 
@@ -150,16 +212,17 @@ impl Document {
 fn get_xml_features(
     node: &Handle,
     path: &mut String,
-    features: &mut HashSet<String>,
+    features: &mut HashMap<String, usize>,
+    class_tokens: bool,
 ) {
     match &node.data {
         NodeData::Document => {
             for child in node.children.borrow().iter() {
-                get_xml_features(child, path, features);
+                get_xml_features(child, path, features, class_tokens);
             }
         }
         NodeData::Text { .. } => {
-            features.insert(format!("{}/text", path));
+            *features.entry(format!("{}/text", path)).or_insert(0) += 1;
         }
         NodeData::Element {
             ref name,
@@ -170,17 +233,33 @@ fn get_xml_features(
 
             for attr in attrs.borrow().iter() {
                 let attr_name = attr.name.local.trim();
-                features.insert(format!("{}.{}", new_path, attr_name));
+                *features.entry(format!("{}.{}", new_path, attr_name)).or_insert(0) += 1;
+
+                if class_tokens && attr_name == "class" {
+                    for token in get_class_tokens(&attr.value) {
+                        *features.entry(format!("{}.class~{}", new_path, token)).or_insert(0) += 1;
+                    }
+                }
             }
 
             for child in node.children.borrow().iter() {
-                get_xml_features(child, &mut new_path, features);
+                get_xml_features(child, &mut new_path, features, class_tokens);
             }
         }
         _ => {}
     }
 }
 
+// Tokens containing digits are usually generated by CSS tooling and change
+// between deployments, so they are left out
+fn get_class_tokens(class: &str) -> Vec<String> {
+    class
+        .split_whitespace()
+        .filter(|token| !token.chars().any(|c| c.is_ascii_digit()))
+        .map(|token| token.to_string())
+        .collect()
+}
+
 fn walk(xhtml: &mut String, handle: &Handle, indent: usize) {
     let node = handle;
     let real_indent = " ".repeat(indent * 2);
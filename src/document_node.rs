@@ -42,10 +42,10 @@ impl DocumentNode {
     pub fn from_transformations(
         xml_node: XMLNode,
         xml_element_transformation: XMLElementTransformation,
-    ) -> Option<Self> {
+    ) -> Result<Option<Self>, Errors> {
         match &xml_node {
             XMLNode::Element(element_node) => {
-                let mut element: Option<String> = Some(element_node.name.clone());
+                let element: Option<String>;
                 let mut attributes: HashMap<String, String>  = HashMap::new();
 
                 for (attr, val) in element_node.attributes.iter() {
@@ -55,9 +55,9 @@ impl DocumentNode {
                 log::info!("Applying XML element transformation...");
 
                 let (transformed_element, transformed_attributes) = xml_element_transformation.transform(
-                    element.unwrap().clone(),
+                    element_node.name.clone(),
                     attributes.clone()
-                );
+                )?;
 
                 attributes = transformed_attributes;
 
@@ -70,7 +70,7 @@ impl DocumentNode {
 
                 log::info!("Done applying XML element transformations.");
 
-                element.map(|some_element| {
+                Ok(element.map(|some_element| {
                     let mut transformed_node = xml_node.clone();
 
                     if let XMLNode::Element(ref mut elem) = transformed_node {
@@ -79,15 +79,19 @@ impl DocumentNode {
                     }
 
                     DocumentNode::new(transformed_node)
-                })
+                }))
             },
             XMLNode::Text(_text_node) => {
-                Some(DocumentNode::new(xml_node))
+                Ok(Some(DocumentNode::new(xml_node)))
             },
             _ => panic!("Unexpected XML node type")
         }
     }
 
+    pub fn get_xml_node(&self) -> &XMLNode {
+        &self.data
+    }
+
     pub fn get_fields(&self) -> HashMap<String, String> {
         match &self.data {
             XMLNode::Element(element_node) => {
@@ -122,25 +126,27 @@ impl DocumentNode {
     pub fn get_children(
         &self,
         xml_element_transformation: Option<XMLElementTransformation>
-    ) -> Vec<DocumentNode> {
+    ) -> Result<Vec<DocumentNode>, Errors> {
         match &self.data {
             XMLNode::Element(element_node) => {
-                element_node.children
-                    .iter()
-                    .filter_map(|child| {
-
-                        if let Some(xml_element_transformation) = &xml_element_transformation {
-                            DocumentNode::from_transformations(
-                                child.clone(),
-                                xml_element_transformation.clone()
-                            )
-                        } else {
-                            Some(DocumentNode::new(child.clone()))
+                let mut children = Vec::new();
+
+                for child in element_node.children.iter() {
+                    if let Some(xml_element_transformation) = &xml_element_transformation {
+                        if let Some(document_node) = DocumentNode::from_transformations(
+                            child.clone(),
+                            xml_element_transformation.clone()
+                        )? {
+                            children.push(document_node);
                         }
-                    })
-                    .collect()
+                    } else {
+                        children.push(DocumentNode::new(child.clone()));
+                    }
+                }
+
+                Ok(children)
             },
-            XMLNode::Text(_text_node) => Vec::new(),
+            XMLNode::Text(_text_node) => Ok(Vec::new()),
             _ => panic!("Unexpected XML node type")
         }
     }
@@ -0,0 +1,335 @@
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+
+use crate::prelude::*;
+use crate::basis_node::BasisNode;
+use crate::config::{CONFIG};
+use crate::profile::ProfileMatch;
+use crate::provider::Provider;
+
+/// A document that came close to an existing profile without reaching the
+/// similarity threshold, typically because its site was redesigned. The new
+/// profile is saved as the next version of the drifted one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileDrift {
+    pub previous_profile_id: ID,
+    pub previous_version: usize,
+    pub group: Option<String>,
+    pub score: f64,
+    pub threshold: f64,
+    pub migrations: Vec<BasisNodeMigration>,
+    pub migrated: bool,
+}
+
+/// A basis node of the previous profile version whose lineage still exists,
+/// under different ancestors, in the structure of the new version
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BasisNodeMigration {
+    pub basis_node_id: ID,
+    pub from_lineage: String,
+    pub to_lineage: String,
+    pub shared_ancestry: usize,
+}
+
+impl ProfileDrift {
+    /// Picks the best candidate scoring between the drift threshold and the
+    /// similarity threshold. Candidates known to belong to another group are
+    /// other sites rather than earlier versions of this one.
+    pub fn detect(candidates: &[ProfileMatch], group: &Option<String>) -> Option<Self> {
+        log::trace!("In detect");
+
+        let (drift_threshold, threshold) = {
+            let lock = read_lock!(CONFIG);
+            (lock.matching.drift_threshold, lock.matching.similarity_threshold)
+        };
+
+        candidates
+            .iter()
+            .filter(|candidate| !candidate.accepted && candidate.score >= drift_threshold)
+            .find(|candidate| match (&candidate.profile.group, group) {
+                (Some(candidate_group), Some(group)) => candidate_group == group,
+                _ => true,
+            })
+            .map(|candidate| {
+                log::warn!(
+                    "Document drifted from profile {} version {} with a similarity of {} against a threshold of {}",
+                    candidate.profile.id.to_string(),
+                    candidate.profile.version,
+                    candidate.score,
+                    threshold
+                );
+
+                ProfileDrift {
+                    previous_profile_id: candidate.profile.id.clone(),
+                    previous_version: candidate.profile.version,
+                    group: candidate.profile.group.clone(),
+                    score: candidate.score,
+                    threshold,
+                    migrations: Vec::new(),
+                    migrated: false,
+                }
+            })
+    }
+}
+
+/// Pairs basis nodes of the drifted profile with lineages of the new document.
+/// A redesign changes the ancestors of parts of a page, so the ancestors a
+/// basis node does not share with a lineage are taken to have become the
+/// ones of the lineage. This drift mapping from old to new ancestors must
+/// hold for every migration, and everything beneath a mapped ancestor keeps
+/// its structure. A lineage that only ends like a basis node, under other
+/// ancestors within a mapped part of the page, is a different node.
+fn get_migrations(
+    previous_basis_nodes: &[BasisNode],
+    lineages: &[Lineage],
+    minimum_ancestry: usize,
+    profile_id: &ID,
+) -> Vec<(BasisNodeMigration, BasisNode)> {
+    log::trace!("In get_migrations");
+
+    struct Candidate<'a> {
+        basis_node: &'a BasisNode,
+        lineage: &'a Lineage,
+        shared_ancestry: usize,
+        ancestors: (Lineage, Lineage),
+    }
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for lineage in lineages.iter() {
+        for basis_node in previous_basis_nodes.iter() {
+            let shared_ancestry = basis_node.lineage.get_shared_ancestry(lineage);
+
+            if shared_ancestry < minimum_ancestry ||
+                shared_ancestry >= basis_node.lineage.get_depth() ||
+                shared_ancestry >= lineage.get_depth()
+            {
+                continue;
+            }
+
+            candidates.push(Candidate {
+                basis_node,
+                lineage,
+                shared_ancestry,
+                ancestors: (
+                    basis_node.lineage.get_ancestor(shared_ancestry),
+                    lineage.get_ancestor(shared_ancestry),
+                ),
+            });
+        }
+    }
+
+    // Ancestors nearest the root are mapped first, and among those the ones
+    // most nodes agree on
+    let mut votes: HashMap<&(Lineage, Lineage), usize> = HashMap::new();
+    for candidate in candidates.iter() {
+        *votes.entry(&candidate.ancestors).or_insert(0) += 1;
+    }
+
+    let mut proposed: Vec<(&(Lineage, Lineage), usize)> = votes.into_iter().collect();
+    proposed.sort_by_key(|((old, new), votes)| (old.get_depth() + new.get_depth(), std::cmp::Reverse(*votes)));
+
+    let mut mapping: Vec<&(Lineage, Lineage)> = Vec::new();
+
+    for (ancestors, _) in proposed.into_iter() {
+        let (old, new) = ancestors;
+
+        if mapping.iter().all(|(mapped_old, mapped_new)| !old.descends_from(mapped_old) && !new.descends_from(mapped_new)) {
+            mapping.push(ancestors);
+        }
+    }
+
+    candidates
+        .iter()
+        .filter(|candidate| mapping.contains(&&candidate.ancestors))
+        .map(|candidate| {
+            let migration = BasisNodeMigration {
+                basis_node_id: candidate.basis_node.id.clone(),
+                from_lineage: candidate.basis_node.lineage.to_string(),
+                to_lineage: candidate.lineage.to_string(),
+                shared_ancestry: candidate.shared_ancestry,
+            };
+
+            let migrated_basis_node = BasisNode {
+                id: ID::new(),
+                lineage: candidate.lineage.clone(),
+                profile_id: Some(profile_id.clone()),
+                ..candidate.basis_node.clone()
+            };
+
+            (migration, migrated_basis_node)
+        })
+        .collect()
+}
+
+/// Finds the basis nodes of the drifted profile that can be carried over to
+/// lineages of the new document, and copies them over if configured to.
+/// Otherwise the migrations are only reported.
+pub async fn migrate_basis_nodes<P: Provider>(
+    provider: Arc<P>,
+    meta_context: Arc<RwLock<MetaContext>>,
+) -> Result<(), Errors> {
+    log::trace!("In migrate_basis_nodes");
+
+    let (drift, profile, lineages) = {
+        let lock = read_lock!(meta_context);
+
+        let drift = match lock.profile_drift.clone() {
+            Some(drift) => drift,
+            None => return Ok(()),
+        };
+        let profile = lock.profile.clone().ok_or(Errors::ProfileNotProvided)?;
+        let contexts = lock.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
+
+        let mut seen: HashSet<Lineage> = HashSet::new();
+        let lineages: Vec<Lineage> = contexts
+            .values()
+            .map(|context| context.lineage.clone())
+            .filter(|lineage| seen.insert(lineage.clone()))
+            .collect();
+
+        (drift, profile, lineages)
+    };
+
+    let (minimum_ancestry, migrate) = {
+        let lock = read_lock!(CONFIG);
+        (lock.matching.migration_minimum_ancestry, lock.matching.migrate_basis_nodes)
+    };
+
+    let previous_basis_nodes = provider
+        .get_basis_nodes_by_profile(&drift.previous_profile_id)
+        .await
+        .stage(Stage::Provider)?;
+
+    let mut unknown_lineages: Vec<Lineage> = Vec::new();
+
+    for lineage in lineages.into_iter() {
+        if provider.get_basis_node_by_lineage(&lineage).await.stage(Stage::Provider)?.is_none() {
+            unknown_lineages.push(lineage);
+        }
+    }
+
+    let migrations = get_migrations(&previous_basis_nodes, &unknown_lineages, minimum_ancestry, &profile.id);
+
+    if migrate {
+        for (_, basis_node) in migrations.iter() {
+            provider.save_basis_node(
+                &basis_node.lineage,
+                basis_node.clone(),
+            ).await.stage(Stage::Provider)?;
+        }
+
+        log::info!("Migrated {} basis nodes from profile {}", migrations.len(), drift.previous_profile_id.to_string());
+    } else if !migrations.is_empty() {
+        log::warn!(
+            "{} basis nodes of profile {} can be migrated, set matching.migrate_basis_nodes to migrate them",
+            migrations.len(),
+            drift.previous_profile_id.to_string()
+        );
+    }
+
+    let drift = ProfileDrift {
+        migrations: migrations.into_iter().map(|(migration, _)| migration).collect(),
+        migrated: migrate,
+        ..drift
+    };
+
+    {
+        let mut lock = write_lock!(meta_context);
+        lock.update_profile_drift(drift);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{Profile, ProfileMatch};
+
+    fn lineage(path: &str) -> Lineage {
+        Lineage::from_hashes(path.split('/').map(Hash::from_str).collect())
+    }
+
+    fn basis_node(path: &str) -> BasisNode {
+        BasisNode {
+            id: ID::new(),
+            hash: Hash::from_str(path),
+            lineage: lineage(path),
+            description: path.to_string(),
+            transformations: Vec::new(),
+            eliminations: Vec::new(),
+            profile_id: None,
+        }
+    }
+
+    fn candidate(score: f64, accepted: bool, group: Option<&str>) -> ProfileMatch {
+        let mut profile = Profile::default_profile(&HashMap::new());
+        profile.group = group.map(String::from);
+
+        ProfileMatch { profile, score, accepted }
+    }
+
+    #[test]
+    fn drift_is_detected_below_the_similarity_threshold() {
+        let candidates = vec![
+            candidate(0.95, true, None),
+            candidate(0.3, false, None),
+            candidate(0.7, false, Some("other.example")),
+            candidate(0.6, false, Some("shop.example")),
+        ];
+
+        let drift = ProfileDrift::detect(&candidates, &Some("shop.example".to_string())).unwrap();
+
+        assert_eq!(drift.previous_profile_id, candidates[3].profile.id);
+        assert_eq!(drift.score, 0.6);
+        assert!(ProfileDrift::detect(&candidates[..2], &None).is_none());
+    }
+
+    #[test]
+    fn basis_nodes_migrate_to_lineages_matching_under_the_drift_mapping() {
+        // The listing got a new wrapper, which changed its hash
+        let previous_basis_nodes = vec![
+            basis_node("html/body/main/item"),
+            basis_node("html/body/main/item/a"),
+            basis_node("html/body/main/item/a/text"),
+            basis_node("html/body/main/item/span"),
+            basis_node("html/body/main/item/span/text"),
+        ];
+
+        let lineages = vec![
+            lineage("html/body/layout/item"),
+            lineage("html/body/layout/item/a"),
+            lineage("html/body/layout/item/a/text"),
+            lineage("html/body/layout/item/span/text"),
+            // New to the page, but ends like the link of an item
+            lineage("html/body/layout/footer/a"),
+            lineage("html/body/layout/footer/a/text"),
+        ];
+
+        let profile_id = ID::new();
+        let migrations = get_migrations(&previous_basis_nodes, &lineages, 2, &profile_id);
+
+        let migrated: HashSet<(String, String)> = migrations
+            .iter()
+            .map(|(migration, _)| (migration.from_lineage.clone(), migration.to_lineage.clone()))
+            .collect();
+
+        let expected: HashSet<(String, String)> = [
+            ("html/body/main/item/a", "html/body/layout/item/a"),
+            ("html/body/main/item/a/text", "html/body/layout/item/a/text"),
+            ("html/body/main/item/span/text", "html/body/layout/item/span/text"),
+        ]
+            .iter()
+            .map(|(from, to)| (lineage(from).to_string(), lineage(to).to_string()))
+            .collect();
+
+        assert_eq!(migrated, expected);
+
+        for (migration, basis_node) in migrations.iter() {
+            assert_eq!(basis_node.lineage.to_string(), migration.to_lineage);
+            assert_eq!(basis_node.profile_id, Some(profile_id.clone()));
+        }
+    }
+}
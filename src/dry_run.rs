@@ -0,0 +1,168 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::context_group::ContextGroup;
+use crate::document::Document;
+use crate::llm::LLM;
+use crate::network_analysis::{get_unique_subgraphs, detect_recursion, get_fragment_character_limit};
+use crate::profile::Profile;
+use crate::provider::Provider;
+use crate::token_usage::{TokenUsage, TokenUsageReport};
+use crate::traverse::traverse_document;
+
+/// What organizing a document would cost, found by checking which parts of
+/// it the provider already knows and sizing the requests for the rest
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DryRunReport {
+    pub profile_id: Option<ID>,
+    pub basis_graph_known: bool,
+    pub context_groups: usize,
+    pub known_context_groups: usize,
+    pub subgraphs: usize,
+    pub known_subgraphs: usize,
+    pub estimate: TokenUsageReport,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.profile_id {
+            Some(profile_id) => writeln!(f, "profile: {}", profile_id.to_string())?,
+            None => writeln!(f, "profile: none, one would be created")?,
+        }
+
+        writeln!(f, "basis graph: {}", if self.basis_graph_known { "known" } else { "unknown" })?;
+        writeln!(f, "context groups: {} of {} known", self.known_context_groups, self.context_groups)?;
+        writeln!(f, "subgraphs: {} of {} known", self.known_subgraphs, self.subgraphs)?;
+        writeln!(f, "estimated LLM requests: {}", self.estimate.total.requests)?;
+        write!(f, "{}", self.estimate)
+    }
+}
+
+/// Performs analysis and traversal of a document and estimates the LLM
+/// requests organizing it would make. Nothing is sent to the LLM and nothing
+/// is saved to the provider.
+pub async fn estimate_document<P: Provider>(
+    provider: Arc<P>,
+    document: Document,
+) -> Result<DryRunReport, Errors> {
+    log::trace!("In estimate_document");
+
+    let estimate = TokenUsage::new();
+    let features = document.get_features().ok_or(Errors::UnexpectedDocumentType)?;
+
+    // Without a matching profile the traversal uses the default profile, as
+    // the proposed one cannot be known without asking for it
+    let (profile, profile_id) = match provider.get_profile(&features).await.stage(Stage::Provider)? {
+        Some(profile) => {
+            let profile_id = Some(profile.id.clone());
+            (profile, profile_id)
+        },
+        None => {
+            if read_lock!(CONFIG).llm.generate_profiles {
+                Profile::estimate_proposal(&document, &estimate).stage(Stage::Analysis)?;
+            }
+
+            (Profile::default_profile(&features), None)
+        }
+    };
+
+    let meta_context = Arc::new(RwLock::new(MetaContext::new()));
+
+    {
+        let mut lock = write_lock!(meta_context);
+        lock.update_profile(Arc::new(profile));
+    }
+
+    let (contexts, graph_root) = traverse_document(document, meta_context.clone()).stage(Stage::Traversal)?;
+
+    {
+        let mut lock = write_lock!(meta_context);
+        lock.update_data_structures(contexts, graph_root.clone());
+    }
+
+    let lineage = read_lock!(graph_root).lineage.clone();
+    let overall_context = match provider.get_basis_graph_by_lineage(&lineage).await.stage(Stage::Provider)? {
+        Some(basis_graph) => Some(basis_graph.structure),
+        None => {
+            let chunk_length = read_lock!(CONFIG).llm.summary_chunk_length;
+            let document_chunks = read_lock!(meta_context).get_original_document_chunks(chunk_length)?;
+            LLM::estimate_categorize_and_summarize(&estimate, document_chunks);
+
+            None
+        }
+    };
+    let basis_graph_known = overall_context.is_some();
+    let overall_context = overall_context.unwrap_or_default();
+
+    let context_groups = ContextGroup::from_meta_context(Arc::clone(&meta_context)).stage(Stage::BasisNode)?;
+    let mut known_context_groups = 0;
+
+    for context_group in context_groups.iter() {
+        if provider.get_basis_node_by_lineage(&context_group.lineage).await.stage(Stage::Provider)?.is_some() {
+            known_context_groups += 1;
+        } else {
+            LLM::estimate_field_transformations(&estimate, context_group);
+        }
+    }
+
+    let subgraphs = get_unique_subgraphs(graph_root);
+    let mut known_subgraphs = 0;
+
+    for (subgraph_hash, graph) in subgraphs.iter() {
+        let subgraph_hash = subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?;
+
+        if provider.get_basis_network_by_subgraph_hash(&subgraph_hash).await.stage(Stage::Provider)?.is_some() {
+            known_subgraphs += 1;
+            continue;
+        }
+
+        let parent = match read_lock!(graph).parents.first() {
+            Some(parent) => parent.clone(),
+            None => continue,
+        };
+
+        // The JSON of a fragment depends on basis nodes that do not exist
+        // yet, so every fragment is taken to be as long as it may get
+        let placeholder = "x".repeat(get_fragment_character_limit());
+
+        if detect_recursion(Arc::clone(graph)).is_some() {
+            LLM::estimate_recursion(&estimate, &overall_context, &placeholder);
+            continue;
+        }
+
+        // Which siblings have empty JSON is not known yet, so each distinct
+        // subgraph among the siblings is counted once
+        let mut sibling_hashes: Vec<String> = read_lock!(parent).children
+            .iter()
+            .filter_map(|sibling| read_lock!(sibling).subgraph_hash.to_string())
+            .collect();
+        sibling_hashes.sort();
+        sibling_hashes.dedup();
+
+        // Like get_basis_network, only siblings of another subgraph make
+        // associations worth asking for
+        if sibling_hashes.iter().all(|sibling_hash| *sibling_hash == subgraph_hash) {
+            continue;
+        }
+
+        let siblings: Vec<(String, String)> = sibling_hashes
+            .into_iter()
+            .map(|sibling_hash| (sibling_hash, placeholder.clone()))
+            .collect();
+
+        LLM::estimate_relationships(&estimate, &overall_context, &subgraph_hash, &siblings);
+    }
+
+    Ok(DryRunReport {
+        profile_id,
+        basis_graph_known,
+        context_groups: context_groups.len(),
+        known_context_groups,
+        subgraphs: subgraphs.len(),
+        known_subgraphs,
+        estimate: estimate.get_report(),
+    })
+}
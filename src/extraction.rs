@@ -0,0 +1,789 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use xmltree::XMLNode;
+use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::prelude::*;
+use crate::basis_network::NetworkRelationship;
+use crate::context::Context;
+use crate::document::Document;
+use crate::graph_node::Graph;
+use crate::meta_context::MetaContext;
+use crate::network_analysis::is_nested_instance;
+use crate::document_node::DocumentNode;
+use crate::transformation::XMLElementTransformation;
+
+/// A single step along a selector path. Element steps match a child element
+/// by tag and by attributes it must carry, allowing others; text steps match
+/// the child text nodes of the current element.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SelectorStep {
+    Element {
+        tag: String,
+        attributes: Vec<String>,
+    },
+    Text,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Selector {
+    pub steps: Vec<SelectorStep>,
+    pub xpath: String,
+    pub css: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldExtraction {
+    pub key: String,
+    pub description: String,
+    pub field: String,
+    pub selector: Selector,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AssociatedExtraction {
+    pub step: SelectorStep,
+    pub scope: ExtractionScope,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ObjectExtraction {
+    pub name: String,
+    pub description: String,
+    pub parent: Selector,
+    pub step: SelectorStep,
+    pub associated: Vec<AssociatedExtraction>,
+    pub scope: ExtractionScope,
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ExtractionScope {
+    pub fields: Vec<FieldExtraction>,
+    pub objects: Vec<ObjectExtraction>,
+    /// Paths to nested instances of the enclosing recursive object
+    #[serde(default)]
+    pub recursions: Vec<Selector>,
+}
+
+/// Standalone extraction plan compiled from a learned basis graph, its basis
+/// nodes and basis networks. Running a plan requires neither the LLM nor a
+/// provider.
+///
+/// Basis data is keyed by hashes, which cannot be turned back into
+/// selectors, so a plan only covers the structures found in the documents it
+/// was compiled from. Plans compiled from several documents of a profile can
+/// be merged to cover more of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExtractionPlan {
+    pub id: ID,
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub profile_id: Option<ID>,
+    /// Applied to a document before it is matched, as it was to the documents
+    /// the plan was compiled from
+    #[serde(default)]
+    pub xml_element_transformation: Option<XMLElementTransformation>,
+    pub root: SelectorStep,
+    pub scope: ExtractionScope,
+}
+
+impl ExtractionPlan {
+    pub fn compile(meta_context: Arc<RwLock<MetaContext>>) -> Result<Self, Errors> {
+        log::trace!("In compile");
+
+        let (graph_root, basis_graph, contexts, profile) = {
+            let lock = read_lock!(meta_context);
+            (
+                lock.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?,
+                lock.basis_graph.clone().ok_or(Errors::BasisGraphNotProvided)?,
+                lock.contexts.clone().ok_or(Errors::ContextsNotProvided)?,
+                lock.profile.clone().ok_or(Errors::ProfileNotProvided)?,
+            )
+        };
+
+        let root_context = get_context(&contexts, &graph_root)?;
+        let root = get_selector_step(&root_context);
+
+        let scope = compile_scope(Arc::clone(&meta_context), &contexts, graph_root, None)?;
+
+        Ok(ExtractionPlan {
+            id: ID::new(),
+            name: basis_graph.name.clone(),
+            description: basis_graph.description.clone(),
+            profile_id: Some(profile.id.clone()),
+            xml_element_transformation: profile.xml_element_transformation.clone(),
+            root,
+            scope,
+        })
+    }
+
+    /// Adds the fields and objects of a plan compiled from another document of
+    /// the same profile
+    pub fn merge(&mut self, other: ExtractionPlan) -> Result<(), Errors> {
+        log::trace!("In merge");
+
+        if self.profile_id.is_none() || self.profile_id != other.profile_id || self.root != other.root {
+            log::error!("Only plans compiled for the same profile can be merged");
+            return Err(Errors::ExtractionPlanMismatch);
+        }
+
+        self.scope.merge(other.scope);
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String, Errors> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Errors> {
+        serde_json::from_str(json).map_err(|err| {
+            log::error!("Could not deserialize extraction plan: {}", err);
+            Errors::ExtractionPlanParseError
+        })
+    }
+
+    pub fn run_text(&self, text: String) -> Result<String, Errors> {
+        log::trace!("In run_text");
+
+        let document = Document::from_string(text, &None)?;
+        let document_node = document.get_document_node()?;
+
+        self.run(document_node.get_xml_node())
+    }
+
+    pub fn run(&self, root: &XMLNode) -> Result<String, Errors> {
+        log::trace!("In run");
+
+        if !matches_step(root, &self.root) {
+            log::error!("Document root does not match extraction plan root");
+            return Err(Errors::ExtractionPlanMismatch);
+        }
+
+        let result = match &self.xml_element_transformation {
+            Some(xml_element_transformation) => {
+                let root = transform_children(root.clone(), xml_element_transformation)?;
+
+                run_scope(&self.scope, &root, None)
+            },
+            None => run_scope(&self.scope, root, None),
+        };
+
+        Ok(serde_json::to_string(&result)?)
+    }
+}
+
+impl Selector {
+    fn from_steps(steps: Vec<SelectorStep>) -> Self {
+        let xpath = std::iter::once(".".to_string())
+            .chain(steps.iter().map(|step| match step {
+                SelectorStep::Element { tag, attributes } => {
+                    let predicates: String = attributes
+                        .iter()
+                        .map(|attribute| format!("[@{}]", attribute))
+                        .collect();
+                    format!("{}{}", tag, predicates)
+                },
+                SelectorStep::Text => "text()".to_string(),
+            }))
+            .collect::<Vec<String>>()
+            .join("/");
+
+        let css = std::iter::once(":scope".to_string())
+            .chain(steps.iter().filter_map(|step| match step {
+                SelectorStep::Element { tag, attributes } => {
+                    let predicates: String = attributes
+                        .iter()
+                        .map(|attribute| format!("[{}]", attribute))
+                        .collect();
+                    Some(format!("{}{}", tag, predicates))
+                },
+                SelectorStep::Text => None,
+            }))
+            .collect::<Vec<String>>()
+            .join(" > ");
+
+        Selector {
+            steps,
+            xpath,
+            css,
+        }
+    }
+
+    fn with_field(&self, field: &str) -> Self {
+        let mut selector = self.clone();
+
+        if field != "text" {
+            selector.xpath = format!("{}/@{}", selector.xpath, field);
+        }
+
+        selector
+    }
+}
+
+impl ExtractionScope {
+    fn add_field(&mut self, field: FieldExtraction) {
+        let exists = self.fields.iter().any(|existing| {
+            existing.key == field.key &&
+                existing.field == field.field &&
+                existing.selector.steps == field.selector.steps
+        });
+
+        if !exists {
+            self.fields.push(field);
+        }
+    }
+
+    fn add_object(&mut self, object: ObjectExtraction) {
+        let existing = self.objects.iter_mut().find(|existing| {
+            existing.name == object.name &&
+                existing.step == object.step &&
+                existing.parent.steps == object.parent.steps
+        });
+
+        if let Some(existing) = existing {
+            existing.scope.merge(object.scope);
+
+            for associated in object.associated.into_iter() {
+                match existing.associated.iter_mut().find(|item| item.step == associated.step) {
+                    Some(item) => item.scope.merge(associated.scope),
+                    None => existing.associated.push(associated),
+                }
+            }
+        } else {
+            self.objects.push(object);
+        }
+    }
+
+    fn add_recursion(&mut self, selector: Selector) {
+        if !self.recursions.iter().any(|existing| existing.steps == selector.steps) {
+            self.recursions.push(selector);
+        }
+    }
+
+    fn merge(&mut self, other: ExtractionScope) {
+        for field in other.fields.into_iter() {
+            self.add_field(field);
+        }
+
+        for recursion in other.recursions.into_iter() {
+            self.add_recursion(recursion);
+        }
+
+        for object in other.objects.into_iter() {
+            self.add_object(object);
+        }
+    }
+}
+
+fn get_context(
+    contexts: &HashMap<ID, Arc<Context>>,
+    graph: &Graph,
+) -> Result<Arc<Context>, Errors> {
+    contexts
+        .get(&read_lock!(graph).id)
+        .cloned()
+        .ok_or(Errors::ContextsNotProvided)
+}
+
+fn get_selector_step(context: &Context) -> SelectorStep {
+    let fields = read_lock!(context.document_node).get_fields();
+
+    match fields.get("tag") {
+        Some(tag) => {
+            let mut attributes: Vec<String> = fields
+                .keys()
+                .filter(|key| key.as_str() != "tag")
+                .cloned()
+                .collect();
+            attributes.sort();
+
+            SelectorStep::Element {
+                tag: tag.clone(),
+                attributes,
+            }
+        },
+        None => SelectorStep::Text,
+    }
+}
+
+// Mirrors traverse::process_network, except that instead of transforming data
+// nodes we record where each field and object was found relative to the scope.
+fn compile_scope(
+    meta_context: Arc<RwLock<MetaContext>>,
+    contexts: &HashMap<ID, Arc<Context>>,
+    graph: Graph,
+    recursion: Option<&Graph>,
+) -> Result<ExtractionScope, Errors> {
+    log::trace!("In compile_scope");
+
+    let mut scope = ExtractionScope::default();
+    let mut queue: VecDeque<(Graph, Vec<SelectorStep>)> = VecDeque::new();
+    queue.push_back((graph, Vec::new()));
+
+    let mut processed_child_ids = HashSet::new();
+
+    while let Some((current, path)) = queue.pop_front() {
+        let context = get_context(contexts, &current)?;
+
+        let maybe_basis_node = {
+            let lock = read_lock!(meta_context);
+            lock.get_basis_node_by_lineage(&context.lineage)?
+        };
+
+        if let Some(basis_node) = maybe_basis_node {
+            let selector = Selector::from_steps(path.clone());
+
+            for transformation in basis_node.transformations.iter() {
+                scope.add_field(FieldExtraction {
+                    key: transformation.image.clone(),
+                    description: transformation.description.clone(),
+                    field: transformation.field.clone(),
+                    selector: selector.with_field(&transformation.field),
+                });
+            }
+        }
+
+        let children = read_lock!(current).children.clone();
+
+        for (index, child) in children.iter().enumerate() {
+            let child_id = read_lock!(child).id.clone();
+
+            if processed_child_ids.contains(&child_id) {
+                continue;
+            }
+
+            let child_context = get_context(contexts, child)?;
+            let child_step = get_selector_step(&child_context);
+            let child_subgraph_hash = read_lock!(child).subgraph_hash.clone();
+
+            if recursion.is_some_and(|recursion| is_nested_instance(recursion, child)) {
+                let mut child_path = path.clone();
+                child_path.push(child_step);
+
+                scope.add_recursion(Selector::from_steps(child_path));
+                processed_child_ids.insert(child_id);
+                continue;
+            }
+
+            let maybe_basis_network = {
+                let lock = read_lock!(meta_context);
+                lock.get_basis_network_by_subgraph_hash(
+                    &child_subgraph_hash.to_string().unwrap_or_default()
+                )?
+            };
+
+            match maybe_basis_network {
+                Some(basis_network) if !basis_network.is_null_network() => {
+                    let mut associated_graphs = match &basis_network.relationship {
+                        NetworkRelationship::Association(assoc) => assoc.clone(),
+                        _ => Vec::new(),
+                    };
+
+                    let recursive = matches!(basis_network.relationship, NetworkRelationship::Recursion(_));
+                    let inner_recursion = if recursive {
+                        Some(child)
+                    } else {
+                        recursion
+                    };
+
+                    let mut associated = Vec::new();
+
+                    for subsequent_child in children.iter().skip(index + 1) {
+                        let subsequent_child_id = read_lock!(subsequent_child).id.clone();
+
+                        if processed_child_ids.contains(&subsequent_child_id) {
+                            continue;
+                        }
+
+                        let subsequent_subgraph_hash = read_lock!(subsequent_child)
+                            .subgraph_hash
+                            .to_string()
+                            .unwrap_or_default();
+
+                        if associated_graphs.contains(&subsequent_subgraph_hash) {
+                            let subsequent_context = get_context(contexts, subsequent_child)?;
+
+                            associated.push(AssociatedExtraction {
+                                step: get_selector_step(&subsequent_context),
+                                scope: compile_scope(
+                                    Arc::clone(&meta_context),
+                                    contexts,
+                                    subsequent_child.clone(),
+                                    inner_recursion,
+                                )?,
+                            });
+
+                            associated_graphs.retain(|item| item != &subsequent_subgraph_hash);
+                            processed_child_ids.insert(subsequent_child_id);
+                        }
+                    }
+
+                    scope.add_object(ObjectExtraction {
+                        name: basis_network.name.clone(),
+                        description: basis_network.description.clone(),
+                        parent: Selector::from_steps(path.clone()),
+                        step: child_step,
+                        associated,
+                        scope: compile_scope(
+                            Arc::clone(&meta_context),
+                            contexts,
+                            child.clone(),
+                            inner_recursion,
+                        )?,
+                        recursive,
+                    });
+
+                    processed_child_ids.insert(child_id);
+                },
+                _ => {
+                    let mut child_path = path.clone();
+                    child_path.push(child_step);
+
+                    queue.push_back((child.clone(), child_path));
+                }
+            }
+        }
+    }
+
+    Ok(scope)
+}
+
+// Attributes a document adds to an element, such as a tracking id, do not
+// stop it from matching
+fn matches_step(node: &XMLNode, step: &SelectorStep) -> bool {
+    match (node, step) {
+        (XMLNode::Element(element), SelectorStep::Element { tag, attributes }) => {
+            element.name == *tag &&
+                attributes.iter().all(|attribute| element.attributes.contains_key(attribute))
+        },
+        (XMLNode::Text(_), SelectorStep::Text) => true,
+        _ => false,
+    }
+}
+
+// Applies the profile's element transformation below the root, like
+// DocumentNode::get_children does while a document is traversed. Elements are
+// transformed without their children, which are moved over afterwards, so
+// that no subtree is copied.
+fn transform_children(
+    node: XMLNode,
+    xml_element_transformation: &XMLElementTransformation,
+) -> Result<XMLNode, Errors> {
+    let mut element = match node {
+        XMLNode::Element(element) => element,
+        node => return Ok(node),
+    };
+
+    let children = std::mem::take(&mut element.children);
+
+    for child in children.into_iter() {
+        let (child, grandchildren) = match child {
+            XMLNode::Element(mut child_element) => {
+                let grandchildren = std::mem::take(&mut child_element.children);
+                (XMLNode::Element(child_element), grandchildren)
+            },
+            XMLNode::Text(_) => (child, Vec::new()),
+            _ => continue,
+        };
+
+        let document_node = DocumentNode::from_transformations(child, xml_element_transformation.clone())?;
+
+        if let Some(document_node) = document_node {
+            let mut child = document_node.get_xml_node().clone();
+
+            if let XMLNode::Element(ref mut child_element) = child {
+                child_element.children = grandchildren;
+            }
+
+            element.children.push(transform_children(child, xml_element_transformation)?);
+        }
+    }
+
+    Ok(XMLNode::Element(element))
+}
+
+fn select<'a>(node: &'a XMLNode, steps: &[SelectorStep]) -> Vec<&'a XMLNode> {
+    let mut current: Vec<&XMLNode> = vec![node];
+
+    for step in steps.iter() {
+        current = current
+            .into_iter()
+            .filter_map(|node| node.as_element())
+            .flat_map(|element| element.children.iter())
+            .filter(|child| matches_step(child, step))
+            .collect();
+    }
+
+    current
+}
+
+fn get_field_value(node: &XMLNode, field: &str) -> Option<String> {
+    match node {
+        XMLNode::Text(text) if field == "text" => Some(text.clone()),
+        XMLNode::Element(element) => element.attributes.get(field).cloned(),
+        _ => None,
+    }
+}
+
+fn insert_value(result: &mut HashMap<String, Value>, key: &str, value: Value) {
+    if let Some(existing_value) = result.get_mut(key) {
+        if let Value::Array(ref mut arr) = existing_value {
+            arr.push(value);
+        } else {
+            *existing_value = json!(vec![existing_value.clone(), value]);
+        }
+    } else {
+        result.insert(key.to_string(), value);
+    }
+}
+
+fn run_scope(
+    scope: &ExtractionScope,
+    node: &XMLNode,
+    recursive_object: Option<&ObjectExtraction>,
+) -> HashMap<String, Value> {
+    let mut result: HashMap<String, Value> = HashMap::new();
+
+    for field in scope.fields.iter() {
+        for matched in select(node, &field.selector.steps) {
+            if let Some(value) = get_field_value(matched, &field.field) {
+                insert_value(&mut result, &field.key, json!(value.trim().to_string()));
+            }
+        }
+    }
+
+    if let Some(recursive_object) = recursive_object {
+        for recursion in scope.recursions.iter() {
+            for matched in select(node, &recursion.steps) {
+                let nested_result = run_scope(&recursive_object.scope, matched, Some(recursive_object));
+
+                match result.get_mut("children") {
+                    Some(Value::Array(ref mut arr)) => arr.push(json!(nested_result)),
+                    _ => {
+                        result.insert("children".to_string(), json!(vec![nested_result]));
+                    }
+                }
+            }
+        }
+    }
+
+    for object in scope.objects.iter() {
+        let inner_recursive_object = if object.recursive {
+            Some(object)
+        } else {
+            recursive_object
+        };
+
+        for parent in select(node, &object.parent.steps) {
+            let children = match parent.as_element() {
+                Some(element) => &element.children,
+                None => continue,
+            };
+
+            let mut claimed: HashSet<usize> = HashSet::new();
+
+            for (index, child) in children.iter().enumerate() {
+                if claimed.contains(&index) || !matches_step(child, &object.step) {
+                    continue;
+                }
+
+                let mut inner_result: HashMap<String, Value> = HashMap::new();
+                let mut remaining: Vec<&AssociatedExtraction> = object.associated.iter().collect();
+
+                for (subsequent_index, subsequent_child) in children.iter().enumerate().skip(index + 1) {
+                    if claimed.contains(&subsequent_index) {
+                        continue;
+                    }
+
+                    if let Some(position) = remaining
+                        .iter()
+                        .position(|associated| matches_step(subsequent_child, &associated.step))
+                    {
+                        let associated = remaining.remove(position);
+
+                        for (key, value) in run_scope(&associated.scope, subsequent_child, inner_recursive_object) {
+                            insert_value(&mut inner_result, &key, value);
+                        }
+
+                        claimed.insert(subsequent_index);
+                    }
+                }
+
+                for (key, value) in run_scope(&object.scope, child, inner_recursive_object) {
+                    insert_value(&mut inner_result, &key, value);
+                }
+
+                claimed.insert(index);
+                insert_value(&mut result, &object.name, json!(inner_result));
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basis_graph::BasisGraph;
+    use crate::basis_network::BasisNetwork;
+    use crate::basis_node::BasisNode;
+    use crate::profile::Profile;
+    use crate::transformation::{FieldTransformation, FieldMetadata};
+    use crate::traverse::{traverse_document, traverse_meta_context};
+
+    const LISTING: &str = concat!(
+        r#"<html><body><div class="listing">"#,
+        r#"<div class="item"><a href="/lamp">Lamp</a><span class="price">12</span></div>"#,
+        r#"<div class="item"><a href="/chair">Chair</a><span class="price">30</span></div>"#,
+        r#"</div></body></html>"#,
+    );
+
+    // The same listing, with an attribute added to a link and an element the
+    // default profile blacklists
+    const CHANGED_LISTING: &str = concat!(
+        r#"<html><body><div class="listing">"#,
+        r#"<div class="item"><a class="link" href="/lamp">Lamp</a><script>track()</script><span class="price">12</span></div>"#,
+        r#"<div class="item"><a href="/chair">Chair</a><span class="price">30</span></div>"#,
+        r#"</div></body></html>"#,
+    );
+
+    fn field_transformation(field: &str, image: &str) -> FieldTransformation {
+        FieldTransformation {
+            id: ID::new(),
+            description: format!("The {}", image),
+            field: field.to_string(),
+            image: image.to_string(),
+            meta: FieldMetadata::from_confidence(1.0),
+        }
+    }
+
+    fn basis_node(context: &Context, transformations: Vec<FieldTransformation>) -> (ID, Arc<BasisNode>) {
+        let basis_node = BasisNode {
+            id: ID::new(),
+            hash: context.data_node.hash.clone(),
+            lineage: context.lineage.clone(),
+            description: String::new(),
+            transformations,
+            eliminations: Vec::new(),
+            profile_id: None,
+        };
+
+        (basis_node.id.clone(), Arc::new(basis_node))
+    }
+
+    fn find_context<'a>(contexts: &'a HashMap<ID, Arc<Context>>, field: &str, value: &str) -> &'a Arc<Context> {
+        contexts
+            .values()
+            .find(|context| read_lock!(context.document_node).get_fields().get(field).map(|found| found.trim()) == Some(value))
+            .unwrap()
+    }
+
+    // Learns the listing as the LLM would: items are objects, with the link
+    // target, link text and price as fields
+    fn learn(html: &str) -> Arc<RwLock<MetaContext>> {
+        let meta_context = Arc::new(RwLock::new(MetaContext::new()));
+        write_lock!(meta_context).update_profile(Arc::new(Profile::default_profile(&HashMap::new())));
+
+        let document = Document::from_string(html.to_string(), &None).unwrap();
+        let (contexts, graph_root) = traverse_document(document, Arc::clone(&meta_context)).unwrap();
+
+        let item_subgraph_hash = read_lock!(find_context(&contexts, "class", "item").graph_node)
+            .subgraph_hash
+            .to_string()
+            .unwrap();
+
+        let basis_nodes = HashMap::from([
+            basis_node(find_context(&contexts, "href", "/lamp"), vec![field_transformation("href", "url")]),
+            basis_node(find_context(&contexts, "text", "Lamp"), vec![field_transformation("text", "name")]),
+            basis_node(find_context(&contexts, "text", "12"), vec![field_transformation("text", "price")]),
+        ]);
+
+        let item_network = BasisNetwork {
+            id: ID::new(),
+            name: "item".to_string(),
+            description: "An item for sale".to_string(),
+            relationship: NetworkRelationship::Association(Vec::new()),
+            subgraph_hash: item_subgraph_hash,
+            confidence: 1.0,
+            needs_review: false,
+            suspicious_content: false,
+        };
+
+        {
+            let mut lock = write_lock!(meta_context);
+            lock.update_data_structures(contexts, graph_root);
+            lock.update_basis_graph(Arc::new(BasisGraph {
+                id: ID::new(),
+                lineage: Lineage::new(),
+                name: "listing_page".to_string(),
+                description: "A page listing items for sale".to_string(),
+                structure: String::new(),
+                suspicious_content: false,
+                needs_review: false,
+            }));
+            lock.update_basis_nodes(basis_nodes);
+            lock.update_basis_networks(HashMap::from([(item_network.id.clone(), Arc::new(item_network))]));
+        }
+
+        meta_context
+    }
+
+    fn parse(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn plans_extract_what_normalization_does() {
+        let meta_context = learn(LISTING);
+
+        let normalized = traverse_meta_context(Arc::clone(&meta_context), &None).unwrap();
+        let plan = ExtractionPlan::compile(meta_context).unwrap();
+        let extracted = plan.run_text(LISTING.to_string()).unwrap();
+
+        assert_eq!(parse(&extracted), parse(&normalized.data));
+        assert_eq!(parse(&extracted), json!({
+            "item": [
+                { "url": "/lamp", "name": "Lamp", "price": "12" },
+                { "url": "/chair", "name": "Chair", "price": "30" },
+            ]
+        }));
+    }
+
+    #[test]
+    fn plans_tolerate_added_attributes_and_blacklisted_elements() {
+        let meta_context = learn(LISTING);
+
+        let normalized = traverse_meta_context(Arc::clone(&meta_context), &None).unwrap();
+        let plan = ExtractionPlan::from_json(&ExtractionPlan::compile(meta_context).unwrap().to_json().unwrap()).unwrap();
+        let extracted = plan.run_text(CHANGED_LISTING.to_string()).unwrap();
+
+        assert_eq!(parse(&extracted), parse(&normalized.data));
+    }
+
+    #[test]
+    fn plans_of_a_profile_merge() {
+        let mut plan = ExtractionPlan::compile(learn(LISTING)).unwrap();
+        let mut other = ExtractionPlan::compile(learn(LISTING)).unwrap();
+
+        assert!(plan.merge(other.clone()).is_err());
+
+        other.profile_id = plan.profile_id.clone();
+        other.scope.fields.push(FieldExtraction {
+            key: "title".to_string(),
+            description: "The title".to_string(),
+            field: "text".to_string(),
+            selector: Selector::from_steps(Vec::new()),
+        });
+
+        plan.merge(other).unwrap();
+
+        assert_eq!(plan.scope.fields.len(), 1);
+        assert_eq!(plan.scope.objects.len(), 1);
+    }
+}
@@ -0,0 +1,458 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::basis_network::{BasisNetwork, NetworkRelationship};
+use crate::graph_node::Graph;
+
+type Node = usize;
+type Edge = (usize, usize);
+
+struct DotNode {
+    description: String,
+    subgraph_hash: String,
+    fields: Vec<(String, Option<String>)>,
+    network: Option<Arc<BasisNetwork>>,
+    parent: Option<Node>,
+}
+
+impl DotNode {
+    fn is_null_network(&self) -> bool {
+        self.network.as_ref().is_some_and(|network| network.is_null_network())
+    }
+
+    fn is_eliminated(&self) -> bool {
+        !self.fields.is_empty() && self.fields.iter().all(|(_, image)| image.is_none())
+    }
+}
+
+struct DotGraph {
+    nodes: Vec<DotNode>,
+    edges: Vec<Edge>,
+}
+
+impl<'a> dot::Labeller<'a, Node, Edge> for DotGraph {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("parversion").unwrap()
+    }
+
+    fn node_id(&'a self, n: &Node) -> dot::Id<'a> {
+        dot::Id::new(format!("N{}", n)).unwrap()
+    }
+
+    fn node_label(&'a self, n: &Node) -> dot::LabelText<'a> {
+        let node = &self.nodes[*n];
+        let hash: String = node.subgraph_hash.chars().take(12).collect();
+
+        let mut lines = vec![node.description.trim().to_string(), hash];
+
+        for (field, image) in node.fields.iter() {
+            match image {
+                Some(image) => lines.push(format!("{} -> {}", field, image)),
+                None => lines.push(format!("{} (eliminated)", field)),
+            }
+        }
+
+        dot::LabelText::label(lines.join("\n"))
+    }
+
+    fn node_style(&'a self, n: &Node) -> dot::Style {
+        let node = &self.nodes[*n];
+
+        if node.is_null_network() {
+            dot::Style::Dashed
+        } else if node.is_eliminated() {
+            dot::Style::Dotted
+        } else {
+            dot::Style::None
+        }
+    }
+
+    fn node_color(&'a self, n: &Node) -> Option<dot::LabelText<'a>> {
+        let node = &self.nodes[*n];
+
+        if node.is_null_network() {
+            Some(dot::LabelText::label("gray"))
+        } else if node.is_eliminated() {
+            Some(dot::LabelText::label("red"))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> dot::GraphWalk<'a, Node, Edge> for DotGraph {
+    fn nodes(&'a self) -> dot::Nodes<'a, Node> {
+        Cow::Owned((0..self.nodes.len()).collect())
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, Edge> {
+        Cow::Borrowed(&self.edges[..])
+    }
+
+    fn source(&'a self, edge: &Edge) -> Node {
+        edge.0
+    }
+
+    fn target(&'a self, edge: &Edge) -> Node {
+        edge.1
+    }
+}
+
+pub fn render_meta_context(meta_context: &MetaContext) -> Result<String, Errors> {
+    log::trace!("In render_meta_context");
+
+    let graph_root = meta_context.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?;
+    let contexts = meta_context.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
+
+    let mut graph = DotGraph {
+        nodes: Vec::new(),
+        edges: Vec::new(),
+    };
+    let mut visited: HashSet<ID> = HashSet::new();
+    let mut queue: VecDeque<(Graph, Option<Node>)> = VecDeque::new();
+    queue.push_back((graph_root, None));
+
+    while let Some((current, parent)) = queue.pop_front() {
+        let lock = read_lock!(current);
+
+        if !visited.insert(lock.id.clone()) {
+            continue;
+        }
+
+        let context = contexts.get(&lock.id).ok_or(Errors::ContextsNotProvided)?;
+        let subgraph_hash = lock.subgraph_hash.to_string().unwrap_or_default();
+
+        let network = if meta_context.basis_networks.is_some() {
+            meta_context.get_basis_network_by_subgraph_hash(&subgraph_hash)?
+        } else {
+            None
+        };
+
+        let basis_node = if meta_context.basis_nodes.is_some() {
+            meta_context.get_basis_node_by_lineage(&context.lineage)?
+        } else {
+            None
+        };
+
+        let mut fields: Vec<(String, Option<String>)> = match basis_node {
+            Some(basis_node) => context.data_node.fields
+                .keys()
+                .map(|field| {
+                    let image = basis_node.transformations
+                        .iter()
+                        .find(|transformation| transformation.field == *field)
+                        .map(|transformation| transformation.image.clone());
+
+                    (field.clone(), image)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        fields.sort();
+
+        let index = graph.nodes.len();
+
+        graph.nodes.push(DotNode {
+            description: lock.description.clone(),
+            subgraph_hash,
+            fields,
+            network,
+            parent,
+        });
+
+        if let Some(parent) = parent {
+            graph.edges.push((parent, index));
+        }
+
+        for child in lock.children.iter() {
+            queue.push_back((Arc::clone(child), Some(index)));
+        }
+    }
+
+    let mut output = Vec::new();
+
+    dot::render(&graph, &mut output).map_err(|err| {
+        log::error!("Could not render graph: {}", err);
+        Errors::from(err)
+    })?;
+
+    let mut output = String::from_utf8(output).map_err(|err| {
+        log::error!("Rendered graph is not valid UTF-8: {}", err);
+        Errors::from(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    })?;
+
+    // The dot crate has no notion of subgraphs, so clusters are appended to
+    // the rendered graph before it is closed.
+    let closing = output.rfind('}').ok_or_else(|| {
+        log::error!("Rendered graph is not closed");
+        Errors::from(std::io::Error::new(std::io::ErrorKind::InvalidData, "rendered graph is not closed"))
+    })?;
+    output.truncate(closing);
+
+    let clusters = get_clusters(&graph);
+    let parents = get_cluster_parents(&clusters);
+
+    for (index, parent) in parents.iter().enumerate() {
+        if parent.is_none() {
+            render_cluster(&mut output, &clusters, &parents, index, 1);
+        }
+    }
+
+    output.push_str("}\n");
+
+    Ok(output)
+}
+
+struct Cluster {
+    network_id: ID,
+    label: String,
+    members: HashSet<Node>,
+}
+
+// A cluster holds the subtree of a node whose subgraph hash maps to a
+// network, along with the subtrees of the siblings it is associated with
+fn get_clusters(graph: &DotGraph) -> Vec<Cluster> {
+    let mut children: HashMap<Node, Vec<Node>> = HashMap::new();
+
+    for (parent, child) in graph.edges.iter() {
+        children.entry(*parent).or_default().push(*child);
+    }
+
+    let get_subtree = |root: Node| -> Vec<Node> {
+        let mut subtree = Vec::new();
+        let mut queue: VecDeque<Node> = VecDeque::from([root]);
+
+        while let Some(node) = queue.pop_front() {
+            subtree.push(node);
+            queue.extend(children.get(&node).into_iter().flatten());
+        }
+
+        subtree
+    };
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let network = match &node.network {
+            Some(network) if !network.is_null_network() => network,
+            _ => continue,
+        };
+
+        // Siblings associated with a root already clustered belong to its
+        // cluster rather than one of their own
+        if clusters.iter().any(|cluster| cluster.network_id == network.id && cluster.members.contains(&index)) {
+            continue;
+        }
+
+        let mut roots = vec![index];
+
+        if let (NetworkRelationship::Association(associated), Some(parent)) = (&network.relationship, node.parent) {
+            roots.extend(children[&parent].iter().filter(|sibling| {
+                **sibling != index && associated.contains(&graph.nodes[**sibling].subgraph_hash)
+            }));
+        }
+
+        clusters.push(Cluster {
+            network_id: network.id.clone(),
+            label: network.name.clone(),
+            members: roots.into_iter().flat_map(get_subtree).collect(),
+        });
+    }
+
+    clusters
+}
+
+// Each cluster is nested in the smallest other cluster containing all of
+// its members
+fn get_cluster_parents(clusters: &[Cluster]) -> Vec<Option<usize>> {
+    clusters
+        .iter()
+        .enumerate()
+        .map(|(index, cluster)| {
+            clusters
+                .iter()
+                .enumerate()
+                .filter(|(other_index, other)| {
+                    *other_index != index
+                        && cluster.members.is_subset(&other.members)
+                        && (other.members.len() > cluster.members.len() || *other_index < index)
+                })
+                .min_by_key(|(_, other)| other.members.len())
+                .map(|(other_index, _)| other_index)
+        })
+        .collect()
+}
+
+fn render_cluster(
+    output: &mut String,
+    clusters: &[Cluster],
+    parents: &[Option<usize>],
+    index: usize,
+    depth: usize,
+) {
+    let indent = "    ".repeat(depth);
+    let cluster = &clusters[index];
+
+    output.push_str(&format!("{}subgraph cluster_{} {{\n", indent, index));
+    output.push_str(&format!(
+        "{}    label={};\n",
+        indent,
+        dot::LabelText::label(cluster.label.as_str()).to_dot_string()
+    ));
+
+    let mut nested: HashSet<Node> = HashSet::new();
+
+    for (child, parent) in parents.iter().enumerate() {
+        if *parent == Some(index) {
+            render_cluster(output, clusters, parents, child, depth + 1);
+            nested.extend(clusters[child].members.iter());
+        }
+    }
+
+    let mut members: Vec<&Node> = cluster.members.difference(&nested).collect();
+    members.sort();
+
+    for node in members {
+        output.push_str(&format!("{}    N{};\n", indent, node));
+    }
+
+    output.push_str(&format!("{}}}\n", indent));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use crate::basis_node::BasisNode;
+    use crate::context::Context;
+    use crate::document::Document;
+    use crate::profile::Profile;
+    use crate::transformation::{FieldTransformation, FieldMetadata};
+    use crate::traverse::traverse_document;
+
+    const PRODUCT: &str = r#"<html><body><div><h2>Lamp</h2><span class="price">12</span></div><p><em>Ships</em> tomorrow</p></body></html>"#;
+
+    fn find_context<'a>(contexts: &'a HashMap<ID, Arc<Context>>, field: &str, value: &str) -> &'a Arc<Context> {
+        contexts
+            .values()
+            .find(|context| read_lock!(context.document_node).get_fields().get(field).map(|found| found.trim()) == Some(value))
+            .unwrap()
+    }
+
+    fn get_ancestor_subgraph_hash(context: &Context, generations: usize) -> String {
+        let mut ancestor = Arc::clone(&context.graph_node);
+
+        for _ in 0..generations {
+            let parent = Arc::clone(&read_lock!(ancestor).parents[0]);
+            ancestor = parent;
+        }
+
+        let subgraph_hash = read_lock!(ancestor).subgraph_hash.to_string().unwrap();
+
+        subgraph_hash
+    }
+
+    fn network(name: &str, subgraph_hash: String, relationship: NetworkRelationship) -> (ID, Arc<BasisNetwork>) {
+        let network = BasisNetwork {
+            id: ID::new(),
+            name: name.to_string(),
+            description: String::new(),
+            relationship,
+            subgraph_hash,
+            confidence: 1.0,
+            needs_review: false,
+            suspicious_content: false,
+        };
+
+        (network.id.clone(), Arc::new(network))
+    }
+
+    #[test]
+    fn meta_contexts_render_networks_as_clusters() {
+        let meta_context = Arc::new(RwLock::new(MetaContext::new()));
+        write_lock!(meta_context).update_profile(Arc::new(Profile::default_profile(&HashMap::new())));
+
+        let document = Document::from_string(PRODUCT.to_string(), &None).unwrap();
+        let (contexts, graph_root) = traverse_document(document, Arc::clone(&meta_context)).unwrap();
+
+        let name = find_context(&contexts, "text", "Lamp");
+        // Text is a node of its own, beneath the element it is written in
+        let heading = get_ancestor_subgraph_hash(name, 1);
+        let offer = get_ancestor_subgraph_hash(name, 2);
+        let price = get_ancestor_subgraph_hash(find_context(&contexts, "text", "12"), 1);
+
+        let basis_node = BasisNode {
+            id: ID::new(),
+            hash: name.data_node.hash.clone(),
+            lineage: name.lineage.clone(),
+            description: String::new(),
+            transformations: vec![FieldTransformation {
+                id: ID::new(),
+                description: "The name".to_string(),
+                field: "text".to_string(),
+                image: "name".to_string(),
+                meta: FieldMetadata::from_confidence(1.0),
+            }],
+            eliminations: Vec::new(),
+            profile_id: None,
+        };
+
+        {
+            let mut lock = write_lock!(meta_context);
+            lock.update_data_structures(contexts.clone(), graph_root);
+            lock.update_basis_nodes(HashMap::from([(basis_node.id.clone(), Arc::new(basis_node))]));
+            lock.update_basis_networks(HashMap::from([
+                network("offer", offer, NetworkRelationship::Association(Vec::new())),
+                network("product", heading, NetworkRelationship::Association(vec![price])),
+            ]));
+        }
+
+        let rendered = render_meta_context(&read_lock!(meta_context)).unwrap();
+
+        assert_eq!(rendered, concat!(
+            "digraph parversion {\n",
+            "    N0[label=\"html\\n119d6b2c0f9d\"];\n",
+            "    N1[label=\"head\\n7c29564cbf79\"];\n",
+            "    N2[label=\"body\\n119d6b2c0f9d\"];\n",
+            "    N3[label=\"div\\nafe6287f0fc1\"];\n",
+            "    N4[label=\"p\\n502ad4f8c0a7\"];\n",
+            "    N5[label=\"h2\\n46f0e1b07681\"];\n",
+            "    N6[label=\"span\\n246b4037243d\"];\n",
+            "    N7[label=\"em\\n46f0e1b07681\"];\n",
+            "    N8[label=\"tomorrow\\n2cbb4a15c1f6\"];\n",
+            "    N9[label=\"Lamp\\n2cbb4a15c1f6\\ntext -> name\"];\n",
+            "    N10[label=\"12\\n2cbb4a15c1f6\"];\n",
+            "    N11[label=\"Ships\\n2cbb4a15c1f6\\ntext -> name\"];\n",
+            "    N0 -> N1[label=\"\"];\n",
+            "    N0 -> N2[label=\"\"];\n",
+            "    N2 -> N3[label=\"\"];\n",
+            "    N2 -> N4[label=\"\"];\n",
+            "    N3 -> N5[label=\"\"];\n",
+            "    N3 -> N6[label=\"\"];\n",
+            "    N4 -> N7[label=\"\"];\n",
+            "    N4 -> N8[label=\"\"];\n",
+            "    N5 -> N9[label=\"\"];\n",
+            "    N6 -> N10[label=\"\"];\n",
+            "    N7 -> N11[label=\"\"];\n",
+            "    subgraph cluster_0 {\n",
+            "        label=\"offer\";\n",
+            "        subgraph cluster_1 {\n",
+            "            label=\"product\";\n",
+            "            N5;\n",
+            "            N6;\n",
+            "            N9;\n",
+            "            N10;\n",
+            "        }\n",
+            "        N3;\n",
+            "    }\n",
+            "    subgraph cluster_2 {\n",
+            "        label=\"product\";\n",
+            "        N7;\n",
+            "        N11;\n",
+            "    }\n",
+            "}\n",
+        ));
+    }
+}
@@ -8,8 +8,12 @@ pub mod data_node;
 pub mod document;
 pub mod document_format;
 pub mod document_node;
+pub mod drift;
+pub mod dry_run;
 pub mod environment;
+pub mod extraction;
 pub mod graph_node;
+pub mod graphviz;
 pub mod hash;
 pub mod id;
 pub mod lineage;
@@ -17,19 +21,25 @@ pub mod macros;
 pub mod normalization;
 pub mod organization;
 pub mod profile;
+pub mod profile_schema;
 pub mod provider;
+pub mod sandbox;
 pub mod transformation;
+pub mod transformation_report;
 pub mod translation;
 pub mod types;
 pub mod prelude;
 pub mod utility;
+pub mod worker;
 pub mod json_node;
 pub mod context;
 pub mod context_group;
 pub mod llm;
 pub mod traverse;
 pub mod meta_context;
+pub mod minhash;
 pub mod schema;
 pub mod network_analysis;
 pub mod node_analysis;
 pub mod schema_node;
+pub mod token_usage;
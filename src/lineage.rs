@@ -37,6 +37,34 @@ impl Lineage {
         }
     }
 
+    /// Number of trailing hashes two lineages have in common, i.e. how many
+    /// generations of a node and its ancestors are identical
+    pub fn get_shared_ancestry(&self, other: &Lineage) -> usize {
+        self.source_hashes
+            .iter()
+            .rev()
+            .zip(other.source_hashes.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// Number of generations from the root down to the node
+    pub fn get_depth(&self) -> usize {
+        self.source_hashes.len()
+    }
+
+    /// Lineage of the ancestor a number of generations up
+    pub fn get_ancestor(&self, generations: usize) -> Lineage {
+        let depth = self.source_hashes.len().saturating_sub(generations);
+
+        Lineage::from_hashes(self.source_hashes[..depth].to_vec())
+    }
+
+    /// Whether a node of this lineage is, or is beneath, one of the other
+    pub fn descends_from(&self, ancestor: &Lineage) -> bool {
+        self.source_hashes.starts_with(&ancestor.source_hashes)
+    }
+
     pub fn to_string(&self) -> String {
         self.identity_hash.to_string().clone().unwrap()
     }
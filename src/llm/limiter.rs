@@ -0,0 +1,223 @@
+use lazy_static::lazy_static;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, OwnedSemaphorePermit};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+
+// Rough ratio of characters to tokens for English text and markup
+const CHARACTERS_PER_TOKEN: usize = 4;
+
+lazy_static! {
+    // Shared by every stage that talks to the LLM, so that concurrent node
+    // analysis, network analysis and summarization never exceed
+    // llm.max_concurrency requests in flight between them
+    static ref LLM_LIMITER: Arc<Semaphore> = {
+        let max_concurrency = read_lock!(CONFIG).llm.max_concurrency.max(1);
+
+        Arc::new(Semaphore::new(max_concurrency))
+    };
+
+    static ref RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::from_config());
+}
+
+/// Token bucket refilled continuously up to its capacity
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_second: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(capacity: u64) -> Self {
+        let capacity = capacity.max(1) as f64;
+
+        TokenBucket {
+            capacity,
+            available: capacity,
+            refill_per_second: capacity / 60.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+
+        self.available = (self.available + elapsed * self.refill_per_second).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// How long until the amount is available. A request larger than the
+    /// bucket only waits for a full bucket, otherwise it could never run.
+    fn get_wait(&self, amount: f64) -> Duration {
+        let amount = amount.min(self.capacity);
+
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) / self.refill_per_second)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+}
+
+/// Requests per minute and tokens per minute limits, shared by all LLM calls.
+/// A limit that is not configured is not enforced.
+struct RateLimiter {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn from_config() -> Self {
+        let lock = read_lock!(CONFIG);
+
+        RateLimiter {
+            requests: lock.llm.requests_per_minute.map(TokenBucket::per_minute),
+            tokens: lock.llm.tokens_per_minute.map(TokenBucket::per_minute),
+        }
+    }
+
+    fn try_acquire(&mut self, tokens: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets: Vec<(&mut TokenBucket, f64)> = [
+            self.requests.as_mut().map(|bucket| (bucket, 1.0)),
+            self.tokens.as_mut().map(|bucket| (bucket, tokens as f64)),
+        ].into_iter().flatten().collect();
+
+        for (bucket, _) in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+
+        let wait = buckets
+            .iter()
+            .map(|(bucket, amount)| bucket.get_wait(*amount))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if wait.is_zero() {
+            for (bucket, amount) in buckets.iter_mut() {
+                bucket.take(*amount);
+            }
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+}
+
+/// Waits for a free slot under the global LLM concurrency limit. The slot is
+/// held until the returned permit is dropped.
+pub async fn acquire_llm_permit() -> Result<OwnedSemaphorePermit, Errors> {
+    log::trace!("In acquire_llm_permit");
+
+    LLM_LIMITER.clone().acquire_owned().await.map_err(|_| Errors::UnexpectedError)
+}
+
+/// Waits until a request of the estimated size fits within the requests per
+/// minute and tokens per minute limits, and reserves it
+pub async fn wait_for_rate_limit(tokens: usize) {
+    log::trace!("In wait_for_rate_limit");
+
+    loop {
+        let wait = {
+            let mut lock = RATE_LIMITER.lock().unwrap();
+
+            match lock.try_acquire(tokens) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            }
+        };
+
+        log::debug!("Rate limited, waiting {}ms", wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Exponential backoff with jitter for a retry attempt, starting at zero.
+/// A delay requested by the server through Retry-After takes precedence.
+/// Either is capped at llm.retry_max_delay_ms.
+pub fn get_retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let (base_delay_ms, max_delay_ms) = {
+        let lock = read_lock!(CONFIG);
+        (lock.llm.retry_base_delay_ms, lock.llm.retry_max_delay_ms)
+    };
+
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(Duration::from_millis(max_delay_ms));
+    }
+
+    let delay_ms = base_delay_ms
+        .saturating_mul(2u64.saturating_pow(attempt))
+        .min(max_delay_ms);
+
+    // Somewhere between half and all of the delay, so that concurrent
+    // requests failing together do not retry together
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let jitter = hasher.finish() % (delay_ms / 2 + 1);
+
+    Duration::from_millis(delay_ms - jitter)
+}
+
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARACTERS_PER_TOKEN)
+}
+
+/// Characters of text that make up roughly the given number of tokens
+pub fn get_character_limit(tokens: usize) -> usize {
+    tokens.saturating_mul(CHARACTERS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_wait_covers_the_missing_tokens() {
+        let mut bucket = TokenBucket::per_minute(60);
+
+        assert_eq!(bucket.get_wait(10.0), Duration::ZERO);
+
+        bucket.take(60.0);
+
+        assert_eq!(bucket.get_wait(10.0), Duration::from_secs(10));
+
+        let refilled_at = bucket.refilled_at;
+        bucket.refill(refilled_at + Duration::from_secs(4));
+
+        assert_eq!(bucket.get_wait(10.0), Duration::from_secs(6));
+
+        bucket.refill(refilled_at + Duration::from_secs(600));
+
+        assert_eq!(bucket.available, 60.0);
+    }
+
+    #[test]
+    fn get_wait_caps_requests_larger_than_the_bucket() {
+        let mut bucket = TokenBucket::per_minute(60);
+
+        assert_eq!(bucket.get_wait(600.0), Duration::ZERO);
+
+        bucket.take(600.0);
+
+        assert_eq!(bucket.available, 0.0);
+        assert_eq!(bucket.get_wait(600.0), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn get_retry_delay_caps_retry_after() {
+        let max_delay = Duration::from_millis(read_lock!(CONFIG).llm.retry_max_delay_ms);
+
+        assert_eq!(get_retry_delay(0, Some(Duration::from_millis(1))), Duration::from_millis(1));
+        assert_eq!(get_retry_delay(0, Some(max_delay * 10)), max_delay);
+        assert!(get_retry_delay(20, None) <= max_delay);
+    }
+}
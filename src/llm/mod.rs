@@ -1,61 +1,367 @@
+use futures::future::try_join_all;
+use std::sync::Arc;
+
 use crate::prelude::*;
 use crate::transformation::FieldTransformation;
 use crate::context_group::ContextGroup;
+use crate::profile::ProfileProposal;
+use crate::config::{CONFIG};
+use crate::token_usage::{TokenUsage, LlmStage};
 
 mod openai;
+mod limiter;
+mod prompts;
+
+pub use limiter::{estimate_tokens, get_character_limit};
+pub use prompts::find_instructions;
+use prompts::Prompts;
+
+// Expected length of a response, which cannot be known before it is sent
+const ESTIMATED_COMPLETION_TOKENS: usize = 150;
+const ESTIMATED_COMPLETION_TOKENS_PER_FIELD: usize = 100;
 
 pub struct LLM {}
 
 impl LLM {
-    pub async fn categorize_and_summarize(document: String) -> Result<(
+    /// Summarizes a document from its chunks. A document in several chunks
+    /// has each chunk summarized on its own, concurrently, and the summaries
+    /// combined into one. The summary is flagged when the document, or the
+    /// summary itself, reads like instructions to the LLM.
+    pub async fn categorize_and_summarize(token_usage: Arc<TokenUsage>, chunks: Vec<String>) -> Result<(
         String, // name
         String, // description
-        String // structure
+        String, // structure
+        bool // suspicious content
     ), Errors> {
         log::trace!("In categorize_and_summarize");
 
-        let (name, description, structure) = openai::OpenAI::categorize_summarize(&document).await?;
+        let chunks = select_summary_chunks(chunks);
+        let (name, description, structure) = summarize_chunks(&token_usage, &chunks).await?;
+
+        let instructions = chunks
+            .iter()
+            .chain([&name, &description, &structure])
+            .find_map(|content| find_instructions(content));
+
+        if let Some(instructions) = &instructions {
+            log::warn!("Document reads like instructions to the LLM, flagging basis graph for review: {}", instructions);
+        }
 
-        Ok((name, description, structure))
+        Ok((name, description, structure, instructions.is_some()))
     }
 
+    /// Fields decided from content that reads like instructions to the LLM
+    /// are flagged for review. Eliminated fields are returned, marked as
+    /// such, only when their decision needs review.
     pub async fn get_field_transformations(
+        token_usage: Arc<TokenUsage>,
         context_group: ContextGroup,
     ) -> Result<Vec<FieldTransformation>, Errors> {
         log::trace!("In get_field_transformation");
 
-        let mut field_transformations = Vec::new();
-
-        for (field, value) in context_group.fields.into_iter() {
-            match openai::OpenAI::get_field_transformation(
-                &context_group.lineage,
-                &field,
-                &value,
-                context_group.snippets.clone()
-            ).await {
-                Some(transformation) => field_transformations.push(transformation),
-                None => {
-                    log::info!("Field eliminated");
-                }
-            }
+        let lineage = &context_group.lineage;
+        let snippets = &context_group.snippets;
+
+        let mut fields: Vec<(String, String)> = context_group.fields.clone().into_iter().collect();
+        fields.sort();
+
+        let instructions = snippets
+            .iter()
+            .chain(fields.iter().map(|(_, value)| value))
+            .find_map(|content| find_instructions(content));
+
+        if let Some(instructions) = &instructions {
+            log::warn!("Content of {} reads like instructions to the LLM, flagging its fields for review: {}", lineage.to_string(), instructions);
         }
 
-        Ok(field_transformations)
+        let field_transformations = decide_field_transformations(&token_usage, lineage, &fields, snippets).await?;
+
+        // A description that repeats instruction-like text is flagged too,
+        // since it is shown to later requests and persisted as is
+        Ok(field_transformations
+            .into_iter()
+            .map(|transformation| {
+                let repeats_instructions = find_instructions(&transformation.description);
+
+                if let Some(repeated) = &repeats_instructions {
+                    log::warn!("Description of {} reads like instructions to the LLM, flagging it for review: {}", transformation.image, repeated);
+                }
+
+                FieldTransformation {
+                    meta: transformation.meta.with_suspicious_content(instructions.is_some() || repeats_instructions.is_some()),
+                    ..transformation
+                }
+            })
+            .filter(|transformation| {
+                if !transformation.meta.eliminated {
+                    return true;
+                }
+
+                if transformation.meta.needs_review {
+                    log::warn!(
+                        "Field {} eliminated with confidence {:.2}, keeping it for review",
+                        transformation.field,
+                        transformation.meta.confidence
+                    );
+                } else {
+                    log::info!("Eliminating unmeaningful field: {}", transformation.field);
+                }
+
+                transformation.meta.needs_review
+            })
+            .collect())
+    }
+
+    pub async fn get_recursion(
+        token_usage: Arc<TokenUsage>,
+        overall_context: String,
+        json: String,
+    ) -> Result<(String, String), Errors> {
+        log::trace!("In get_recursion");
+
+        let (name, description) = openai::OpenAI::get_recursion(
+            &token_usage,
+            overall_context.clone(),
+            json.clone(),
+        ).await.stage(Stage::LLM)?;
+
+        Ok((name, description))
+    }
+
+    pub async fn propose_profile(
+        token_usage: Arc<TokenUsage>,
+        inventory: String,
+        sample: String,
+    ) -> Result<ProfileProposal, Errors> {
+        log::trace!("In propose_profile");
+
+        openai::OpenAI::propose_profile(&token_usage, &inventory, &sample).await.stage(Stage::LLM)
     }
 
     pub async fn get_relationships(
+        token_usage: Arc<TokenUsage>,
         overall_context: String,
         target_subgraph_hash: String,
         subgraphs: Vec<(String, String)>
-    ) -> Result<(String, Vec<String>, String), Errors> {
+    ) -> Result<(String, Vec<String>, String, f64), Errors> {
         log::trace!("In get_relationships");
 
-        let (name, matches, description) = openai::OpenAI::get_relationships(
+        let (name, matches, description, confidence) = openai::OpenAI::get_relationships(
+            &token_usage,
             overall_context.clone(),
             target_subgraph_hash.clone(),
             subgraphs.clone(),
-        ).await?;
+        ).await.stage(Stage::LLM)?;
+
+        Ok((name, matches, description, confidence))
+    }
+
+    /// The estimate_ functions record the requests their counterparts would
+    /// make, sized from the same prompts, without sending anything
+    pub fn estimate_categorize_and_summarize(token_usage: &TokenUsage, chunks: Vec<String>) {
+        let chunks = select_summary_chunks(chunks);
+
+        if let [document] = chunks.as_slice() {
+            let prompts = openai::OpenAI::get_summary_prompts(document);
+
+            record_estimate(token_usage, LlmStage::Summary, prompts, ESTIMATED_COMPLETION_TOKENS);
+            return;
+        }
+
+        for (index, chunk) in chunks.iter().enumerate() {
+            let prompts = openai::OpenAI::get_summary_chunk_prompts(index + 1, chunks.len(), chunk);
+
+            record_estimate(token_usage, LlmStage::Summary, prompts, ESTIMATED_COMPLETION_TOKENS);
+        }
+
+        // The combined summaries are as long as the responses that make them
+        let empty_summaries = vec![(String::new(), String::new(), String::new()); chunks.len()];
+        let prompts = openai::OpenAI::get_summary_combination_prompts(&empty_summaries);
+
+        token_usage.record(
+            LlmStage::Summary,
+            estimate_tokens(&prompts.system) + estimate_tokens(&prompts.user) + ESTIMATED_COMPLETION_TOKENS * chunks.len(),
+            ESTIMATED_COMPLETION_TOKENS,
+        );
+    }
+
+    pub fn estimate_field_transformations(token_usage: &TokenUsage, context_group: &ContextGroup) {
+        let mut fields: Vec<(String, String)> = context_group.fields.clone().into_iter().collect();
+        fields.sort();
+
+        if fields.is_empty() {
+            return;
+        }
+
+        let lineage = &context_group.lineage;
+        let snippets = &context_group.snippets;
+
+        if read_lock!(CONFIG).llm.batch_field_decisions {
+            let prompts = openai::OpenAI::get_field_decisions_prompts(lineage, &fields, snippets);
+            let completion_tokens = ESTIMATED_COMPLETION_TOKENS_PER_FIELD * fields.len();
+
+            record_votes_estimate(token_usage, LlmStage::FieldDecisions, prompts, completion_tokens);
+            return;
+        }
+
+        // Assumes no field is eliminated or found peripheral, which makes
+        // this an upper bound
+        for (field, value) in fields.iter() {
+            let elimination_prompts = if field == "text" {
+                openai::OpenAI::get_text_elimination_prompts(lineage, snippets)
+            } else {
+                openai::OpenAI::get_attribute_elimination_prompts(lineage, field, snippets)
+            };
+
+            record_votes_estimate(token_usage, LlmStage::Elimination, elimination_prompts, ESTIMATED_COMPLETION_TOKENS);
+            record_votes_estimate(
+                token_usage,
+                LlmStage::Peripheral,
+                openai::OpenAI::get_peripheral_prompts(lineage, field, value, snippets),
+                ESTIMATED_COMPLETION_TOKENS
+            );
+            record_estimate(
+                token_usage,
+                LlmStage::Primary,
+                openai::OpenAI::get_primary_prompts(lineage, field, value, snippets),
+                ESTIMATED_COMPLETION_TOKENS
+            );
+        }
+    }
+
+    pub fn estimate_recursion(token_usage: &TokenUsage, overall_context: &str, json: &str) {
+        let prompts = openai::OpenAI::get_recursion_prompts(overall_context, json);
+
+        record_estimate(token_usage, LlmStage::Recursion, prompts, ESTIMATED_COMPLETION_TOKENS);
+    }
+
+    pub fn estimate_relationships(
+        token_usage: &TokenUsage,
+        overall_context: &str,
+        target_subgraph_hash: &str,
+        subgraphs: &[(String, String)],
+    ) {
+        let prompts = openai::OpenAI::get_relationships_prompts(overall_context, target_subgraph_hash, subgraphs);
+
+        record_votes_estimate(token_usage, LlmStage::Associations, prompts, ESTIMATED_COMPLETION_TOKENS);
+    }
+
+    pub fn estimate_profile(token_usage: &TokenUsage, inventory: &str, sample: &str) {
+        let prompts = openai::OpenAI::get_profile_prompts(inventory, sample);
+
+        record_estimate(token_usage, LlmStage::Profile, prompts, ESTIMATED_COMPLETION_TOKENS);
+    }
+}
+
+async fn summarize_chunks(
+    token_usage: &TokenUsage,
+    chunks: &[String],
+) -> Result<(String, String, String), Errors> {
+    log::trace!("In summarize_chunks");
+
+    if let [document] = chunks {
+        return openai::OpenAI::categorize_summarize(token_usage, document).await.stage(Stage::LLM);
+    }
+
+    log::info!("Summarizing document in {} chunks", chunks.len());
+
+    let parts = chunks.len();
+    let summaries = try_join_all(
+        chunks.iter().enumerate().map(|(index, chunk)| async move {
+            openai::OpenAI::categorize_summarize_chunk(token_usage, index + 1, parts, chunk).await.stage(Stage::LLM)
+        })
+    ).await?;
+
+    openai::OpenAI::combine_summaries(token_usage, &summaries).await.stage(Stage::LLM)
+}
+
+/// Decides every field in one batched request when configured to, falling
+/// back to deciding each field separately
+async fn decide_field_transformations(
+    token_usage: &TokenUsage,
+    lineage: &Lineage,
+    fields: &[(String, String)],
+    snippets: &[String],
+) -> Result<Vec<FieldTransformation>, Errors> {
+    log::trace!("In decide_field_transformations");
+
+    let batch_field_decisions = read_lock!(CONFIG).llm.batch_field_decisions;
+
+    if batch_field_decisions && !fields.is_empty() {
+        match openai::OpenAI::get_field_transformations(
+            token_usage,
+            lineage,
+            fields,
+            snippets.to_vec()
+        ).await {
+            Ok(field_transformations) => return Ok(field_transformations),
+            // Deciding each field separately would only run out of budget
+            // once per field
+            Err(err) if matches!(err.root_cause(), Errors::BudgetExceeded(_)) => {
+                return Err(err).stage(Stage::LLM);
+            },
+            Err(err) => {
+                log::warn!("Batched field decisions failed, deciding each field separately: {}", err);
+            }
+        }
+    }
+
+    // Each field still runs its decisions in sequence, but fields run
+    // concurrently under the global LLM limiter
+    try_join_all(
+        fields.iter().map(|(field, value)| async move {
+            openai::OpenAI::get_field_transformation(
+                token_usage,
+                lineage,
+                field,
+                value,
+                snippets.to_vec()
+            ).await.stage(Stage::LLM)
+        })
+    ).await
+}
+
+/// Keeps at most llm.summary_max_chunks chunks, spread evenly over the
+/// document so that the summary does not only see its beginning
+fn select_summary_chunks(chunks: Vec<String>) -> Vec<String> {
+    let max_chunks = read_lock!(CONFIG).llm.summary_max_chunks.max(1);
+
+    if chunks.len() <= max_chunks {
+        return chunks;
+    }
+
+    log::warn!("Summarizing {} of {} document chunks", max_chunks, chunks.len());
+
+    let step = chunks.len() as f64 / max_chunks as f64;
+
+    (0..max_chunks)
+        .map(|index| chunks[(index as f64 * step) as usize].clone())
+        .collect()
+}
+
+fn record_estimate(
+    token_usage: &TokenUsage,
+    stage: LlmStage,
+    prompts: Prompts,
+    completion_tokens: usize,
+) {
+    token_usage.record(
+        stage,
+        estimate_tokens(&prompts.system) + estimate_tokens(&prompts.user),
+        completion_tokens,
+    );
+}
+
+/// Records one estimate per sample of a decision that is voted on
+fn record_votes_estimate(
+    token_usage: &TokenUsage,
+    stage: LlmStage,
+    prompts: Prompts,
+    completion_tokens: usize,
+) {
+    let samples = read_lock!(CONFIG).llm.vote_samples.max(1);
 
-        Ok((name, matches, description))
+    for _ in 0..samples {
+        record_estimate(token_usage, stage, prompts.clone(), completion_tokens);
     }
 }
@@ -1,16 +1,46 @@
 use serde::{Serialize, Deserialize};
-use reqwest::header;
+use reqwest::{header, StatusCode};
+use reqwest::header::HeaderMap;
+use lazy_static::lazy_static;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde::de::DeserializeOwned;
 use serde_json::json;
+use futures::future::join_all;
+use std::collections::HashMap;
 
 use crate::prelude::*;
 use crate::transformation::{FieldTransformation, FieldMetadata};
+use crate::profile::ProfileProposal;
 #[cfg(feature = "caching")]
 use crate::cache::Cache;
 use crate::environment::{get_env_variable};
+use crate::config::{CONFIG};
+use crate::token_usage::{TokenUsage, LlmStage};
+use super::limiter::{acquire_llm_permit, wait_for_rate_limit, get_retry_delay, estimate_tokens};
+use super::prompts::{Prompts, render_prompts, fence};
+
+const OPENAI_RESPONSES_URL: &str = "https://api.openai.com/v1/responses";
+
+// Bounds on the names and descriptions persisted from a response
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+}
 
 pub struct OpenAI;
 
+enum RequestFailure {
+    /// Rate limited, server error or malformed response, with the delay the
+    /// server asked for if any
+    Retryable(Option<Duration>),
+    Fatal,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct EliminationResponse {
     pub is_unmeaningful: bool,
@@ -30,6 +60,21 @@ struct PrimaryResponse {
     pub justification: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FieldDecisionResponse {
+    pub field: String,
+    pub is_unmeaningful: bool,
+    pub is_peripheral: bool,
+    pub name: String,
+    pub description: String,
+    pub justification: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FieldDecisionsResponse {
+    pub fields: Vec<FieldDecisionResponse>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct AssociationsResponse {
     pub name: String,
@@ -38,6 +83,13 @@ struct AssociationsResponse {
     pub justification: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RecursionResponse {
+    pub name: String,
+    pub description: String,
+    pub justification: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct SummaryResponse {
     pub category: String,
@@ -47,41 +99,42 @@ struct SummaryResponse {
 
 impl OpenAI {
     pub async fn get_field_transformation(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
         field: &str,
         value: &str,
         snippets: Vec<String>,
-    ) -> Option<FieldTransformation> {
+    ) -> Result<FieldTransformation, Errors> {
         log::trace!("In get_field_transformation");
 
         log::info!("Determining if field is meaningful...");
 
-        let elimination = match field {
+        let (elimination, elimination_confidence) = match field {
             "text" => {
-                Self::should_eliminate_text(lineage, snippets.clone())
-                    .await
-                    .expect("Could not determine if text should be eliminated")
+                Self::should_eliminate_text(token_usage, lineage, snippets.clone()).await?
             },
             _ => {
-                Self::should_eliminate_attribute(lineage, field, snippets.clone())
-                    .await
-                    .expect("Could not determine if attribute should be eliminated")
+                Self::should_eliminate_attribute(token_usage, lineage, field, snippets.clone()).await?
             }
         };
 
         if elimination.is_unmeaningful {
-            log::info!("Eliminating unmeaningful field");
-            return None;
+            return Ok(get_eliminated_transformation(field, &elimination.justification, elimination_confidence));
         }
 
         log::info!("Determining if field is peripheral...");
 
-        let peripheral = Self::get_peripheral_if_applicable(
+        let (peripheral, peripheral_confidence) = Self::get_peripheral_if_applicable(
+            token_usage,
             lineage,
             field,
             value,
             snippets.clone(),
-        ).await.expect("Could not determine if field is peripheral");
+        ).await?;
+
+        // Naming is not voted on, so the field is as certain as the weaker of
+        // the two decisions that kept it
+        let meta = FieldMetadata::from_confidence(elimination_confidence.min(peripheral_confidence));
 
         if peripheral.is_peripheral {
             log::info!("Field identified as secondary/peripheral");
@@ -91,52 +144,216 @@ impl OpenAI {
                 description: String::from("Related content description"),
                 field: field.to_string(),
                 image: String::from("related_content"),
-                meta: FieldMetadata {}
+                meta,
             };
 
-            return Some(transformation);
+            return Ok(transformation);
         }
 
         log::info!("Determining primary field name and metadata...");
 
         let primary_content = Self::get_primary_content(
+            token_usage,
             lineage,
             field,
             value,
             snippets.clone(),
-        ).await.expect("Could not obtain primary content");
+        ).await?;
 
         let transformation = FieldTransformation {
             id: ID::new(),
             description: primary_content.description.clone(),
             field: field.to_string(),
             image: primary_content.name.clone(),
-            meta: FieldMetadata {},
+            meta,
         };
 
-        Some(transformation)
+        Ok(transformation)
+    }
+
+    /// Decides elimination, peripheral status, name and description for every
+    /// field of a context group in a single request, or in one request per
+    /// vote sample. Fails if no response covers exactly the requested fields,
+    /// so that the caller can fall back to deciding each field separately.
+    pub async fn get_field_transformations(
+        token_usage: &TokenUsage,
+        lineage: &Lineage,
+        fields: &[(String, String)],
+        snippets: Vec<String>,
+    ) -> Result<Vec<FieldTransformation>, Errors> {
+        log::trace!("In get_field_transformations");
+
+        log::info!("Determining meaning of {} fields in one request...", fields.len());
+
+        let prompts = Self::get_field_decisions_prompts(lineage, fields, &snippets);
+
+        let response_format = json!({
+            "type": "json_schema",
+            "name": "field_decisions",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "fields": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "field": {
+                                    "type": "string"
+                                },
+                                "is_unmeaningful": {
+                                    "type": "boolean"
+                                },
+                                "is_peripheral": {
+                                    "type": "boolean"
+                                },
+                                "name": {
+                                    "type": "string"
+                                },
+                                "description": {
+                                    "type": "string"
+                                },
+                                "justification": {
+                                    "type": "string"
+                                }
+                            },
+                            "required": ["field", "is_unmeaningful", "is_peripheral", "name", "description", "justification"],
+                            "additionalProperties": false
+                        }
+                    }
+                },
+                "required": ["fields"],
+                "additionalProperties": false
+            }
+        });
+
+        let samples = read_lock!(CONFIG).llm.vote_samples.max(1);
+
+        let results = join_all((0..samples).map(|sample| {
+            let response_format = response_format.clone();
+            let prompts = &prompts;
+
+            async move {
+                Self::send_openai_sample::<FieldDecisionsResponse>(
+                    token_usage,
+                    LlmStage::FieldDecisions,
+                    prompts,
+                    response_format,
+                    sample
+                ).await
+            }
+        })).await;
+
+        // Samples that fail or do not cover exactly the requested fields are
+        // left out of the vote
+        let mut sampled_decisions = Vec::new();
+        let mut last_error = None;
+
+        for result in results {
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    log::error!("Failed to get response from OpenAI: {}", e);
+                    keep_error(&mut last_error, e);
+                    continue;
+                }
+            };
+
+            log::debug!("╔════════════════════════════════════════╗");
+            log::debug!("║        FIELD DECISIONS START           ║");
+            log::debug!("╚════════════════════════════════════════╝");
+
+            log::debug!("***lineage***\n{}", lineage.to_string());
+            log::debug!("***system_prompt***\n{}", prompts.system);
+            log::debug!("***user_prompt***\n{}", prompts.user);
+            log::debug!("***response***\n{:?}", response);
+
+            log::debug!("╔═══════════════════════════════════════╗");
+            log::debug!("║        FIELD DECISIONS END            ║");
+            log::debug!("╚═══════════════════════════════════════╝");
+
+            match validate_field_decisions(fields, response) {
+                Ok(decisions) => sampled_decisions.push(decisions),
+                Err(err) => {
+                    log::warn!("Discarding field decisions sample: {}", err);
+                    keep_error(&mut last_error, err);
+                }
+            }
+        }
+
+        if sampled_decisions.is_empty() {
+            return Err(last_error.unwrap_or(Errors::UnexpectedError));
+        }
+
+        Ok(vote_field_decisions(sampled_decisions, samples)
+            .into_iter()
+            .map(|(decision, confidence)| {
+                let meta = FieldMetadata::from_confidence(confidence);
+
+                if decision.is_unmeaningful {
+                    return get_eliminated_transformation(&decision.field, &decision.justification, confidence);
+                }
+
+                if decision.is_peripheral {
+                    log::info!("Field identified as secondary/peripheral: {}", decision.field);
+
+                    return FieldTransformation {
+                        id: ID::new(),
+                        description: String::from("Related content description"),
+                        field: decision.field,
+                        image: String::from("related_content"),
+                        meta,
+                    };
+                }
+
+                FieldTransformation {
+                    id: ID::new(),
+                    description: decision.description,
+                    field: decision.field,
+                    image: decision.name,
+                    meta,
+                }
+            })
+            .collect())
     }
 
-    pub async fn categorize_summarize(document: &String) -> Result<(String, String, String), Errors> {
+    pub async fn categorize_summarize(token_usage: &TokenUsage, document: &str) -> Result<(String, String, String), Errors> {
         log::trace!("In categorize_summarize");
 
-        let document = if document.len() > 3000 {
-            log::warn!("truncating document");
-            &format!("{}...", &document[..3000])
-        } else {
-            document
-        };
+        let prompts = Self::get_summary_prompts(document);
+
+        Self::send_summary_request(token_usage, &prompts).await
+    }
+
+    /// Summarizes one chunk of a document too long to summarize at once
+    pub async fn categorize_summarize_chunk(
+        token_usage: &TokenUsage,
+        part: usize,
+        parts: usize,
+        document: &str,
+    ) -> Result<(String, String, String), Errors> {
+        log::trace!("In categorize_summarize_chunk");
 
-        let system_prompt = format!(r##"
- You analyze a condensed website, extrapolate from this minimized version, and provide the following information about the original website the condensed document was derived from:
- 1. category: Use one or two words to categorize this type of website. Provide response in snake case.
- 2. description: A short paragraph describing what content this website shows.
- 3. structure: A detailed description on how the HTML of the page is structured and the way content is organized from a technical perspective.
-     "##);
-        let user_prompt = format!(r##"
- [Document]
- {}
-     "##, document);
+        let prompts = Self::get_summary_chunk_prompts(part, parts, document);
+
+        Self::send_summary_request(token_usage, &prompts).await
+    }
+
+    /// Combines the summaries of the chunks of a document, in document order
+    pub async fn combine_summaries(
+        token_usage: &TokenUsage,
+        summaries: &[(String, String, String)],
+    ) -> Result<(String, String, String), Errors> {
+        log::trace!("In combine_summaries");
+
+        let prompts = Self::get_summary_combination_prompts(summaries);
+
+        Self::send_summary_request(token_usage, &prompts).await
+    }
+
+    async fn send_summary_request(token_usage: &TokenUsage, prompts: &Prompts) -> Result<(String, String, String), Errors> {
+        log::trace!("In send_summary_request");
 
         let response_format = json!({
             "type": "json_schema",
@@ -161,8 +378,9 @@ impl OpenAI {
         });
 
         match Self::send_openai_request::<SummaryResponse>(
-            &system_prompt,
-            &user_prompt,
+            token_usage,
+            LlmStage::Summary,
+            prompts,
             response_format
         ).await {
             Ok(response) => {
@@ -170,151 +388,196 @@ impl OpenAI {
                 log::debug!("║       SUMMARY START        ║");
                 log::debug!("╚════════════════════════════╝");
 
-                log::debug!("***system_prompt***\n{}", system_prompt);
-                log::debug!("***user_prompt***\n{}", user_prompt);
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
                 log::debug!("***response***\n{:?}", response);
 
                 log::debug!("╔═══════════════════════════╗");
                 log::debug!("║      SUMMARY END          ║");
                 log::debug!("╚═══════════════════════════╝");
 
-                Ok((response.category, response.description, response.structure))
+                validate_summary(&response)?;
+
+                Ok((response.category.trim().to_string(), response.description, response.structure))
             }
             Err(e) => {
                 log::error!("Failed to get response from OpenAI: {}", e);
-                Err(Errors::UnexpectedError)
+                Err(e)
             }
         }
     }
 
     pub async fn get_relationships(
+        token_usage: &TokenUsage,
         overall_context: String,
         target_subgraph_hash: String,
         subgraphs: Vec<(String, String)>,
-    ) -> Result<(String, Vec<String>, String), Errors> {
+    ) -> Result<(String, Vec<String>, String, f64), Errors> {
         log::trace!("In get_relationships");
 
         if subgraphs.is_empty() {
-            panic!("Expected at least one subgraph");
+            return Err(Errors::LLMError("Expected at least one subgraph".to_string()));
         }
 
-        let system_prompt = format!(r##"
-The data model for a website has been fragmented into distinct objects. You must interpret JSON fragments and attempt to reconstitute the original objects by matching fragment IDs to other fragment IDs.
-
-A target fragment ID will be provided, and a list of fragments with corresponding fragment ID. Attempt to determine what other fragment IDs may match the target fragment ID by considering the contextual meaning of JSON values and their potential relationship to other fragments of particular type IDs.
-
-If objects with the target fragment type ID would be merged with other objects of another type ID, the resulting JSON should be a coherent object representing a particular type in the data model for a website.
-
-Zero or multiple fragments may match the target fragment. Please provide an array of fragment ID matches and a justification too.
-
-Do not consider the keys or structure of the object, only the values.
-
-Only provide a unique list of fragment IDs that does not include the target fragment ID.
-
-Please also suggest a name in snake case that could be used for programmatically representing objects that result after merging matching fragments (name). Leave blank if zero fragments match.
-
-Provide a description decribing in details the nature and purpose of the object.
-
-The following is an example of how to perform this task:
-
+        let prompts = Self::get_relationships_prompts(&overall_context, &target_subgraph_hash, &subgraphs);
 
-Target fragment ID: 1
+        let response_format = json!({
+            "type": "json_schema",
+            "name": "matching_fragments",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string"
+                    },
+                    "description": {
+                        "type": "string"
+                    },
+                    "matching_fragments": {
+                        "type": "array",
+                        "items": {
+                            "type": "string"
+                        }
+                    },
+                    "justification": {
+                        "type": "string"
+                    }
+                },
+                "required": ["name", "description", "matching_fragments", "justification"],
+                "additionalProperties": false
+            }
+        });
 
-Fragment ID: 1
-{{
-  "id": 1,
-  "username": "alice_smith",
-  "email": "alice.smith@example.com",
-  "firstName": "Alice"
-}}
+        // Samples agree when they match the same fragments, in any order
+        let matching_fragments = |response: &AssociationsResponse| {
+            let mut matching_fragments = response.matching_fragments.clone();
+            matching_fragments.sort();
+            matching_fragments.dedup();
+            matching_fragments
+        };
 
-Fragment ID: 2
-{{
-  "lastName": "Smith",
-  "createdAt": "2023-01-10T09:00:00Z",
-  "roles": ["user"],
-  "isActive": true
-}}
+        match Self::send_openai_votes(token_usage, LlmStage::Associations, &prompts, response_format, matching_fragments).await {
+            Ok((response, confidence)) => {
+                log::debug!("╔══════════════════════════════╗");
+                log::debug!("║       ASSOCIATIONS START     ║");
+                log::debug!("╚══════════════════════════════╝");
 
-Fragment ID: 1
-{{
-  "id": 2,
-  "username": "bob_jones",
-  "email": "bob.jones@example.com",
-  "firstName": "Bob"
-}}
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
+                log::debug!("***response***\n{:?}", response);
 
-Fragment ID: 2
-{{
-  "lastName": "Jones",
-  "createdAt": "2023-01-12T11:15:00Z",
-  "roles": ["user", "moderator"],
-  "isActive": true
-}}
+                log::debug!("╔═══════════════════════════╗");
+                log::debug!("║       ASSOCIATIONS END    ║");
+                log::debug!("╚═══════════════════════════╝");
 
-Fragment ID: 1
-{{
-  "id": 3,
-  "username": "carol_white",
-  "email": "carol.white@example.com",
-  "firstName": "Carol"
-}}
+                let matching_fragments = validate_matching_fragments(
+                    &target_subgraph_hash,
+                    &subgraphs,
+                    response.matching_fragments
+                );
 
-Fragment ID: 2
-{{
-  "lastName": "White",
-  "createdAt": "2023-01-14T13:30:00Z",
-  "roles": ["user"],
-  "isActive": false
-}}
+                if !matching_fragments.is_empty() {
+                    validate_name_and_description(&response.name, &response.description)?;
+                }
 
-The response should indicate that fragment ID 2 matches the target fragment ID 1, as we can merge pairs of fragments with IDs 1 and 2 to get coherent typed objects representing user accounts.
+                Ok((response.name.trim().to_string(), matching_fragments, response.description, confidence))
+            }
+            Err(e) => {
+                log::error!("Failed to get response from OpenAI: {}", e);
+                Err(e)
+            }
+        }
+    }
 
-"##);
+    pub async fn get_recursion(
+        token_usage: &TokenUsage,
+        overall_context: String,
+        json: String,
+    ) -> Result<(String, String), Errors> {
+        log::trace!("In get_recursion");
 
-        let fragments = subgraphs.iter().enumerate().fold(
-            String::new(),
-            |mut acc, (index, (subgraph_hash, json))| {
-                acc.push_str(&format!(r##"
-Fragment ID: {}:
-{}
-    "##, subgraph_hash, json));
-                acc
-            },
-        );
+        let prompts = Self::get_recursion_prompts(&overall_context, &json);
 
-        let user_prompt = format!(r##"
-===================================================
+        let response_format = json!({
+            "type": "json_schema",
+            "name": "recursion",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string"
+                    },
+                    "description": {
+                        "type": "string"
+                    },
+                    "justification": {
+                        "type": "string"
+                    }
+                },
+                "required": ["name", "description", "justification"],
+                "additionalProperties": false
+            }
+        });
 
-Consider this website context when deciding how to match fragment type IDs:
+        match Self::send_openai_request::<RecursionResponse>(token_usage, LlmStage::Recursion, &prompts, response_format).await {
+            Ok(response) => {
+                log::debug!("╔══════════════════════════════╗");
+                log::debug!("║       RECURSION START        ║");
+                log::debug!("╚══════════════════════════════╝");
 
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
+                log::debug!("***response***\n{:?}", response);
 
-{}
+                log::debug!("╔═══════════════════════════╗");
+                log::debug!("║       RECURSION END       ║");
+                log::debug!("╚═══════════════════════════╝");
 
+                validate_name_and_description(&response.name, &response.description)?;
 
-===================================================
+                Ok((response.name.trim().to_string(), response.description))
+            }
+            Err(e) => {
+                log::error!("Failed to get response from OpenAI: {}", e);
+                Err(e)
+            }
+        }
+    }
 
-[Target fragment ID]
-{}
+    pub async fn propose_profile(
+        token_usage: &TokenUsage,
+        inventory: &str,
+        sample: &str,
+    ) -> Result<ProfileProposal, Errors> {
+        log::trace!("In propose_profile");
 
-[Fragments]
-{}
-"##, overall_context, target_subgraph_hash, fragments);
+        let prompts = Self::get_profile_prompts(inventory, sample);
 
         let response_format = json!({
             "type": "json_schema",
-            "name": "matching_fragments",
+            "name": "profile",
             "strict": true,
             "schema": {
                 "type": "object",
                 "properties": {
-                    "name": {
-                        "type": "string"
-                    },
                     "description": {
                         "type": "string"
                     },
-                    "matching_fragments": {
+                    "meaningful_fields": {
+                        "type": "array",
+                        "items": {
+                            "type": "string"
+                        }
+                    },
+                    "blacklisted_elements": {
+                        "type": "array",
+                        "items": {
+                            "type": "string"
+                        }
+                    },
+                    "blacklisted_attributes": {
                         "type": "array",
                         "items": {
                             "type": "string"
@@ -324,35 +587,36 @@ Consider this website context when deciding how to match fragment type IDs:
                         "type": "string"
                     }
                 },
-                "required": ["name", "description", "matching_fragments", "justification"],
+                "required": ["description", "meaningful_fields", "blacklisted_elements", "blacklisted_attributes", "justification"],
                 "additionalProperties": false
             }
         });
 
-        match Self::send_openai_request::<AssociationsResponse>(&system_prompt, &user_prompt, response_format).await {
+        match Self::send_openai_request::<ProfileProposal>(token_usage, LlmStage::Profile, &prompts, response_format).await {
             Ok(response) => {
                 log::debug!("╔══════════════════════════════╗");
-                log::debug!("║       ASSOCIATIONS START     ║");
+                log::debug!("║       PROFILE START          ║");
                 log::debug!("╚══════════════════════════════╝");
 
-                log::debug!("***system_prompt***\n{}", system_prompt);
-                log::debug!("***user_prompt***\n{}", user_prompt);
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
                 log::debug!("***response***\n{:?}", response);
 
                 log::debug!("╔═══════════════════════════╗");
-                log::debug!("║       ASSOCIATIONS END    ║");
+                log::debug!("║       PROFILE END         ║");
                 log::debug!("╚═══════════════════════════╝");
 
-                Ok((response.name, response.matching_fragments, response.description))
+                Ok(response)
             }
             Err(e) => {
                 log::error!("Failed to get response from OpenAI: {}", e);
-                Err(Errors::UnexpectedError)
+                Err(e)
             }
         }
     }
 
     async fn get_primary_content(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
         field: &str,
         value: &str,
@@ -360,40 +624,7 @@ Consider this website context when deciding how to match fragment type IDs:
     ) -> Result<PrimaryResponse, Errors> {
         log::trace!("In get_primary_content");
 
-        let field_value = if field == "text" { value } else { field };
-
-        let system_prompt = format!(r##"
-You interpret the contextual meaning of HTML attributes or text nodes and reverse engineer the data model that was possibly used when building the website.
-
-Please provide the following information:
-* (name): A variable name in snake case that could be used to represent this text node or attribute programmatically
-* (description): A description of the variable name as it might be found in a JSON schema.
-* (justification): A justification for your response
-
-One or more examples of the attribute or text node will be provided, contained within an HTML snippet, providing crucial context for you to use. 
-
-The target attribute or text node will be delimited with an HTML comment like so:
-<!-- Target node: Start --><a href="https://example.com" other-attribute="val"><!-- Target node: End -->.
-
-When providing your response, you must generalize across all possible values for the text node or attribute, which are not limited to just the set of values in the example snippets. 
-        "##);
-        let examples = snippets.iter().enumerate().fold(
-            String::new(),
-            |mut acc, (index, snippet)| {
-                acc.push_str(&format!(r##"
-Example {}:
-{}
-"##, index + 1, snippet));
-                acc
-            }
-        );
-        let user_prompt = format!(r##"
-[attribute/text]
-{}
-
-[Examples]
-{}
-        "##, field_value, examples);
+        let prompts = Self::get_primary_prompts(lineage, field, value, &snippets);
 
         let response_format = json!({
             "type": "json_schema",
@@ -417,9 +648,10 @@ Example {}:
             }
         });
 
-        match Self::send_openai_request(
-            &system_prompt,
-            &user_prompt,
+        match Self::send_openai_request::<PrimaryResponse>(
+            token_usage,
+            LlmStage::Primary,
+            &prompts,
             response_format
         ).await {
             Ok(response) => {
@@ -428,74 +660,38 @@ Example {}:
                 log::debug!("╚═════════════════════════════════╝");
 
                 log::debug!("***lineage***\n{}", lineage.to_string());
-                log::debug!("***system_prompt***\n{}", system_prompt);
-                log::debug!("***user_prompt***\n{}", user_prompt);
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
                 log::debug!("***response***\n{:?}", response);
 
                 log::debug!("╔══════════════════════════════╗");
                 log::debug!("║          PRIMARY END         ║");
                 log::debug!("╚══════════════════════════════╝");
 
-                Ok(response)
+                validate_name_and_description(&response.name, &response.description)?;
+
+                Ok(PrimaryResponse {
+                    name: response.name.trim().to_string(),
+                    ..response
+                })
             }
             Err(e) => {
                 log::error!("Failed to get response from OpenAI: {}", e);
-                Err(Errors::UnexpectedError)
+                Err(e)
             }
         }
     }
 
     async fn get_peripheral_if_applicable(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
         field: &str,
         value: &str,
         snippets: Vec<String>,
-    ) -> Result<PeripheralResponse, Errors> {
+    ) -> Result<(PeripheralResponse, f64), Errors> {
         log::trace!("In get_peripheral_if_applicable");
 
-        let field_value = if field == "text" { value } else { field };
-
-        let system_prompt = format!(r##"
-You interpret the contextual meaning of HTML attributes or text nodes and infer if it is content pertaining to the core purpose of the website, or if it is peripheral/secondary content. Peripheral content is not the primary focus of the website's message or purpose.
-
-Primary content is defined as content that is essential to the website's core purpose and cannot be removed without altering the fundamental experience of interacting with the
-content. This includes:
-* Content that directly contributes to the main purpose of the site, such as articles, user profiles, or discussion threads on news and social platforms.
-* Elements that are integral to user engagement and understanding of the site's main offerings.
-
-Peripheral content includes:
-* Website menu bars, footers, or sidebars that link to unrelated pages or external resources.
-* Links to administrative pages such as login, signup, or settings that do not enhance the understanding or interaction with the main content.
-* Advertisements or promotional banners that do not contribute to the main purpose of the site.
-
-Include the following in your response:
-1. (is_peripheral): If this is peripheral content.
-2. (justification): Provide justification for your response.
-
-One or more examples of the attribute or text node will be provided, contained within an HTML snippet, providing crucial context for you to use.
-
-The target attribute or text node will be delimited with an HTML comment like so:
-<!-- Target node: Start --><a href="https://example.com" other-attribute="val"><!-- Target node: End -->.
-
-When providing your response, you must generalize across all possible values for the text node or attribute, which are not limited to just the set of values in the example snippet(s).
-        "##);
-        let examples = snippets.iter().enumerate().fold(
-            String::new(),
-            |mut acc, (index, snippet)| {
-                acc.push_str(&format!(r##"
-Example {}:
-{}
-"##, index + 1, snippet));
-                acc
-            }
-        );
-        let user_prompt = format!(r##"
-[attribute/text]
-{}
-
-[Examples]
-{}
-        "##, field_value, examples);
+        let prompts = Self::get_peripheral_prompts(lineage, field, value, &snippets);
 
         let response_format = json!({
             "type": "json_schema",
@@ -516,131 +712,66 @@ Example {}:
             }
         });
 
-        match Self::send_openai_request(
-            &system_prompt,
-            &user_prompt,
-            response_format
+        match Self::send_openai_votes(
+            token_usage,
+            LlmStage::Peripheral,
+            &prompts,
+            response_format,
+            |response: &PeripheralResponse| response.is_peripheral
         ).await {
-            Ok(response) => {
+            Ok((response, confidence)) => {
                 log::debug!("╔════════════════════════════════════════╗");
                 log::debug!("║          IS PERIPHERAL START           ║");
                 log::debug!("╚════════════════════════════════════════╝");
 
                 log::debug!("***lineage***\n{}", lineage.to_string());
-                log::debug!("***system_prompt***\n{}", system_prompt);
-                log::debug!("***user_prompt***\n{}", user_prompt);
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
                 log::debug!("***response***\n{:?}", response);
 
                 log::debug!("╔═══════════════════════════════════════╗");
                 log::debug!("║          IS PERIPHERAL END            ║");
                 log::debug!("╚═══════════════════════════════════════╝");
 
-                Ok(response)
+                Ok((response, confidence))
             }
             Err(e) => {
                 log::error!("Failed to get response from OpenAI: {}", e);
-                Err(Errors::UnexpectedError)
+                Err(e)
             }
         }
     }
 
     async fn should_eliminate_attribute(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
         field: &str,
         snippets: Vec<String>
-    ) -> Result<EliminationResponse, Errors> {
+    ) -> Result<(EliminationResponse, f64), Errors> {
         log::trace!("In should_eliminate_attribute");
 
-        let system_prompt = format!(r##"
-You interpret the contextual meaning of a specific HTML attribute, and infer if the attribute represents meaningful natural language meant to be consumed by humans as part of their core purpose in visiting a website, as opposed to ancillary content. If a user would intentionally read the attribute's value as part of their usage, it is likely meaningful content.
-
-Carefully examine the HTML attribute along with its surrounding content providing crucial context, and determine if any of the following applies to it:
-
-1. If the attribute represents an advertisement of some kind.
-2. If the attribute value contains code of some kind
-
-Include the following in your response:
-1. (is_unmeaningful): if any of the above criteria apply to the text node, respond true
-2. (justification): provide justification for your response
-
-One or more examples of the attribute will be provided, contained within an HTML snippet, providing crucial context for you to use. 
-
-The attribute will be contained/delimited with an HTML comment like so:
-<!-- Target node: Start --><a href="https://example.com" other-attribute="val"><!-- Target node: End -->
+        let prompts = Self::get_attribute_elimination_prompts(lineage, field, &snippets);
 
-When providing your response, you must generalize across all possible values for the attribute, which is not limited to just the set of values in the example snippets. 
-        "##);
-        let examples = snippets.iter().enumerate().fold(
-            String::new(),
-            |mut acc, (index, snippet)| {
-                acc.push_str(&format!(r##"
-Example {}:
-{}
-"##, index + 1, snippet));
-                acc
-            }
-        );
-        let user_prompt = format!(r##"
-[Attribute]
-{}
-
-[Examples]
-{}
-        "##, field.trim(), examples);
-
-
-        Self::should_eliminate(lineage, &system_prompt, &user_prompt).await
+        Self::should_eliminate(token_usage, lineage, &prompts).await
     }
 
     async fn should_eliminate_text(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
         snippets: Vec<String>
-    ) -> Result<EliminationResponse, Errors> {
+    ) -> Result<(EliminationResponse, f64), Errors> {
         log::trace!("In should_eliminate_text");
 
-        let system_prompt = format!(r##"
-You interpret the contextual meaning of a type of HTML text node, and infer if the text node represents meaningful natural language meant to be consumed by humans as part of their core purpose in visiting a website, as opposed to ancillary or presentational text.
-
-Carefully examine the provided HTML text node along with supplementary information providing crucial context, and determine if any of the following applies to it:
-
-1. If the text node represents an advertisement of some kind.
-2. If the text node serves a presentational purpose. For example, a pipe symbol may be used to delineate menu items, other text nodes might represent an icon. Presentational text is not meaningful, semantic content humans consume as part of their core purpose for visiting a website.
-3. If the text node is a label for a UI element meant to assist the user in understanding how to operate the website, as opposed to content that is meant to be consumed
-
-Include the following in your response:
-1. (is_unmeaningful): if any of the above criteria apply to the text node, respond true
-2. (justification): provide justification for your response
+        let prompts = Self::get_text_elimination_prompts(lineage, &snippets);
 
-One or more examples of the text node will be provided, contained within an HTML snippet, providing crucial context for you to use. 
-
-The text nodes will be contained/delimited with an HTML comment like so:
-<!-- Target node: Start -->Text node content here<!-- Target node: End -->
-
-When providing your response, you must generalize across all possible values for the text node, which is not limited to just the set of values in the example snippets. 
-        "##);
-        let examples = snippets.iter().enumerate().fold(
-            String::new(),
-            |mut acc, (index, snippet)| {
-                acc.push_str(&format!(r##"
-Example {}:
-{}
-"##, index + 1, snippet));
-                acc
-            }
-        );
-        let user_prompt = format!(r##"
-[Examples]
-{}
-        "##, examples);
-
-        Self::should_eliminate(lineage, &system_prompt, &user_prompt).await
+        Self::should_eliminate(token_usage, lineage, &prompts).await
     }
 
     async fn should_eliminate(
+        token_usage: &TokenUsage,
         lineage: &Lineage,
-        system_prompt: &str,
-        user_prompt: &str,
-    ) -> Result<EliminationResponse, Errors> {
+        prompts: &Prompts,
+    ) -> Result<(EliminationResponse, f64), Errors> {
         log::trace!("In should_eliminate");
 
         let response_format = json!({
@@ -662,65 +793,307 @@ Example {}:
             }
         });
 
-        match Self::send_openai_request(
-            &system_prompt,
-            &user_prompt,
-            response_format
+        match Self::send_openai_votes(
+            token_usage,
+            LlmStage::Elimination,
+            prompts,
+            response_format,
+            |response: &EliminationResponse| response.is_unmeaningful
         ).await {
-            Ok(response) => {
+            Ok((response, confidence)) => {
                 log::debug!("╔════════════════════════════════════════╗");
                 log::debug!("║    SHOULD ELIMINATE FIELD START        ║");
                 log::debug!("╚════════════════════════════════════════╝");
 
                 log::debug!("***lineage***\n{}", lineage.to_string());
-                log::debug!("***system_prompt***\n{}", system_prompt);
-                log::debug!("***user_prompt***\n{}", user_prompt);
+                log::debug!("***system_prompt***\n{}", prompts.system);
+                log::debug!("***user_prompt***\n{}", prompts.user);
                 log::debug!("***response***\n{:?}", response);
 
                 log::debug!("╔═══════════════════════════════════════╗");
                 log::debug!("║    SHOULD ELIMINATE FIELD END         ║");
                 log::debug!("╚═══════════════════════════════════════╝");
 
-                Ok(response)
+                Ok((response, confidence))
             }
             Err(e) => {
                 log::error!("Failed to get response from OpenAI: {}", e);
-                Err(Errors::UnexpectedError)
+                Err(e)
             }
         }
     }
 
+    pub fn get_field_decisions_prompts(lineage: &Lineage, fields: &[(String, String)], snippets: &[String]) -> Prompts {
+        log::trace!("In get_field_decisions_prompts");
+
+        let field_list = fields.iter().fold(
+            String::new(),
+            |mut acc, (field, value)| {
+                if field == "text" {
+                    acc.push_str(&format!("* text, for example:\n{}\n", fence(value.trim())));
+                } else {
+                    acc.push_str(&format!("* {}\n", field.trim()));
+                }
+                acc
+            }
+        );
+
+        render_prompts("field_decisions", &[
+            ("lineage", &lineage.to_string()),
+            ("fields", &field_list),
+            ("snippets", &format_snippets(snippets)),
+        ])
+    }
+
+    pub fn get_summary_prompts(document: &str) -> Prompts {
+        log::trace!("In get_summary_prompts");
+
+        render_prompts("summary", &[
+            ("document", &fence(document)),
+        ])
+    }
+
+    pub fn get_summary_chunk_prompts(part: usize, parts: usize, document: &str) -> Prompts {
+        log::trace!("In get_summary_chunk_prompts");
+
+        render_prompts("summary_chunk", &[
+            ("part", &part.to_string()),
+            ("parts", &parts.to_string()),
+            ("document", &fence(document)),
+        ])
+    }
+
+    pub fn get_summary_combination_prompts(summaries: &[(String, String, String)]) -> Prompts {
+        log::trace!("In get_summary_combination_prompts");
+
+        let summary_list = summaries.iter().enumerate().fold(
+            String::new(),
+            |mut acc, (index, (category, description, structure))| {
+                let summary = format!("Category: {}\nDescription: {}\nStructure: {}", category, description, structure);
+
+                acc.push_str(&format!(r##"
+Part {} of {}
+{}
+"##, index + 1, summaries.len(), fence(&summary)));
+                acc
+            }
+        );
+
+        render_prompts("summary_combination", &[
+            ("summaries", &summary_list),
+        ])
+    }
+
+    pub fn get_relationships_prompts(overall_context: &str, target_subgraph_hash: &str, subgraphs: &[(String, String)]) -> Prompts {
+        log::trace!("In get_relationships_prompts");
+
+        let fragments = subgraphs.iter().fold(
+            String::new(),
+            |mut acc, (subgraph_hash, json)| {
+                acc.push_str(&format!(r##"
+Fragment ID: {}:
+{}
+    "##, subgraph_hash, fence(json)));
+                acc
+            },
+        );
+
+        render_prompts("associations", &[
+            ("context", &fence(overall_context)),
+            ("target_fragment", target_subgraph_hash),
+            ("fragments", &fragments),
+        ])
+    }
+
+    pub fn get_recursion_prompts(overall_context: &str, json: &str) -> Prompts {
+        log::trace!("In get_recursion_prompts");
+
+        render_prompts("recursion", &[
+            ("context", &fence(overall_context)),
+            ("fragment", &fence(json)),
+        ])
+    }
+
+    pub fn get_profile_prompts(inventory: &str, sample: &str) -> Prompts {
+        log::trace!("In get_profile_prompts");
+
+        render_prompts("profile", &[
+            ("inventory", &fence(inventory)),
+            ("sample", &fence(sample)),
+        ])
+    }
+
+    pub fn get_primary_prompts(lineage: &Lineage, field: &str, value: &str, snippets: &[String]) -> Prompts {
+        log::trace!("In get_primary_prompts");
+
+        let field_value = if field == "text" { value } else { field };
+
+        render_prompts("primary", &[
+            ("lineage", &lineage.to_string()),
+            ("field", &fence(field_value)),
+            ("snippets", &format_snippets(snippets)),
+        ])
+    }
+
+    pub fn get_peripheral_prompts(lineage: &Lineage, field: &str, value: &str, snippets: &[String]) -> Prompts {
+        log::trace!("In get_peripheral_prompts");
+
+        let field_value = if field == "text" { value } else { field };
+
+        render_prompts("peripheral", &[
+            ("lineage", &lineage.to_string()),
+            ("field", &fence(field_value)),
+            ("snippets", &format_snippets(snippets)),
+        ])
+    }
+
+    pub fn get_attribute_elimination_prompts(lineage: &Lineage, field: &str, snippets: &[String]) -> Prompts {
+        log::trace!("In get_attribute_elimination_prompts");
+
+        render_prompts("attribute_elimination", &[
+            ("lineage", &lineage.to_string()),
+            ("field", &fence(field.trim())),
+            ("snippets", &format_snippets(snippets)),
+        ])
+    }
+
+    pub fn get_text_elimination_prompts(lineage: &Lineage, snippets: &[String]) -> Prompts {
+        log::trace!("In get_text_elimination_prompts");
+
+        render_prompts("text_elimination", &[
+            ("lineage", &lineage.to_string()),
+            ("snippets", &format_snippets(snippets)),
+        ])
+    }
+
     async fn send_openai_request<T>(
-        system_prompt: &str,
-        user_prompt: &str,
+        token_usage: &TokenUsage,
+        stage: LlmStage,
+        prompts: &Prompts,
         response_format: serde_json::Value,
-    ) -> Result<T, Box<dyn std::error::Error>>
+    ) -> Result<T, Errors>
     where
         T: DeserializeOwned,
     {
         log::trace!("In send_openai_request");
 
+        Self::send_openai_sample(token_usage, stage, prompts, response_format, 0).await
+    }
+
+    /// Sends the prompts llm.vote_samples times and keeps the answer most
+    /// samples agree on by the given key, along with the share of samples
+    /// that agreed. Ties go to the earliest sample, so that the deterministic
+    /// first sample settles them.
+    async fn send_openai_votes<T, K>(
+        token_usage: &TokenUsage,
+        stage: LlmStage,
+        prompts: &Prompts,
+        response_format: serde_json::Value,
+        key: impl Fn(&T) -> K,
+    ) -> Result<(T, f64), Errors>
+    where
+        T: DeserializeOwned,
+        K: PartialEq,
+    {
+        log::trace!("In send_openai_votes");
+
+        let samples = read_lock!(CONFIG).llm.vote_samples.max(1);
+
+        let results = join_all((0..samples).map(|sample| {
+            let response_format = response_format.clone();
+
+            async move {
+                Self::send_openai_sample::<T>(token_usage, stage, prompts, response_format, sample).await
+            }
+        })).await;
+
+        let mut responses = Vec::new();
+        let mut last_error = None;
+
+        for result in results {
+            match result {
+                Ok(response) => responses.push(response),
+                Err(err) => {
+                    log::warn!("Discarding failed {} sample: {}", stage, err);
+                    keep_error(&mut last_error, err);
+                }
+            }
+        }
+
+        if responses.is_empty() {
+            return Err(last_error.unwrap_or_else(|| Errors::LLMError("No samples were sent".to_string())));
+        }
+
+        let keys: Vec<K> = responses.iter().map(key).collect();
+        let (winner, confidence) = tally(&keys, samples);
+
+        if samples > 1 {
+            log::info!("{} decision agreed on by {:.0}% of {} samples", stage, confidence * 100.0, samples);
+        }
+
+        Ok((responses.swap_remove(winner), confidence))
+    }
+
+    /// Sends one sample of a request. The first sample is deterministic, the
+    /// others are drawn at llm.vote_temperature and cached separately.
+    async fn send_openai_sample<T>(
+        token_usage: &TokenUsage,
+        stage: LlmStage,
+        prompts: &Prompts,
+        response_format: serde_json::Value,
+        sample: usize,
+    ) -> Result<T, Errors>
+    where
+        T: DeserializeOwned,
+    {
+        log::trace!("In send_openai_sample");
+
+        let temperature = if sample == 0 {
+            0.0
+        } else {
+            read_lock!(CONFIG).llm.vote_temperature
+        };
+
         let mut hash = Hash::from_items(vec![
-            system_prompt,
-            user_prompt,
+            &prompts.version,
+            &prompts.system,
+            &prompts.user,
             &response_format.to_string()
         ]);
+
+        if sample > 0 {
+            hash.push(sample).push(temperature);
+        }
+
         let hash = hash.finalize();
 
+        let fetched = AtomicBool::new(false);
+        let budget_error: Mutex<Option<Errors>> = Mutex::new(None);
+
         let response = Self::get_or_set_cache(hash.clone(), || async {
+            fetched.store(true, Ordering::Relaxed);
+
+            // Cached responses cost nothing, so the budget is only enforced
+            // once a request would actually be sent
+            if let Err(err) = token_usage.check_budget() {
+                log::warn!("Skipping {} request: {}", stage, err);
+                *budget_error.lock().unwrap() = Some(err);
+                return None;
+            }
+
             let openai_api_key = get_env_variable("OPENAI_API_KEY");
 
             let request_json = json!({
                 "model": "gpt-4o-2024-08-06",
-                "temperature": 0,
+                "temperature": temperature,
                 "input": [
                     {
                         "role": "system",
-                        "content": system_prompt
+                        "content": prompts.system
                     },
                     {
                         "role": "user",
-                        "content": user_prompt
+                        "content": prompts.user
                     }
                 ],
                 "text": {
@@ -728,49 +1101,130 @@ Example {}:
                 }
             });
 
-            let url = "https://api.openai.com/v1/responses";
             let authorization = format!("Bearer {}", openai_api_key);
-            let client = reqwest::Client::new();
-
-            match client
-                .post(url)
-                .json(&request_json)
-                .header(header::CONTENT_TYPE, "application/json")
-                .header(header::AUTHORIZATION, authorization)
-                .send()
-                .await
-            {
-                Ok(res) => {
-                    log::trace!("okay response from openai");
-                    log::debug!("res: {:?}", res);
-
-                    match res.json::<serde_json::Value>().await {
-                        Ok(json_response) => {
-                            log::trace!("okay json from openai");
-                            log::debug!("json_response: {:?}", json_response);
-
-                            json_response["output"][0]["content"][0]["text"]
-                                .as_str()
-                                .map(String::from)
-                        }
-                        Err(e) => {
-                            log::error!("Failed to parse JSON response: {}", e);
-                            None
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to send request to OpenAI: {}", e);
-                    None
+            let estimated_tokens = estimate_tokens(&prompts.system) + estimate_tokens(&prompts.user);
+            let max_retries = read_lock!(CONFIG).llm.max_retries;
+
+            for attempt in 0..=max_retries {
+                let retry_after = match Self::attempt_openai_request::<T>(
+                    token_usage,
+                    stage,
+                    &request_json,
+                    &authorization,
+                    estimated_tokens,
+                ).await {
+                    Ok(text) => return Some(text),
+                    Err(RequestFailure::Fatal) => return None,
+                    Err(RequestFailure::Retryable(retry_after)) => retry_after,
+                };
+
+                if attempt < max_retries {
+                    let delay = get_retry_delay(attempt, retry_after);
+                    log::warn!(
+                        "Retrying OpenAI request in {}ms ({} of {} retries)",
+                        delay.as_millis(),
+                        attempt + 1,
+                        max_retries
+                    );
+                    tokio::time::sleep(delay).await;
                 }
             }
+
+            log::error!("OpenAI request failed after {} attempts", max_retries + 1);
+            None
         }).await;
 
-        let json_response = response.ok_or("Failed to get response from OpenAI")?;
+        if let Some(err) = budget_error.into_inner().unwrap() {
+            return Err(err);
+        }
+
+        if !fetched.load(Ordering::Relaxed) {
+            token_usage.record_cached(stage);
+        }
+
+        let json_response = response.ok_or_else(|| Errors::LLMError("Failed to get response from OpenAI".to_string()))?;
         let parsed_response: T = serde_json::from_str(&json_response)?;
         Ok(parsed_response)
     }
 
+    /// Makes a single request under the concurrency and rate limits. The
+    /// output is parsed before it is returned, so that a malformed response
+    /// is retried instead of being cached.
+    async fn attempt_openai_request<T>(
+        token_usage: &TokenUsage,
+        stage: LlmStage,
+        request_json: &serde_json::Value,
+        authorization: &str,
+        estimated_tokens: usize,
+    ) -> Result<String, RequestFailure>
+    where
+        T: DeserializeOwned,
+    {
+        log::trace!("In attempt_openai_request");
+
+        let _permit = acquire_llm_permit().await.map_err(|_| RequestFailure::Fatal)?;
+        wait_for_rate_limit(estimated_tokens).await;
+
+        let res = HTTP_CLIENT
+            .post(OPENAI_RESPONSES_URL)
+            .json(request_json)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, authorization)
+            .send()
+            .await
+            .map_err(|e| {
+                log::error!("Failed to send request to OpenAI: {}", e);
+                RequestFailure::Retryable(None)
+            })?;
+
+        log::trace!("okay response from openai");
+        log::debug!("res: {:?}", res);
+
+        let status = res.status();
+
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            log::warn!("OpenAI responded with {}", status);
+            return Err(RequestFailure::Retryable(get_retry_after(res.headers(), Utc::now())));
+        }
+
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            log::error!("OpenAI responded with {}: {}", status, body);
+            return Err(RequestFailure::Fatal);
+        }
+
+        let json_response = res.json::<serde_json::Value>().await.map_err(|e| {
+            log::error!("Failed to parse JSON response: {}", e);
+            RequestFailure::Retryable(None)
+        })?;
+
+        log::trace!("okay json from openai");
+        log::debug!("json_response: {:?}", json_response);
+
+        // Recorded before the output is checked, since a malformed output is
+        // billed all the same
+        token_usage.record(
+            stage,
+            json_response["usage"]["input_tokens"].as_u64().unwrap_or(0) as usize,
+            json_response["usage"]["output_tokens"].as_u64().unwrap_or(0) as usize,
+        );
+
+        let text = json_response["output"][0]["content"][0]["text"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| {
+                log::error!("OpenAI response does not contain output text");
+                RequestFailure::Retryable(None)
+            })?;
+
+        serde_json::from_str::<T>(&text).map_err(|e| {
+            log::error!("OpenAI output does not match the response format: {}", e);
+            RequestFailure::Retryable(None)
+        })?;
+
+        Ok(text)
+    }
+
     async fn get_or_set_cache<F, Fut>(hash: Hash, fetch_data: F) -> Option<String>
     where
         F: FnOnce() -> Fut,
@@ -783,8 +1237,361 @@ Example {}:
 
         #[cfg(not(feature = "caching"))]
         {
-            log::debug!("caching is disabled");
+            log::debug!("caching is disabled, requesting {}", hash.to_string().unwrap_or_default());
             fetch_data().await
         }
     }
 }
+
+/// A field found unmeaningful, described by the justification for its
+/// elimination so that a reviewer can judge the decision
+fn get_eliminated_transformation(field: &str, justification: &str, confidence: f64) -> FieldTransformation {
+    FieldTransformation {
+        id: ID::new(),
+        description: justification.to_string(),
+        field: field.to_string(),
+        image: String::new(),
+        meta: FieldMetadata {
+            eliminated: true,
+            ..FieldMetadata::from_confidence(confidence)
+        },
+    }
+}
+
+/// Orders the decisions like the requested fields, rejecting a response that
+/// misses a field, repeats one, invents one, or leaves a primary field unnamed
+fn validate_field_decisions(
+    fields: &[(String, String)],
+    response: FieldDecisionsResponse,
+) -> Result<Vec<FieldDecisionResponse>, Errors> {
+    log::trace!("In validate_field_decisions");
+
+    let mut decisions: HashMap<String, FieldDecisionResponse> = HashMap::new();
+
+    for decision in response.fields.into_iter() {
+        let field = decision.field.trim().to_string();
+
+        if !fields.iter().any(|(requested, _)| *requested == field) {
+            return Err(Errors::LLMError(format!("Batched response contains unknown field: {}", field)));
+        }
+
+        if !decision.is_unmeaningful && !decision.is_peripheral {
+            validate_name_and_description(&decision.name, &decision.description)
+                .map_err(|err| Errors::LLMError(format!("Batched response for field {} rejected: {}", field, err)))?;
+        }
+
+        let decision = FieldDecisionResponse {
+            field: field.clone(),
+            name: decision.name.trim().to_string(),
+            ..decision
+        };
+
+        if decisions.insert(field.clone(), decision).is_some() {
+            return Err(Errors::LLMError(format!("Batched response repeats field: {}", field)));
+        }
+    }
+
+    fields
+        .iter()
+        .map(|(field, _)| {
+            decisions.remove(field).ok_or_else(|| {
+                Errors::LLMError(format!("Batched response is missing field: {}", field))
+            })
+        })
+        .collect()
+}
+
+/// Rejects a name or description that does not belong in a data model: a
+/// name that is not a short snake case identifier, or a missing or overlong
+/// description
+fn validate_name_and_description(name: &str, description: &str) -> Result<(), Errors> {
+    let name = name.trim();
+
+    if !is_snake_case(name) || name.len() > MAX_NAME_LENGTH {
+        return Err(Errors::LLMError(format!("Response has an invalid name: {}", name)));
+    }
+
+    if description.trim().is_empty() || description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(Errors::LLMError(format!("Response for {} has no description or one that is too long", name)));
+    }
+
+    Ok(())
+}
+
+/// Rejects a summary without a short category
+fn validate_summary(response: &SummaryResponse) -> Result<(), Errors> {
+    let category = response.category.trim();
+
+    if category.is_empty() || category.len() > MAX_NAME_LENGTH {
+        return Err(Errors::LLMError(format!("Summary has an invalid category: {}", category)));
+    }
+
+    Ok(())
+}
+
+/// Keeps the matched fragment IDs that were offered, other than the target,
+/// each once
+fn validate_matching_fragments(
+    target_subgraph_hash: &str,
+    subgraphs: &[(String, String)],
+    matching_fragments: Vec<String>,
+) -> Vec<String> {
+    let mut validated: Vec<String> = Vec::new();
+
+    for fragment in matching_fragments.into_iter() {
+        let fragment = fragment.trim().to_string();
+
+        if fragment == target_subgraph_hash || validated.contains(&fragment) {
+            continue;
+        }
+
+        if !subgraphs.iter().any(|(subgraph_hash, _)| *subgraph_hash == fragment) {
+            log::warn!("Ignoring unknown fragment ID in associations response: {}", fragment);
+            continue;
+        }
+
+        validated.push(fragment);
+    }
+
+    validated
+}
+
+/// Keeps the latest error of failed samples, unless an earlier one ran out
+/// of budget, which callers must see rather than retry
+fn keep_error(last_error: &mut Option<Errors>, err: Errors) {
+    if !matches!(last_error.as_ref().map(Errors::root_cause), Some(Errors::BudgetExceeded(_))) {
+        *last_error = Some(err);
+    }
+}
+
+/// Reads the delay from retry-after-ms, which OpenAI sends with millisecond
+/// precision, or else from the standard Retry-After header, either in seconds
+/// or as the HTTP date to retry at. A date already past means no delay.
+fn get_retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let header_text = |name: &str| Some(headers.get(name)?.to_str().ok()?.trim());
+    let header_value = |name: &str| header_text(name)?.parse::<f64>().ok();
+    let header_date = |name: &str| {
+        let date = DateTime::parse_from_rfc2822(header_text(name)?).ok()?;
+
+        Some((date.with_timezone(&Utc) - now).num_milliseconds().max(0) as f64 / 1000.0)
+    };
+
+    header_value("retry-after-ms")
+        .map(|milliseconds| milliseconds / 1000.0)
+        .or_else(|| header_value("retry-after"))
+        .or_else(|| header_date("retry-after"))
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// The index of the first answer with the most votes, and the share of all
+/// samples that voted for it
+fn tally<K: PartialEq>(keys: &[K], samples: usize) -> (usize, f64) {
+    let (winner, votes) = keys
+        .iter()
+        .enumerate()
+        .map(|(index, key)| (index, keys.iter().filter(|other| *other == key).count()))
+        .fold((0, 0), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+
+    (winner, votes as f64 / samples.max(1) as f64)
+}
+
+/// Votes on each field separately across the validated samples, which all
+/// list the fields in the same order
+fn vote_field_decisions(
+    sampled_decisions: Vec<Vec<FieldDecisionResponse>>,
+    samples: usize,
+) -> Vec<(FieldDecisionResponse, f64)> {
+    let field_count = sampled_decisions.first().map_or(0, |decisions| decisions.len());
+
+    (0..field_count)
+        .map(|index| {
+            let keys: Vec<(bool, bool)> = sampled_decisions
+                .iter()
+                .map(|decisions| (decisions[index].is_unmeaningful, decisions[index].is_peripheral))
+                .collect();
+            let (winner, confidence) = tally(&keys, samples);
+
+            (sampled_decisions[winner][index].clone(), confidence)
+        })
+        .collect()
+}
+
+/// Numbers the example snippets shown alongside a field
+fn format_snippets(snippets: &[String]) -> String {
+    snippets.iter().enumerate().fold(
+        String::new(),
+        |mut acc, (index, snippet)| {
+            acc.push_str(&format!(r##"
+Example {}:
+{}
+"##, index + 1, fence(snippet)));
+            acc
+        }
+    )
+}
+
+fn is_snake_case(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requested(fields: &[&str]) -> Vec<(String, String)> {
+        fields.iter().map(|field| (field.to_string(), "value".to_string())).collect()
+    }
+
+    fn decision(field: &str, name: &str, is_unmeaningful: bool) -> FieldDecisionResponse {
+        FieldDecisionResponse {
+            field: field.to_string(),
+            is_unmeaningful,
+            is_peripheral: false,
+            name: name.to_string(),
+            description: format!("The {}", name),
+            justification: String::new(),
+        }
+    }
+
+    fn response(fields: Vec<FieldDecisionResponse>) -> FieldDecisionsResponse {
+        FieldDecisionsResponse { fields }
+    }
+
+    #[test]
+    fn validate_field_decisions_orders_like_the_request() {
+        let decisions = validate_field_decisions(
+            &requested(&["text", "href", "class"]),
+            response(vec![
+                decision(" href ", " link_url ", false),
+                decision("class", "", true),
+                decision("text", "title", false),
+            ]),
+        ).unwrap();
+
+        let fields: Vec<&str> = decisions.iter().map(|decision| decision.field.as_str()).collect();
+
+        assert_eq!(fields, vec!["text", "href", "class"]);
+        assert_eq!(decisions[1].name, "link_url");
+    }
+
+    #[test]
+    fn validate_field_decisions_rejects_mismatched_fields() {
+        let fields = requested(&["text", "href"]);
+
+        let unknown = response(vec![
+            decision("text", "title", false),
+            decision("href", "link_url", false),
+            decision("src", "image_url", false),
+        ]);
+        let repeated = response(vec![
+            decision("text", "title", false),
+            decision("text", "title", false),
+            decision("href", "link_url", false),
+        ]);
+        let missing = response(vec![decision("text", "title", false)]);
+
+        assert!(validate_field_decisions(&fields, unknown).is_err());
+        assert!(validate_field_decisions(&fields, repeated).is_err());
+        assert!(validate_field_decisions(&fields, missing).is_err());
+    }
+
+    #[test]
+    fn validate_field_decisions_checks_names_of_kept_fields_only() {
+        let fields = requested(&["text"]);
+
+        assert!(validate_field_decisions(&fields, response(vec![decision("text", "Page Title", false)])).is_err());
+        assert!(validate_field_decisions(&fields, response(vec![decision("text", "Page Title", true)])).is_ok());
+    }
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in values.iter() {
+            headers.insert(*name, value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn get_retry_after_prefers_milliseconds() {
+        assert_eq!(get_retry_after(&headers(&[("retry-after", "2")]), now()), Some(Duration::from_secs(2)));
+        assert_eq!(
+            get_retry_after(&headers(&[("retry-after-ms", "1500"), ("retry-after", "2")]), now()),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(get_retry_after(&headers(&[("retry-after", "0.25")]), now()), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn get_retry_after_reads_http_dates() {
+        assert_eq!(
+            get_retry_after(&headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:30 GMT")]), now()),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            get_retry_after(&headers(&[("retry-after", "Wed, 21 Oct 2015 07:27:00 GMT")]), now()),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn get_retry_after_ignores_unusable_values() {
+        assert_eq!(get_retry_after(&HeaderMap::new(), now()), None);
+        assert_eq!(get_retry_after(&headers(&[("retry-after", "in a minute")]), now()), None);
+        assert_eq!(get_retry_after(&headers(&[("retry-after", "-1")]), now()), None);
+        assert_eq!(get_retry_after(&headers(&[("retry-after-ms", "NaN")]), now()), None);
+    }
+
+    #[test]
+    fn tally_picks_the_first_answer_with_most_votes() {
+        assert_eq!(tally(&["a", "b", "a"], 3), (0, 2.0 / 3.0));
+        assert_eq!(tally(&["b", "a", "a"], 3), (1, 2.0 / 3.0));
+        assert_eq!(tally(&["a", "b"], 2), (0, 0.5));
+    }
+
+    #[test]
+    fn tally_counts_failed_samples_against_confidence() {
+        assert_eq!(tally(&["a", "a"], 4), (0, 0.5));
+        assert_eq!(tally::<&str>(&[], 0), (0, 0.0));
+    }
+
+    #[test]
+    fn vote_field_decisions_votes_on_each_field() {
+        let sampled_decisions = vec![
+            vec![decision("text", "title", false), decision("class", "", true)],
+            vec![decision("text", "", true), decision("class", "", true)],
+            vec![decision("text", "heading", false), decision("class", "", true)],
+        ];
+
+        let votes = vote_field_decisions(sampled_decisions, 4);
+
+        assert_eq!(votes.len(), 2);
+        assert_eq!(votes[0].0.name, "title");
+        assert!(!votes[0].0.is_unmeaningful);
+        assert_eq!(votes[0].1, 0.5);
+        assert!(votes[1].0.is_unmeaningful);
+        assert_eq!(votes[1].1, 0.75);
+        assert!(vote_field_decisions(Vec::new(), 3).is_empty());
+    }
+
+    #[test]
+    fn keep_error_keeps_budget_errors_behind_context() {
+        let mut last_error = None;
+
+        keep_error(&mut last_error, Errors::UnexpectedError);
+        assert!(matches!(last_error, Some(Errors::UnexpectedError)));
+
+        keep_error(&mut last_error, Err::<(), _>(Errors::BudgetExceeded("used 10 of 10 tokens".to_string())).stage(Stage::LLM).unwrap_err());
+        keep_error(&mut last_error, Errors::UnexpectedError);
+
+        assert!(matches!(last_error.as_ref().map(Errors::root_cause), Some(Errors::BudgetExceeded(_))));
+    }
+}
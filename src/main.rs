@@ -1,6 +1,6 @@
 use std::io::{self, Read};
 use atty::Stream;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use clap::{Arg, App};
 use log::LevelFilter;
 use std::io::stdout;
@@ -16,8 +16,12 @@ mod data_node;
 mod document;
 mod document_format;
 mod document_node;
+mod drift;
+mod dry_run;
 mod environment;
+mod extraction;
 mod graph_node;
+mod graphviz;
 mod hash;
 mod id;
 mod lineage;
@@ -25,25 +29,35 @@ mod macros;
 mod normalization;
 mod organization;
 mod profile;
+mod profile_schema;
 mod provider;
+mod sandbox;
 mod transformation;
+mod transformation_report;
 mod translation;
 mod types;
 mod prelude;
 mod utility;
+mod worker;
 mod json_node;
 mod context;
 mod context_group;
 mod llm;
 mod traverse;
 mod meta_context;
+mod minhash;
 mod schema;
 mod network_analysis;
 mod node_analysis;
 mod schema_node;
+mod token_usage;
 
 use crate::prelude::*;
-use crate::provider::{YamlFileProvider};
+use crate::provider::{Provider, YamlFileProvider};
+use crate::document::Document;
+use crate::extraction::ExtractionPlan;
+use crate::profile::Profile;
+use crate::transformation_report::TransformationReport;
 
 const VERSION: &str = "1.0.0";
 
@@ -58,6 +72,49 @@ fn load_stdin() -> io::Result<String> {
     return Ok(buffer);
 }
 
+async fn load_input(matches: &clap::ArgMatches) -> Result<String, Errors> {
+    log::trace!("In load_input");
+
+    if let Ok(stdin) = load_stdin() {
+        log::info!("Received data from stdin");
+        Ok(stdin)
+    } else if let Some(path) = matches.value_of("file") {
+        log::info!("Received a file name");
+        get_file_as_text(path)
+    } else if let Some(url) = matches.value_of("url") {
+        log::info!("Received a URL");
+        fetch_url_as_text(url).await
+    } else {
+        Err(Errors::DocumentNotProvided)
+    }
+}
+
+fn load_profile(path: &str) -> Result<Profile, Errors> {
+    log::trace!("In load_profile");
+
+    let profile: Profile = serde_yaml::from_str(&get_file_as_text(path)?)?;
+
+    Ok(profile)
+}
+
+/// Token usage, and the nodes and networks skipped along the way, go to
+/// stderr so that they never mix with the output
+fn print_run_report(meta_context: &Arc<RwLock<MetaContext>>) {
+    let lock = read_lock!(meta_context);
+
+    eprintln!("{}", lock.token_usage.get_report());
+
+    let diagnostics = lock.get_diagnostics();
+
+    if !diagnostics.is_empty() {
+        eprintln!("diagnostics: {}", diagnostics.len());
+
+        for diagnostic in diagnostics.iter() {
+            eprintln!("  {}", diagnostic);
+        }
+    }
+}
+
 fn init_logging() {
     log::info!("Initializing logging...");
 
@@ -99,10 +156,71 @@ async fn main() {
             .long("url")
             .value_name("URL")
             .help("Provide url as document for processing"))
+        .arg(Arg::with_name("plan")
+            .long("plan")
+            .value_name("PLAN")
+            .help("Extract the document with a compiled extraction plan instead of the LLM"))
+        .arg(Arg::with_name("export-plan")
+            .long("export-plan")
+            .value_name("PLAN")
+            .help("Learn the document and write the compiled extraction plan to this path, merged with the plan it holds for the same profile"))
+        .arg(Arg::with_name("dot")
+            .long("dot")
+            .help("Output the document graph and basis networks in Graphviz DOT format"))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Estimate the LLM requests and tokens learning the document would take, without sending any"))
         .arg(Arg::with_name("version")
             .short('v')
             .long("version")
             .help("Display program version"))
+        .subcommand(App::new("transform-test")
+            .about("Run a profile's XML element and hash transformations against a sample document")
+            .arg(Arg::with_name("file")
+                .short('f')
+                .long("file")
+                .value_name("FILE")
+                .help("Provide file as sample document"))
+            .arg(Arg::with_name("url")
+                .short('u')
+                .long("url")
+                .value_name("URL")
+                .help("Provide url as sample document"))
+            .arg(Arg::with_name("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("YAML file containing the profile to test, instead of the profile matched by the provider"))
+            .arg(Arg::with_name("compare")
+                .long("compare")
+                .value_name("PROFILE")
+                .help("YAML file containing a second profile to diff against"))
+            .arg(Arg::with_name("xml-element-script")
+                .long("xml-element-script")
+                .value_name("SCRIPT")
+                .help("Diff against the profile with its XML element transformation script replaced"))
+            .arg(Arg::with_name("hash-script")
+                .long("hash-script")
+                .value_name("SCRIPT")
+                .help("Diff against the profile with its hash transformation script replaced")))
+        .subcommand(App::new("match-profile")
+            .about("Rank saved profiles by similarity to a sample document")
+            .arg(Arg::with_name("file")
+                .short('f')
+                .long("file")
+                .value_name("FILE")
+                .help("Provide file as sample document"))
+            .arg(Arg::with_name("url")
+                .short('u')
+                .long("url")
+                .value_name("URL")
+                .help("Provide url as sample document"))
+            .arg(Arg::with_name("count")
+                .long("count")
+                .value_name("COUNT")
+                .help("Number of candidates to show"))
+            .arg(Arg::with_name("explain")
+                .long("explain")
+                .help("Show the features each candidate shares with the document and the features unique to each")))
         .get_matches();
 
     if matches.is_present("version") {
@@ -110,6 +228,26 @@ async fn main() {
         return;
     }
 
+    if let Some(plan_path) = matches.value_of("plan") {
+        let result = async {
+            let plan = ExtractionPlan::from_json(&get_file_as_text(plan_path)?)?;
+            let text = load_input(&matches).await?;
+
+            plan.run_text(text)
+        }.await;
+
+        match result {
+            Ok(json) => {
+                println!("{}", json);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to run extraction plan: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let document_format = document_format::DocumentFormat::default();
 
     let provider = Arc::new(YamlFileProvider::new(String::from("provider.yaml")));
@@ -122,49 +260,235 @@ async fn main() {
 
     log::debug!("options: {:?}", options);
 
-    let document = {
+    if let Some(match_matches) = matches.subcommand_matches("match-profile") {
+        let result = async {
+            let text = load_input(match_matches).await?;
+            let document = Document::from_string(text, &None)?;
+            let feature_paths = document.get_feature_paths().ok_or(Errors::UnexpectedDocumentType)?;
+            let features = document.get_features().ok_or(Errors::UnexpectedDocumentType)?;
+
+            let count = match match_matches.value_of("count") {
+                Some(count) => count.parse::<usize>().map_err(|err| {
+                    log::error!("Invalid candidate count {}: {}", count, err);
+                    Errors::UnexpectedError
+                })?,
+                None => read_lock!(config::CONFIG).matching.candidate_count,
+            };
+
+            let candidates = provider.get_profile_candidates(&features, count).await?;
+            let drift_threshold = read_lock!(config::CONFIG).matching.drift_threshold;
+
+            let candidates: Vec<serde_json::Value> = candidates.iter().map(|candidate| {
+                let mut value = serde_json::json!({
+                    "profile_id": candidate.profile.id.to_string(),
+                    "description": candidate.profile.description,
+                    "status": candidate.profile.status,
+                    "version": candidate.profile.version,
+                    "group": candidate.profile.group,
+                    "score": candidate.score,
+                    "accepted": candidate.accepted,
+                    "drifted": !candidate.accepted && candidate.score >= drift_threshold,
+                });
+
+                if match_matches.is_present("explain") {
+                    value["explanation"] = serde_json::json!(candidate.profile.explain_match(&feature_paths));
+                }
+
+                value
+            }).collect();
+
+            Ok::<String, Errors>(serde_json::to_string_pretty(&candidates)?)
+        }.await;
+
+        match result {
+            Ok(json) => {
+                println!("{}", json);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to match profiles: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(test_matches) = matches.subcommand_matches("transform-test") {
+        let result = async {
+            let text = load_input(test_matches).await?;
+
+            let profile = match test_matches.value_of("profile") {
+                Some(path) => load_profile(path)?,
+                None => transformation_report::get_profile_for_text(provider.clone(), text.clone()).await?,
+            };
+
+            let report = TransformationReport::from_profile(&profile, text.clone())?;
+
+            let candidate = if let Some(path) = test_matches.value_of("compare") {
+                Some(load_profile(path)?)
+            } else if test_matches.is_present("xml-element-script") || test_matches.is_present("hash-script") {
+                let xml_element_script = test_matches.value_of("xml-element-script").map(get_file_as_text).transpose()?;
+                let hash_script = test_matches.value_of("hash-script").map(get_file_as_text).transpose()?;
+
+                Some(transformation_report::replace_scripts(&profile, xml_element_script, hash_script))
+            } else {
+                None
+            };
+
+            match candidate {
+                Some(candidate) => {
+                    let candidate_report = TransformationReport::from_profile(&candidate, text)?;
+
+                    report.diff(&candidate_report).to_json()
+                },
+                None => report.to_json(),
+            }
+        }.await;
+
+        match result {
+            Ok(json) => {
+                println!("{}", json);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to test transformations: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(plan_path) = matches.value_of("export-plan") {
+        let result = async {
+            let text = load_input(&matches).await?;
+            let meta_context = organization::organize_text(
+                provider.clone(),
+                text,
+                &Some(options.clone()),
+            ).await?;
+
+            print_run_report(&meta_context);
+
+            let plan = ExtractionPlan::compile(meta_context)?;
+
+            if !std::path::Path::new(plan_path).exists() {
+                return plan.to_json();
+            }
+
+            // Each document of a profile adds the structures it contains
+            let mut existing_plan = ExtractionPlan::from_json(&get_file_as_text(plan_path)?)?;
+
+            match existing_plan.merge(plan.clone()) {
+                Ok(()) => existing_plan.to_json(),
+                Err(_) => {
+                    log::warn!("Replacing the extraction plan at {}, which is for another profile", plan_path);
+                    plan.to_json()
+                }
+            }
+        }.await;
+
+        match result {
+            Ok(json) => {
+                if let Err(err) = write_text_to_file(plan_path, &json) {
+                    eprintln!("Failed to write extraction plan: {}", err);
+                    std::process::exit(1);
+                }
+
+                log::info!("Wrote extraction plan to {}", plan_path);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to compile extraction plan: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("dry-run") {
+        let result = async {
+            let text = load_input(&matches).await?;
+            let document = Document::from_string(text, &Some(options.clone()))?;
+
+            dry_run::estimate_document(provider.clone(), document).await
+        }.await;
+
+        match result {
+            Ok(report) => {
+                println!("{}", report);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to estimate document: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("dot") {
+        let result = async {
+            let text = load_input(&matches).await?;
+            let meta_context = organization::organize_text(
+                provider.clone(),
+                text,
+                &Some(options.clone()),
+            ).await?;
+
+            print_run_report(&meta_context);
+
+            let lock = read_lock!(meta_context);
+            lock.to_dot()
+        }.await;
+
+        match result {
+            Ok(dot) => {
+                println!("{}", dot);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Failed to render document graph: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let meta_context = {
         if let Ok(stdin) = load_stdin() {
             log::info!("Received data from stdin");
             
-            match normalization::normalize_text_to_document(
+            match normalization::normalize_text_to_meta_context(
                 provider.clone(),
                 stdin,
                 &Some(options),
-                &Some(document_format),
             ).await {
-                Ok(document) => document,
+                Ok(meta_context) => meta_context,
                 Err(err) => {
-                    eprintln!("Failed to normalize text from stdin: {:?}", err);
+                    eprintln!("Failed to normalize text from stdin: {}", err);
                     std::process::exit(1);
                 }
             }
         } else if let Some(path) = matches.value_of("file") {
             log::info!("Received a file name");
 
-            match normalization::normalize_file_to_document(
+            match normalization::normalize_file_to_meta_context(
                 provider.clone(),
                 path,
                 &Some(options),
-                &Some(document_format),
             ).await {
-                Ok(document) => document,
+                Ok(meta_context) => meta_context,
                 Err(err) => {
-                    eprintln!("Failed to normalize URL: {:?}", err);
+                    eprintln!("Failed to normalize URL: {}", err);
                     std::process::exit(1);
                 }
             }
         } else if let Some(url) = matches.value_of("url") {
             log::info!("Received a URL");
 
-            match normalization::normalize_url_to_document(
+            match normalization::normalize_url_to_meta_context(
                 provider.clone(),
                 url,
                 &Some(options),
-                &Some(document_format),
             ).await {
-                Ok(document) => document,
+                Ok(meta_context) => meta_context,
                 Err(err) => {
-                    eprintln!("Failed to normalize URL: {:?}", err);
+                    eprintln!("Failed to normalize URL: {}", err);
                     std::process::exit(1);
                 }
             }
@@ -174,6 +498,19 @@ async fn main() {
         }
     };
 
+    print_run_report(&meta_context);
+
+    let document = match traverse::traverse_meta_context(
+        meta_context,
+        &Some(document_format),
+    ) {
+        Ok(document) => document,
+        Err(err) => {
+            eprintln!("Failed to render normalized document: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     log::info!("Successfully processed document");
 
     println!("{}", document.to_string());
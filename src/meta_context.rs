@@ -1,4 +1,4 @@
-use std::sync::{Arc};
+use std::sync::{Arc, RwLock};
 use std::collections::{HashMap, HashSet};
 
 use crate::prelude::*;
@@ -10,6 +10,10 @@ use crate::basis_network::BasisNetwork;
 use crate::profile::Profile;
 use crate::transformation::SchemaTransformation;
 use crate::document::Document;
+use crate::graphviz::render_meta_context;
+use crate::profile_schema::{ProfileSchema, SchemaViolation};
+use crate::drift::ProfileDrift;
+use crate::token_usage::TokenUsage;
 
 pub struct MetaContext {
     pub contexts: Option<HashMap<ID, Arc<Context>>>,
@@ -21,6 +25,11 @@ pub struct MetaContext {
     pub schema_transformations: Option<HashMap<ID, Arc<SchemaTransformation>>>,
     pub document: Option<Document>,
     pub schema_string: Option<Arc<String>>,
+    pub profile_schema: Option<Arc<ProfileSchema>>,
+    pub schema_violations: Vec<SchemaViolation>,
+    pub diagnostics: RwLock<Vec<Diagnostic>>,
+    pub profile_drift: Option<ProfileDrift>,
+    pub token_usage: Arc<TokenUsage>,
 }
 
 impl MetaContext {
@@ -35,6 +44,11 @@ impl MetaContext {
             schema_transformations: None,
             document: None,
             schema_string: None,
+            profile_schema: None,
+            schema_violations: Vec::new(),
+            diagnostics: RwLock::new(Vec::new()),
+            profile_drift: None,
+            token_usage: Arc::new(TokenUsage::new()),
         }
     }
 
@@ -44,11 +58,11 @@ impl MetaContext {
     ) -> Result<Option<Arc<BasisNetwork>>, Errors> {
         log::trace!("In get_basis_network_by_subgraph_hash");
         
-        let basis_networks = self.basis_networks.as_ref().unwrap();
+        let basis_networks = self.basis_networks.as_ref().ok_or(Errors::BasisNetworksNotProvided)?;
 
         for basis_network in basis_networks.values() {
             if basis_network.subgraph_hash == *subgraph_hash {
-                return Ok(Some(Arc::clone(&basis_network)));
+                return Ok(Some(Arc::clone(basis_network)));
             }
         }
 
@@ -61,11 +75,11 @@ impl MetaContext {
     ) -> Result<Option<Arc<BasisNode>>, Errors> {
         log::trace!("In get_basis_node_by_lineage");
 
-        let basis_nodes = self.basis_nodes.as_ref().unwrap();
+        let basis_nodes = self.basis_nodes.as_ref().ok_or(Errors::BasisNodesNotProvided)?;
 
         for basis_node in basis_nodes.values() {
             if basis_node.lineage == *lineage {
-                return Ok(Some(Arc::clone(&basis_node)));
+                return Ok(Some(Arc::clone(basis_node)));
             }
         }
 
@@ -77,8 +91,10 @@ impl MetaContext {
         document: Document
     ) {
         if let Some(schema) = document.schema.clone() {
-            self.schema_string = Some(Arc::new(serde_json::to_string(&schema)
-                .expect("Could not convert schema to string")))
+            match serde_json::to_string(&schema) {
+                Ok(schema_string) => self.schema_string = Some(Arc::new(schema_string)),
+                Err(err) => log::error!("Could not convert schema to string: {}", err),
+            }
         }
 
         self.document = Some(document);
@@ -91,10 +107,23 @@ impl MetaContext {
         self.schema_transformations = Some(schema_transformations);
     }
 
+    pub fn update_profile_schema(
+        &mut self,
+        profile_schema: Arc<ProfileSchema>,
+        schema_violations: Vec<SchemaViolation>
+    ) {
+        self.profile_schema = Some(profile_schema);
+        self.schema_violations = schema_violations;
+    }
+
     pub fn update_profile(&mut self, profile: Arc<Profile>) {
         self.profile = Some(profile);
     }
 
+    pub fn update_profile_drift(&mut self, profile_drift: ProfileDrift) {
+        self.profile_drift = Some(profile_drift);
+    }
+
     pub fn update_data_structures(&mut self, contexts: HashMap<ID, Arc<Context>>, graph_root: Graph) {
         self.contexts = Some(contexts);
         self.graph_root = Some(graph_root);
@@ -112,34 +141,161 @@ impl MetaContext {
         self.basis_networks = Some(networks);
     }
 
-    pub fn get_original_document(&self) -> String {
+    /// Records a failure that the pipeline has skipped over
+    pub fn add_diagnostic(&self, diagnostic: Diagnostic) {
+        log::warn!("{}", diagnostic);
+
+        write_lock!(self.diagnostics).push(diagnostic);
+    }
+
+    pub fn get_diagnostics(&self) -> Vec<Diagnostic> {
+        read_lock!(self.diagnostics).clone()
+    }
+
+    pub fn to_dot(&self) -> Result<String, Errors> {
+        log::trace!("In to_dot");
+
+        render_meta_context(self)
+    }
+
+    pub fn get_original_document(&self) -> Result<String, Errors> {
         log::trace!("In get_original_document");
 
         let mut document = String::new();
         let mut visited_lineages: HashSet<Lineage> = HashSet::new();
-        let root_node = self.graph_root.clone().unwrap();
+        let root_node = self.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?;
+        let contexts = self.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
 
         traverse_for_condensed_document(
-            self,
+            contexts,
             Arc::clone(&root_node),
             &mut visited_lineages,
             &mut document,
-        );
+        )?;
 
-        document
+        Ok(document)
+    }
+
+    /// The condensed document split into chunks of at most chunk_length
+    /// characters. Chunks end between subtrees, and a subtree is only split
+    /// up when it does not fit in a chunk on its own.
+    pub fn get_original_document_chunks(&self, chunk_length: usize) -> Result<Vec<String>, Errors> {
+        log::trace!("In get_original_document_chunks");
+
+        let chunk_length = chunk_length.max(1);
+        let mut visited_lineages: HashSet<Lineage> = HashSet::new();
+        let root_node = self.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?;
+        let contexts = self.contexts.as_ref().ok_or(Errors::ContextsNotProvided)?;
+
+        let (segments, _) = collect_document_segments(
+            contexts,
+            root_node,
+            &mut visited_lineages,
+            chunk_length,
+        )?;
+
+        if let [document] = segments.as_slice() {
+            return Ok(vec![document.clone()]);
+        }
+
+        Ok(pack_segments(segments, chunk_length))
     }
 }
 
+/// Renders the condensed document bottom-up, in the same order as
+/// traverse_for_condensed_document, as segments no longer than chunk_length.
+/// A subtree that fits is joined into a single segment. Returns the segments
+/// along with their length in characters.
+fn collect_document_segments(
+    contexts: &HashMap<ID, Arc<Context>>,
+    current_node: Graph,
+    visited_lineages: &mut HashSet<Lineage>,
+    chunk_length: usize,
+) -> Result<(Vec<String>, usize), Errors> {
+    let lock = read_lock!(current_node);
+    let current_context = contexts.get(&lock.id).ok_or(Errors::ContextsNotProvided)?;
+    let should_render = !visited_lineages.contains(&current_context.lineage);
+
+    visited_lineages.insert(current_context.lineage.clone());
+
+    let (opening, closing) = read_lock!(current_context.document_node).to_string_components();
+    let closing = closing.unwrap_or_default();
+
+    let mut segments = Vec::new();
+    let mut length = 0;
+
+    if should_render {
+        length += opening.chars().count();
+        segments.extend(split_characters(&opening, chunk_length));
+    }
+
+    for child in &lock.children {
+        let (child_segments, child_length) = collect_document_segments(
+            contexts,
+            Arc::clone(child),
+            visited_lineages,
+            chunk_length,
+        )?;
+
+        length += child_length;
+        segments.extend(child_segments);
+    }
+
+    if should_render {
+        length += closing.chars().count();
+        segments.extend(split_characters(&closing, chunk_length));
+    }
+
+    if length <= chunk_length {
+        return Ok((vec![segments.concat()], length));
+    }
+
+    Ok((segments, length))
+}
+
+/// Joins consecutive segments into chunks of at most chunk_length characters
+fn pack_segments(segments: Vec<String>, chunk_length: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut length = 0;
+
+    for segment in segments {
+        let segment_length = segment.chars().count();
+
+        if length + segment_length > chunk_length && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            length = 0;
+        }
+
+        chunk.push_str(&segment);
+        length += segment_length;
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// Splits on character boundaries, for text too long for any chunk
+fn split_characters(text: &str, chunk_length: usize) -> Vec<String> {
+    text.chars()
+        .collect::<Vec<char>>()
+        .chunks(chunk_length)
+        .map(|characters| characters.iter().collect())
+        .collect()
+}
+
 fn traverse_for_condensed_document(
-    meta_context: &MetaContext,
+    contexts: &HashMap<ID, Arc<Context>>,
     current_node: Graph,
     visited_lineages: &mut HashSet<Lineage>,
     document: &mut String
-) {
+) -> Result<(), Errors> {
     let lock = read_lock!(current_node);
     let current_id = lock.id.clone();
-    let current_context = meta_context.contexts.clone().unwrap();
-    let current_context = current_context.get(&current_id).unwrap();
+    let current_context = contexts.get(&current_id).ok_or(Errors::ContextsNotProvided)?;
     let current_lineage = current_context.lineage.clone();
     let document_node = current_context.document_node.clone();
 
@@ -155,11 +311,11 @@ fn traverse_for_condensed_document(
 
     for child in &lock.children {
         traverse_for_condensed_document(
-            &meta_context,
+            contexts,
             Arc::clone(child),
             visited_lineages,
             document
-        );
+        )?;
     }
 
     if should_render {
@@ -167,4 +323,38 @@ fn traverse_for_condensed_document(
 
         document.push_str(b.as_deref().unwrap_or(""));
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn pack_segments_fills_chunks_in_order() {
+        let chunks = pack_segments(segments(&["<a>", "one", "</a>", "<b>", "two", "</b>"]), 10);
+
+        assert_eq!(chunks, segments(&["<a>one</a>", "<b>two</b>"]));
+        assert!(pack_segments(Vec::new(), 10).is_empty());
+    }
+
+    #[test]
+    fn pack_segments_never_splits_a_segment() {
+        let chunks = pack_segments(segments(&["abc", "defghijk", "l"]), 5);
+
+        assert_eq!(chunks, segments(&["abc", "defghijk", "l"]));
+    }
+
+    #[test]
+    fn split_characters_respects_character_boundaries() {
+        let chunks = split_characters("ééééé日本", 3);
+
+        assert_eq!(chunks, segments(&["ééé", "éé日", "本"]));
+        assert!(split_characters("", 3).is_empty());
+    }
 }
@@ -0,0 +1,186 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+
+use crate::prelude::*;
+
+// Signatures computed with an earlier hash family never agree with current
+// ones, so they are recomputed rather than compared
+const SIGNATURE_VERSION: u32 = 1;
+
+/// MinHash signature of a feature set. The share of positions at which two
+/// signatures agree estimates the Jaccard similarity of their feature sets.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MinHashSignature {
+    #[serde(default)]
+    pub version: u32,
+    pub values: Vec<String>,
+}
+
+impl MinHashSignature {
+    /// Computes the signature with one seeded 64-bit hash of each feature per
+    /// permutation, keeping the minimum value of each
+    pub fn from_features<'a, I>(features: I, permutations: usize) -> Self
+    where
+        I: IntoIterator<Item = &'a Hash>,
+    {
+        log::trace!("In from_features");
+
+        let features: Vec<String> = features
+            .into_iter()
+            .filter_map(|feature| feature.to_string())
+            .collect();
+
+        let values = (0..permutations)
+            .map(|seed| {
+                features
+                    .iter()
+                    .map(|feature| seeded_hash(seed as u64, feature))
+                    .min()
+                    .map(|value| format!("{:016x}", value))
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        MinHashSignature {
+            version: SIGNATURE_VERSION,
+            values,
+        }
+    }
+
+    pub fn is_current(&self, permutations: usize) -> bool {
+        self.version == SIGNATURE_VERSION && self.values.len() == permutations
+    }
+
+    /// Splits the signature into bands and hashes each one into a bucket key.
+    /// Signatures that agree on every row of any band share a bucket.
+    pub fn get_band_keys(&self, bands: usize) -> Vec<String> {
+        if bands == 0 || self.values.is_empty() {
+            return Vec::new();
+        }
+
+        let rows = (self.values.len() / bands).max(1);
+
+        self.values
+            .chunks(rows)
+            .take(bands)
+            .enumerate()
+            .map(|(band_index, band)| {
+                format!("{}:{}", band_index, Hash::hash(band.join("").as_bytes()))
+            })
+            .collect()
+    }
+}
+
+/// Locality sensitive hashing index over MinHash signatures, returning the
+/// entries likely to be similar to a signature without comparing against
+/// every entry
+#[derive(Clone, Debug)]
+pub struct LshIndex {
+    bands: usize,
+    buckets: HashMap<String, HashSet<ID>>,
+}
+
+impl LshIndex {
+    pub fn new(bands: usize) -> Self {
+        LshIndex {
+            bands,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: &ID, signature: &MinHashSignature) {
+        for key in signature.get_band_keys(self.bands) {
+            self.buckets.entry(key).or_default().insert(id.clone());
+        }
+    }
+
+    pub fn remove(&mut self, id: &ID) {
+        for ids in self.buckets.values_mut() {
+            ids.remove(id);
+        }
+
+        self.buckets.retain(|_, ids| !ids.is_empty());
+    }
+
+    pub fn query(&self, signature: &MinHashSignature) -> HashSet<ID> {
+        log::trace!("In query");
+
+        signature
+            .get_band_keys(self.bands)
+            .iter()
+            .filter_map(|key| self.buckets.get(key))
+            .flat_map(|ids| ids.iter().cloned())
+            .collect()
+    }
+}
+
+// The standard library hashers are not guaranteed to be stable between
+// releases, and signatures are stored with profiles
+fn seeded_hash(seed: u64, feature: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(feature.as_bytes());
+
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(range: std::ops::Range<usize>) -> Vec<Hash> {
+        range.map(|index| Hash::from_str(&format!("feature-{}", index))).collect()
+    }
+
+    fn agreement(a: &MinHashSignature, b: &MinHashSignature) -> f64 {
+        let matching = a.values.iter().zip(b.values.iter()).filter(|(a, b)| a == b).count();
+
+        matching as f64 / a.values.len() as f64
+    }
+
+    #[test]
+    fn signatures_estimate_jaccard_similarity() {
+        // 90 shared features out of 110 in total
+        let a = MinHashSignature::from_features(features(0..100).iter(), 128);
+        let b = MinHashSignature::from_features(features(10..110).iter(), 128);
+
+        let estimate = agreement(&a, &b);
+
+        assert!((estimate - 90.0 / 110.0).abs() < 0.15, "estimate was {}", estimate);
+        assert_eq!(agreement(&a, &a), 1.0);
+    }
+
+    #[test]
+    fn lsh_finds_near_duplicates_only() {
+        let mut index = LshIndex::new(32);
+        let near = ID::new();
+        let distant = ID::new();
+
+        index.insert(&near, &MinHashSignature::from_features(features(0..100).iter(), 128));
+        index.insert(&distant, &MinHashSignature::from_features(features(500..600).iter(), 128));
+
+        let query = MinHashSignature::from_features(features(5..105).iter(), 128);
+        let candidates = index.query(&query);
+
+        assert!(candidates.contains(&near));
+        assert!(!candidates.contains(&distant));
+
+        index.remove(&near);
+
+        assert!(index.query(&query).is_empty());
+    }
+
+    #[test]
+    fn outdated_signatures_are_not_current() {
+        let signature = MinHashSignature::from_features(features(0..10).iter(), 16);
+
+        assert!(signature.is_current(16));
+        assert!(!signature.is_current(32));
+        assert!(!MinHashSignature { version: 0, ..signature }.is_current(16));
+    }
+}
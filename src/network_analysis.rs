@@ -1,4 +1,4 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
 use tokio::task;
 use tokio::sync::Semaphore;
@@ -10,11 +10,13 @@ use crate::graph_node::{Graph};
 use crate::basis_network::{
     BasisNetwork,
     NetworkRelationship,
+    Recursion,
 };
 use crate::config::{CONFIG};
-use crate::llm::LLM;
+use crate::llm::{LLM, get_character_limit, find_instructions};
 use crate::meta_context::MetaContext;
 use crate::basis_graph::BasisGraph;
+use crate::token_usage::TokenUsage;
 
 pub async fn get_basis_graph<P: Provider>(
     provider: Arc<P>,
@@ -22,23 +24,44 @@ pub async fn get_basis_graph<P: Provider>(
 ) -> Result<Arc<BasisGraph>, Errors> {
     log::trace!("In get_basis_graph");
 
-    let original_document = {
-        let lock = read_lock!(meta_context);
-        lock.get_original_document()
-    };
     let graph_root = {
         let lock = read_lock!(meta_context);
         lock.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?
     };
     let lineage = read_lock!(graph_root).lineage.clone();
 
-    if let Some(basis_graph) = provider.get_basis_graph_by_lineage(&lineage).await? {
+    if let Some(basis_graph) = provider.get_basis_graph_by_lineage(&lineage).await.stage(Stage::Provider)? {
         log::info!("Provider has supplied basis graph");
 
         return Ok(Arc::new(basis_graph));
     };
 
-    let (name, description, structure) = LLM::categorize_and_summarize(original_document).await?;
+    let document_chunks = {
+        let chunk_length = read_lock!(CONFIG).llm.summary_chunk_length;
+        let lock = read_lock!(meta_context);
+        lock.get_original_document_chunks(chunk_length)?
+    };
+
+    let token_usage = read_lock!(meta_context).token_usage.clone();
+    let (name, description, structure, suspicious_content) = match LLM::categorize_and_summarize(token_usage, document_chunks).await {
+        Ok(summary) => summary,
+        // The graph is left undescribed and unsaved, for a later run to
+        // summarize
+        Err(err) if TokenUsage::is_degraded(&err) => {
+            log::warn!("Leaving the basis graph undescribed: {}", err);
+
+            return Ok(Arc::new(BasisGraph {
+                id: ID::new(),
+                name: String::new(),
+                description: String::new(),
+                structure: String::new(),
+                lineage,
+                suspicious_content: false,
+                needs_review: true,
+            }));
+        },
+        Err(err) => return Err(err),
+    };
 
     let basis_graph = BasisGraph {
         id: ID::new(),
@@ -46,12 +69,14 @@ pub async fn get_basis_graph<P: Provider>(
         description,
         structure,
         lineage: lineage.clone(),
+        suspicious_content,
+        needs_review: suspicious_content,
     };
 
     provider.save_basis_graph(
         &lineage,
         basis_graph.clone()
-    ).await?;
+    ).await.stage(Stage::Provider)?;
     
     Ok(Arc::new(basis_graph))
 }
@@ -67,26 +92,7 @@ pub async fn get_basis_networks<P: Provider>(
         lock.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?
     };
 
-    let mut queue = VecDeque::new();
-    let mut unique_subgraphs = HashMap::new();
-
-    queue.push_back(graph_root);
-
-    while let Some(current) = queue.pop_front() {
-        let current_read = read_lock!(current);
-
-        if current_read.children.is_empty() {
-            continue;
-        }
-
-        if !unique_subgraphs.contains_key(&current_read.subgraph_hash) {
-            unique_subgraphs.insert(current_read.subgraph_hash.clone(), current.clone());
-        }
-
-        for child in &current_read.children {
-            queue.push_back(child.clone());
-        }
-    }
+    let unique_subgraphs = get_unique_subgraphs(graph_root);
 
     log::info!("Number of unique subgraphs: {:?}", unique_subgraphs.len());
 
@@ -98,35 +104,49 @@ pub async fn get_basis_networks<P: Provider>(
     if max_concurrency == 1 {
         let mut results = HashMap::new();
 
-        for subgraph in unique_subgraphs.values().cloned() {
+        for subgraph in unique_subgraphs.values() {
             let cloned_provider = Arc::clone(&provider);
             let cloned_meta_context = Arc::clone(&meta_context);
+            let subgraph_hash = read_lock!(subgraph).subgraph_hash.to_string().unwrap_or_default();
             let result = get_basis_network(
                 cloned_provider,
                 cloned_meta_context,
                 subgraph.clone()
-            ).await?;
-
-            results.insert(result.id.clone(), Arc::new(result));
+            ).await.subgraph_hash(Stage::BasisNetwork, &subgraph_hash);
+
+            match result {
+                Ok(basis_network) => {
+                    results.insert(basis_network.id.clone(), Arc::new(basis_network));
+                },
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::BasisNetwork, err));
+                }
+            }
         }
 
         Ok(results)
     } else {
-        let semaphore = Arc::new(Semaphore::new(max_concurrency));
+        // The LLM limiter only holds a permit per request, so tasks are
+        // bounded here as well rather than spawned all at once
+        let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
         let mut handles = Vec::new();
 
         for subgraph in unique_subgraphs.values().cloned() {
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
             let cloned_provider = Arc::clone(&provider);
             let cloned_meta_context = Arc::clone(&meta_context);
+            let permit = Arc::clone(&semaphore)
+                .acquire_owned()
+                .await
+                .map_err(|_| Errors::UnexpectedError)?;
 
             let handle = task::spawn(async move {
                 let _permit = permit;
+                let subgraph_hash = read_lock!(subgraph).subgraph_hash.to_string().unwrap_or_default();
                 let basis_network = get_basis_network(
                     cloned_provider,
                     cloned_meta_context,
                     subgraph.clone()
-                ).await?;
+                ).await.subgraph_hash(Stage::BasisNetwork, &subgraph_hash)?;
 
                 Ok((basis_network.id.clone(), Arc::new(basis_network)))
             });
@@ -134,7 +154,18 @@ pub async fn get_basis_networks<P: Provider>(
         }
 
         let results: Vec<Result<(ID, Arc<BasisNetwork>), Errors>> = try_join_all(handles).await?;
-        let hashmap_results: HashMap<ID, Arc<BasisNetwork>> = results.into_iter().collect::<Result<_, _>>()?;
+        let mut hashmap_results: HashMap<ID, Arc<BasisNetwork>> = HashMap::new();
+
+        for result in results.into_iter() {
+            match result {
+                Ok((id, basis_network)) => {
+                    hashmap_results.insert(id, basis_network);
+                },
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::BasisNetwork, err));
+                }
+            }
+        }
 
         Ok(hashmap_results)
     }
@@ -157,7 +188,8 @@ async fn get_basis_network<P: Provider>(
     };
 
     let target_subgraph_hash = read_lock!(graph).subgraph_hash.clone();
-    log::debug!("target_subgraph_hash: {}", target_subgraph_hash.to_string().unwrap());
+    let target_subgraph_hash_string = target_subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?;
+    log::debug!("target_subgraph_hash: {}", target_subgraph_hash_string);
 
     if read_lock!(graph).parents.is_empty() {
         log::info!("Node is root node; going to create null network for this node");
@@ -165,17 +197,66 @@ async fn get_basis_network<P: Provider>(
         return Ok(add_null_network(provider.clone(), target_subgraph_hash.clone()).await?);
     }
 
-    if let Some(basis_network) = provider.get_basis_network_by_subgraph_hash(&target_subgraph_hash.to_string().unwrap()).await? {
+    if let Some(basis_network) = provider.get_basis_network_by_subgraph_hash(&target_subgraph_hash_string).await.stage(Stage::Provider)? {
         log::info!("Provider has supplied basis network");
 
         return Ok(basis_network);
     };
 
+    if let Some(lineage) = detect_recursion(Arc::clone(&graph)) {
+        log::info!("Subgraph structure repeats under itself; treating it as recursive");
+
+        let target_context = contexts
+            .get(&read_lock!(graph).id)
+            .ok_or(Errors::ContextsNotProvided)?
+            .clone();
+
+        let target_json = target_context.generate_json(
+            Arc::clone(&provider),
+            Arc::clone(&meta_context)
+        ).await?;
+
+        if !target_json.is_empty() {
+            let truncate_at = target_json.char_indices().nth(get_fragment_character_limit()).map_or(target_json.len(), |(idx, _)| idx);
+
+            let token_usage = read_lock!(meta_context).token_usage.clone();
+            let (name, description) = LLM::get_recursion(
+                token_usage,
+                basis_graph.structure.clone(),
+                target_json[..truncate_at].to_string(),
+            ).await?;
+
+            let suspicious_content = has_suspicious_content(&description, [&target_json[..truncate_at]]);
+
+            let basis_network = BasisNetwork {
+                id: ID::new(),
+                description,
+                subgraph_hash: target_subgraph_hash_string.clone(),
+                name,
+                relationship: NetworkRelationship::Recursion(Recursion { lineage }),
+                // Recursion is detected from the structure, only its name
+                // comes from the LLM
+                confidence: 1.0,
+                needs_review: false,
+                suspicious_content: false,
+            }.with_suspicious_content(suspicious_content);
+
+            provider.save_basis_network(
+                target_subgraph_hash_string.clone(),
+                basis_network.clone(),
+            ).await.stage(Stage::Provider)?;
+
+            return Ok(basis_network);
+        }
+
+        log::info!("Recursive subgraph does not result in any meaningful JSON; continuing with association analysis");
+    }
+
     log::info!("Generating json for siblings...");
 
     let parent: Graph = read_lock!(graph).parents
         .first()
-        .unwrap()
+        .ok_or(Errors::UnexpectedError)?
         .clone();
 
     let sibling_contexts: Vec<_> = read_lock!(parent).children
@@ -183,10 +264,10 @@ async fn get_basis_network<P: Provider>(
         .map(|sibling| {
             contexts
                 .get(&read_lock!(sibling).id)
-                .unwrap()
-                .clone()
+                .cloned()
+                .ok_or(Errors::ContextsNotProvided)
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
 
     log::info!("Number of sibling contexts: {}", sibling_contexts.len());
 
@@ -202,7 +283,7 @@ async fn get_basis_network<P: Provider>(
 
         
 
-        let truncate_at = sibling_json.char_indices().nth(2000).map_or(sibling_json.len(), |(idx, _)| idx);
+        let truncate_at = sibling_json.char_indices().nth(get_fragment_character_limit()).map_or(sibling_json.len(), |(idx, _)| idx);
         sibling_json.truncate(truncate_at);
 
 
@@ -217,7 +298,7 @@ async fn get_basis_network<P: Provider>(
         }
 
         if !sibling_json.is_empty() {
-            sibling_jsons.push((subgraph_hash.to_string().unwrap().clone(), sibling_json));
+            sibling_jsons.push((subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?, sibling_json));
         }
     }
 
@@ -231,7 +312,7 @@ async fn get_basis_network<P: Provider>(
 
     let non_target_count = sibling_jsons
         .iter()
-        .filter(|(fragment_id, _)| *fragment_id != target_subgraph_hash.to_string().unwrap())
+        .filter(|(fragment_id, _)| *fragment_id != target_subgraph_hash_string)
         .count();
 
     if non_target_count < 1 {
@@ -242,55 +323,290 @@ async fn get_basis_network<P: Provider>(
     log::info!("Going to consult LLM for relationships between subgraphs...");
 
     let overall_context = basis_graph.structure.clone();
-
-    let (name, matches, description) = LLM::get_relationships(
+    let token_usage = read_lock!(meta_context).token_usage.clone();
+    let (name, matches, description, confidence) = LLM::get_relationships(
+        token_usage,
         overall_context.clone(),
-        target_subgraph_hash.to_string().unwrap().clone(),
+        target_subgraph_hash_string.clone(),
         sibling_jsons.clone()
     ).await?;
 
     log::info!("Done asking LLM for relationships");
 
+    let suspicious_content = has_suspicious_content(
+        &description,
+        sibling_jsons.iter().map(|(_, json)| json.as_str())
+    );
+
     if matches.is_empty() {
         log::info!("LLM did not find any relationships between subgraphs");
 
-        return Ok(add_null_network(provider.clone(), target_subgraph_hash.clone()).await?);
+        let basis_network = BasisNetwork::new_null_network(&target_subgraph_hash_string)
+            .with_confidence(confidence)
+            .with_suspicious_content(suspicious_content);
+
+        provider.save_basis_network(
+            target_subgraph_hash_string.clone(),
+            basis_network.clone(),
+        ).await.stage(Stage::Provider)?;
+
+        return Ok(basis_network);
     }
 
     log::info!("LLM determined subgraphs are associated: {:?}", matches);
 
-    let associated_subgraphs = matches.iter().cloned()
-        //.chain(std::iter::once(target_subgraph_hash.to_string().unwrap().clone()))
-        .collect();
+    let associated_subgraphs = matches.to_vec();
 
     let basis_network = BasisNetwork {
         id: ID::new(),
         description: description.clone(),
-        subgraph_hash: target_subgraph_hash.to_string().unwrap().clone(),
+        subgraph_hash: target_subgraph_hash_string.clone(),
         name: name.clone(),
         relationship: NetworkRelationship::Association(associated_subgraphs),
-    };
+        confidence: 1.0,
+        needs_review: false,
+        suspicious_content: false,
+    }.with_confidence(confidence).with_suspicious_content(suspicious_content);
 
     provider.save_basis_network(
-        target_subgraph_hash.to_string().unwrap().clone(),
+        target_subgraph_hash_string.clone(),
         basis_network.clone(),
-    ).await?;
+    ).await.stage(Stage::Provider)?;
 
     Ok(basis_network)
 }
 
+/// Whether the description of a network, or any of the fragments it was
+/// decided from, read like instructions to the LLM. The context shown along
+/// with the fragments is flagged on the basis graph instead.
+fn has_suspicious_content<'a>(description: &'a str, fragments: impl IntoIterator<Item = &'a str>) -> bool {
+    let instructions = std::iter::once(description)
+        .chain(fragments)
+        .find_map(find_instructions);
+
+    if let Some(instructions) = &instructions {
+        log::warn!("Fragments read like instructions to the LLM, flagging network for review: {}", instructions);
+    }
+
+    instructions.is_some()
+}
+
+/// JSON fragments shown to the LLM are truncated to this many characters,
+/// set by snippets.fragment_token_budget
+pub fn get_fragment_character_limit() -> usize {
+    get_character_limit(read_lock!(CONFIG).snippets.fragment_token_budget)
+}
+
+/// The first node found for each distinct subgraph hash, leaving out leaves
+pub fn get_unique_subgraphs(graph_root: Graph) -> HashMap<Hash, Graph> {
+    log::trace!("In get_unique_subgraphs");
+
+    let mut queue = VecDeque::new();
+    let mut unique_subgraphs = HashMap::new();
+
+    queue.push_back(graph_root);
+
+    while let Some(current) = queue.pop_front() {
+        let current_read = read_lock!(current);
+
+        if current_read.children.is_empty() {
+            continue;
+        }
+
+        if !unique_subgraphs.contains_key(&current_read.subgraph_hash) {
+            unique_subgraphs.insert(current_read.subgraph_hash.clone(), current.clone());
+        }
+
+        for child in &current_read.children {
+            queue.push_back(child.clone());
+        }
+    }
+
+    unique_subgraphs
+}
+
+// A subgraph is recursive when a node with the same hash as the target, or as
+// one of the target's siblings of the same hash, appears again among its
+// descendants as a nested instance; e.g. a comment containing replies.
+pub fn detect_recursion(graph: Graph) -> Option<Lineage> {
+    log::trace!("In detect_recursion");
+
+    let (target_hash, target_lineage, parents) = {
+        let lock = read_lock!(graph);
+        (lock.hash.clone(), lock.lineage.clone(), lock.parents.clone())
+    };
+
+    let candidates: Vec<Graph> = match parents.first() {
+        Some(parent) => read_lock!(parent).children
+            .iter()
+            .filter(|sibling| read_lock!(sibling).hash == target_hash)
+            .cloned()
+            .collect(),
+        None => vec![graph],
+    };
+
+    for candidate in candidates {
+        let mut queue: VecDeque<Graph> = read_lock!(candidate).children.iter().cloned().collect();
+
+        while let Some(current) = queue.pop_front() {
+            if is_nested_instance(&candidate, &current) {
+                return Some(target_lineage);
+            }
+
+            queue.extend(read_lock!(current).children.iter().cloned());
+        }
+    }
+
+    None
+}
+
+/// Whether a descendant of an instance of a recursive object is itself an
+/// instance nested within it. Hashes only cover tag and attribute names, so
+/// the children of both are also compared by subgraph hash, and at least two
+/// of them must repeat: nodes that only share tag and attribute names, or a
+/// single child, are ordinary nesting.
+pub fn is_nested_instance(instance: &Graph, descendant: &Graph) -> bool {
+    if read_lock!(instance).hash != read_lock!(descendant).hash {
+        return false;
+    }
+
+    let instance_children = get_child_subgraph_hashes(instance);
+    let descendant_children = get_child_subgraph_hashes(descendant);
+
+    let intersection = instance_children.intersection(&descendant_children).count();
+    let union = instance_children.union(&descendant_children).count();
+
+    let threshold = read_lock!(CONFIG).matching.recursion_similarity_threshold;
+
+    intersection >= 2 && intersection as f64 / union as f64 >= threshold
+}
+
+fn get_child_subgraph_hashes(graph: &Graph) -> HashSet<Hash> {
+    read_lock!(graph).children
+        .iter()
+        .map(|child| read_lock!(child).subgraph_hash.clone())
+        .collect()
+}
+
 async fn add_null_network<P: Provider>(
     provider: Arc<P>,
     target_subgraph_hash: Hash,
 ) -> Result<BasisNetwork, Errors> {
-    let basis_network = BasisNetwork::new_null_network(
-        &target_subgraph_hash.to_string().unwrap()
-    );
+    let target_subgraph_hash = target_subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?;
+    let basis_network = BasisNetwork::new_null_network(&target_subgraph_hash);
 
     provider.save_basis_network(
-        target_subgraph_hash.to_string().unwrap().clone(),
+        target_subgraph_hash.clone(),
         basis_network.clone(),
-    ).await?;
+    ).await.stage(Stage::Provider)?;
 
     Ok(basis_network)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::document::Document;
+    use crate::config::BudgetBehavior;
+    use crate::profile::Profile;
+    use crate::provider::VoidProvider;
+    use crate::traverse::traverse_document;
+
+    const COMMENTS: &str = concat!(
+        r#"<html><body><div class="comment"><span class="author">alice</span><p>First</p>"#,
+        r#"<div class="replies"><div class="comment"><span class="author">bob</span><p>Second</p></div></div>"#,
+        r#"<div class="footer"><a class="link" href="/reply">Reply</a><em>2 hours ago</em></div>"#,
+        r#"</div></body></html>"#,
+    );
+
+    fn traverse(html: &str) -> (HashMap<ID, Arc<Context>>, Graph) {
+        let meta_context = Arc::new(RwLock::new(MetaContext::new()));
+        write_lock!(meta_context).update_profile(Arc::new(Profile::default_profile(&HashMap::new())));
+
+        let document = Document::from_string(html.to_string(), &None).unwrap();
+
+        traverse_document(document, meta_context).unwrap()
+    }
+
+    fn find_by_class(contexts: &HashMap<ID, Arc<Context>>, root: &Graph, class: &str) -> Vec<Graph> {
+        let mut found = Vec::new();
+        let mut queue: VecDeque<Graph> = VecDeque::from([Arc::clone(root)]);
+
+        while let Some(current) = queue.pop_front() {
+            let context = &contexts[&read_lock!(current).id];
+
+            if read_lock!(context.document_node).get_fields().get("class").map(String::as_str) == Some(class) {
+                found.push(Arc::clone(&current));
+            }
+
+            queue.extend(read_lock!(current).children.iter().cloned());
+        }
+
+        found
+    }
+
+    #[test]
+    fn nested_instances_repeat_the_children_of_the_instance() {
+        let (contexts, root) = traverse(COMMENTS);
+
+        let comments = find_by_class(&contexts, &root, "comment");
+        let replies = &find_by_class(&contexts, &root, "replies")[0];
+        let footer = &find_by_class(&contexts, &root, "footer")[0];
+
+        assert_eq!(comments.len(), 2);
+        assert!(is_nested_instance(&comments[0], &comments[1]));
+        assert!(detect_recursion(Arc::clone(&comments[0])).is_some());
+
+        // Same tag and attribute names as a comment, but not a comment
+        assert_eq!(read_lock!(footer).hash, read_lock!(comments[0]).hash);
+        assert!(!is_nested_instance(&comments[0], footer));
+        assert!(!is_nested_instance(&comments[0], replies));
+    }
+
+    #[test]
+    fn ordinary_nesting_is_not_recursion() {
+        let (contexts, root) = traverse(concat!(
+            r#"<html><body><div class="post"><span class="author">alice</span><p>First</p>"#,
+            r#"<div class="footer"><a class="link" href="/reply">Reply</a><em>2 hours ago</em></div>"#,
+            r#"</div></body></html>"#,
+        ));
+
+        let post = &find_by_class(&contexts, &root, "post")[0];
+
+        assert!(detect_recursion(Arc::clone(post)).is_none());
+    }
+
+    #[tokio::test]
+    async fn summaries_out_of_budget_abort_or_degrade() {
+        let meta_context = Arc::new(RwLock::new(MetaContext::new()));
+        write_lock!(meta_context).update_profile(Arc::new(Profile::default_profile(&HashMap::new())));
+
+        let document = Document::from_string(COMMENTS.to_string(), &None).unwrap();
+        let (contexts, graph_root) = traverse_document(document, Arc::clone(&meta_context)).unwrap();
+        write_lock!(meta_context).update_data_structures(contexts, graph_root);
+
+        let provider = Arc::new(VoidProvider);
+        let token_usage = read_lock!(meta_context).token_usage.clone();
+
+        write_lock!(CONFIG).llm.max_tokens = Some(0);
+
+        write_lock!(CONFIG).llm.budget_behavior = BudgetBehavior::Abort;
+        let aborted = get_basis_graph(Arc::clone(&provider), Arc::clone(&meta_context)).await;
+
+        assert!(matches!(aborted.unwrap_err().root_cause(), Errors::BudgetExceeded(_)));
+        assert!(token_usage.enforce_budget().is_err());
+
+        write_lock!(CONFIG).llm.budget_behavior = BudgetBehavior::Degrade;
+        let degraded = get_basis_graph(Arc::clone(&provider), Arc::clone(&meta_context)).await;
+
+        write_lock!(CONFIG).llm.max_tokens = None;
+        write_lock!(CONFIG).llm.budget_behavior = BudgetBehavior::Abort;
+
+        let basis_graph = degraded.unwrap();
+        assert!(basis_graph.needs_review);
+        assert!(basis_graph.name.is_empty());
+        assert!(token_usage.enforce_budget().is_ok());
+        assert_eq!(token_usage.get_total().requests, 0);
+    }
+}
@@ -25,9 +25,11 @@ pub async fn get_schema_transformations<P: Provider>(
     log::trace!("In get_schema_transformations");
 
 
-    let lock = read_lock!(meta_context);
-    let document = lock.document.clone().unwrap();
-    let schema = document.schema.unwrap();
+    let schema = {
+        let lock = read_lock!(meta_context);
+        let document = lock.document.clone().ok_or(Errors::DocumentNotProvided)?;
+        document.schema.ok_or(Errors::DocumentNotProvided)?
+    };
 
 
 
@@ -59,37 +61,60 @@ pub async fn get_schema_transformations<P: Provider>(
                 cloned_provider,
                 cloned_meta_context,
                 schema_node.clone()
-            ).await?;
-
-            results.insert(result.id.clone(), Arc::new(result));
+            ).await;
+
+            match result {
+                Ok(Some(transformation)) => {
+                    results.insert(transformation.id.clone(), Arc::new(transformation));
+                },
+                Ok(None) => {},
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::Normalization, err));
+                }
+            }
         }
 
         Ok(results)
     } else {
-        let semaphore = Arc::new(Semaphore::new(max_concurrency));
+        // The LLM limiter only holds a permit per request, so tasks are
+        // bounded here as well rather than spawned all at once
+        let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
         let mut handles = Vec::new();
 
         for schema_node in schema_nodes {
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
             let cloned_provider = Arc::clone(&provider);
             let cloned_meta_context = Arc::clone(&meta_context);
+            let permit = Arc::clone(&semaphore)
+                .acquire_owned()
+                .await
+                .map_err(|_| Errors::UnexpectedError)?;
 
             let handle = task::spawn(async move {
                 let _permit = permit;
-                let transformation = get_schema_tranformation(
+                get_schema_tranformation(
                     cloned_provider,
                     cloned_meta_context,
                     schema_node.clone()
-                ).await?;
-
-                Ok((transformation.id.clone(), Arc::new(transformation)))
+                ).await
             });
             handles.push(handle);
         }
 
-        let results: Vec<Result<(ID, Arc<SchemaTransformation>), Errors>> = try_join_all(handles).await?;
+        let results: Vec<Result<Option<SchemaTransformation>, Errors>> = try_join_all(handles).await?;
 
-        let hashmap_results: HashMap<ID, Arc<SchemaTransformation>> = results.into_iter().collect::<Result<_, _>>()?;
+        let mut hashmap_results: HashMap<ID, Arc<SchemaTransformation>> = HashMap::new();
+
+        for result in results.into_iter() {
+            match result {
+                Ok(Some(transformation)) => {
+                    hashmap_results.insert(transformation.id.clone(), Arc::new(transformation));
+                },
+                Ok(None) => {},
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::Normalization, err));
+                }
+            }
+        }
 
         Ok(hashmap_results)
     }
@@ -99,17 +124,21 @@ async fn get_schema_tranformation<P: Provider>(
     provider: Arc<P>,
     meta_context: Arc<RwLock<MetaContext>>,
     schema_node: SchemaNode,
-) -> Result<SchemaTransformation, Errors> {
+) -> Result<Option<SchemaTransformation>, Errors> {
     log::trace!("In get_schema_transformation");
 
     let complete_schema_string: Arc<String> = {
         let lock = read_lock!(meta_context);
-        lock.schema_string.clone().unwrap()
+        lock.schema_string.clone().ok_or(Errors::DocumentNotProvided)?
     };
 
     log::debug!("complete_schema_string: {}", complete_schema_string);
 
-    unimplemented!()
+    // Schema transformations are not supported yet, which leaves the node
+    // as it is
+    log::debug!("No schema transformation for schema node {}", schema_node.name);
+
+    Ok(None)
 }
 
 pub async fn get_basis_nodes<P: Provider>(
@@ -118,7 +147,7 @@ pub async fn get_basis_nodes<P: Provider>(
 ) -> Result<HashMap<ID, Arc<BasisNode>>, Errors> {
     log::trace!("In get_basis_nodes");
 
-    let context_groups = ContextGroup::from_meta_context(Arc::clone(&meta_context));
+    let context_groups = ContextGroup::from_meta_context(Arc::clone(&meta_context))?;
 
     let max_concurrency = read_lock!(CONFIG).llm.max_concurrency;
 
@@ -128,32 +157,46 @@ pub async fn get_basis_nodes<P: Provider>(
         for context_group in context_groups {
             let cloned_provider = Arc::clone(&provider);
             let cloned_meta_context = Arc::clone(&meta_context);
+            let lineage = context_group.lineage.clone();
             let result = get_basis_node(
                 cloned_provider,
                 cloned_meta_context,
                 context_group.clone()
-            ).await?;
-
-            results.insert(result.id.clone(), Arc::new(result));
+            ).await.lineage(Stage::BasisNode, &lineage);
+
+            match result {
+                Ok(basis_node) => {
+                    results.insert(basis_node.id.clone(), Arc::new(basis_node));
+                },
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::BasisNode, err));
+                }
+            }
         }
 
         Ok(results)
     } else {
-        let semaphore = Arc::new(Semaphore::new(max_concurrency));
+        // The LLM limiter only holds a permit per request, so tasks are
+        // bounded here as well rather than spawned all at once
+        let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
         let mut handles = Vec::new();
 
         for context_group in context_groups {
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
             let cloned_provider = Arc::clone(&provider);
             let cloned_meta_context = Arc::clone(&meta_context);
+            let permit = Arc::clone(&semaphore)
+                .acquire_owned()
+                .await
+                .map_err(|_| Errors::UnexpectedError)?;
 
             let handle = task::spawn(async move {
                 let _permit = permit;
+                let lineage = context_group.lineage.clone();
                 let basis_node = get_basis_node(
                     cloned_provider,
                     cloned_meta_context,
                     context_group.clone()
-                ).await?;
+                ).await.lineage(Stage::BasisNode, &lineage)?;
 
                 Ok((basis_node.id.clone(), Arc::new(basis_node)))
             });
@@ -162,7 +205,18 @@ pub async fn get_basis_nodes<P: Provider>(
 
         let results: Vec<Result<(ID, Arc<BasisNode>), Errors>> = try_join_all(handles).await?;
 
-        let hashmap_results: HashMap<ID, Arc<BasisNode>> = results.into_iter().collect::<Result<_, _>>()?;
+        let mut hashmap_results: HashMap<ID, Arc<BasisNode>> = HashMap::new();
+
+        for result in results.into_iter() {
+            match result {
+                Ok((id, basis_node)) => {
+                    hashmap_results.insert(id, basis_node);
+                },
+                Err(err) => {
+                    read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::BasisNode, err));
+                }
+            }
+        }
 
         Ok(hashmap_results)
     }
@@ -170,40 +224,50 @@ pub async fn get_basis_nodes<P: Provider>(
 
 async fn get_basis_node<P: Provider>(
     provider: Arc<P>,
-    _meta_context: Arc<RwLock<MetaContext>>,
+    meta_context: Arc<RwLock<MetaContext>>,
     context_group: ContextGroup,
 ) -> Result<BasisNode, Errors> {
     log::trace!("In get_basis_node");
 
     let lineage = &context_group.lineage.clone();
-    let data_node = &context_group.contexts.first().unwrap().data_node.clone();
+    let data_node = &context_group.contexts.first().ok_or(Errors::ContextsNotProvided)?.data_node.clone();
     let hash = data_node.hash.clone();
     let description = data_node.description.clone();
 
-    if let Some(basis_node) = provider.get_basis_node_by_lineage(&lineage).await? {
+    if let Some(basis_node) = provider.get_basis_node_by_lineage(lineage).await.stage(Stage::Provider)? {
         log::info!("Provider has supplied basis node");
 
         return Ok(basis_node);
     };
 
+    let token_usage = read_lock!(meta_context).token_usage.clone();
     let field_transformations: Vec<FieldTransformation> = LLM::get_field_transformations(
+        token_usage,
         context_group.clone()
     ).await?;
 
     log::info!("Obtained field transformation");
 
+    let (eliminations, field_transformations): (Vec<FieldTransformation>, Vec<FieldTransformation>) = field_transformations
+        .into_iter()
+        .partition(|transformation| transformation.meta.eliminated);
+
+    let profile_id = read_lock!(meta_context).profile.as_ref().map(|profile| profile.id.clone());
+
     let basis_node = BasisNode {
         id: ID::new(),
         hash,
         description,
         lineage: lineage.clone(),
         transformations: field_transformations,
+        eliminations,
+        profile_id,
     };
 
     provider.save_basis_node(
-        &lineage,
+        lineage,
         basis_node.clone(),
-    ).await?;
+    ).await.stage(Stage::Provider)?;
 
     Ok(basis_node)
 }
@@ -9,6 +9,7 @@ use crate::traverse::{traverse_meta_context};
 use crate::meta_context::MetaContext;
 use crate::schema::Schema;
 use crate::node_analysis::{get_schema_transformations};
+use crate::profile_schema::{unify_profile_schema};
 
 #[allow(dead_code)]
 pub async fn normalize<P: Provider>(
@@ -22,7 +23,7 @@ pub async fn normalize<P: Provider>(
     let document = traverse_meta_context(
         meta_context.clone(),
         &None
-    )?;
+    ).stage(Stage::Traversal)?;
 
     println!("document: {}", document.clone().to_string());
 
@@ -31,6 +32,16 @@ pub async fn normalize<P: Provider>(
         lock.update_document(document);
     }
 
+    log::info!("Unifying profile schema");
+    let violations = unify_profile_schema(
+        Arc::clone(&provider),
+        Arc::clone(&meta_context)
+    ).await?;
+
+    if !violations.is_empty() {
+        log::warn!("Document deviates from profile schema in {} places", violations.len());
+    }
+
     log::info!("Getting schema transformations");
     let schema_transformations = get_schema_transformations(
         Arc::clone(&provider),
@@ -42,8 +53,6 @@ pub async fn normalize<P: Provider>(
         lock.update_schema_transformations(schema_transformations);
     }
 
-    delay();
-
     Ok(meta_context)
 }
 
@@ -229,7 +238,11 @@ pub async fn normalize_url_to_meta_context<P: Provider>(
 
     let text = fetch_url_as_text(url).await?;
 
-    normalize_text_to_meta_context(Arc::clone(&provider), text, _options).await
+    // Documents fetched from a URL originate from it unless told otherwise
+    let mut options = _options.clone().unwrap_or_default();
+    options.origin = options.origin.or_else(|| Some(url.to_string()));
+
+    normalize_text_to_meta_context(Arc::clone(&provider), text, &Some(options)).await
 }
 
 #[allow(dead_code)]
@@ -9,6 +9,7 @@ use crate::traverse::{
     traverse_meta_context
 };
 use crate::meta_context::MetaContext;
+use crate::drift::migrate_basis_nodes;
 use crate::node_analysis::{get_basis_nodes};
 use crate::network_analysis::{get_basis_networks, get_basis_graph};
 
@@ -23,55 +24,77 @@ pub async fn organize<P: Provider>(
     let meta_context = Arc::new(RwLock::new(MetaContext::new()));
 
     log::info!("Performing document analysis");
-    let profile = document.perform_analysis(Arc::clone(&provider)).await?;
+    let token_usage = read_lock!(meta_context).token_usage.clone();
+    let (profile, profile_drift) = document.perform_analysis(
+        Arc::clone(&provider),
+        token_usage,
+    ).await.stage(Stage::Analysis)?;
     let profile = Arc::new(profile);
 
     {
         let mut lock = write_lock!(meta_context);
         lock.update_profile(profile);
+
+        if let Some(profile_drift) = profile_drift {
+            lock.update_profile_drift(profile_drift);
+        }
     }
 
     log::info!("Traversing document");
-    let (contexts, graph_root) = traverse_document(document, meta_context.clone())?;
+    let (contexts, graph_root) = traverse_document(document, meta_context.clone()).stage(Stage::Traversal)?;
 
     {
         let mut lock = write_lock!(meta_context);
         lock.update_data_structures(contexts, graph_root);
     }
 
+    log::info!("Checking for basis nodes to migrate from a drifted profile");
+    migrate_basis_nodes(
+        Arc::clone(&provider),
+        meta_context.clone(),
+    ).await.stage(Stage::Analysis)?;
+
     log::info!("Getting basis graph");
     let basis_graph = get_basis_graph(
         Arc::clone(&provider),
         meta_context.clone(),
-    ).await?;
+    ).await.stage(Stage::BasisGraph)?;
 
     {
         let mut lock = write_lock!(meta_context);
         lock.update_basis_graph(basis_graph);
     }
 
+    read_lock!(meta_context).token_usage.enforce_budget().stage(Stage::BasisGraph)?;
+
     log::info!("Getting basis nodes");
     let basis_nodes = get_basis_nodes(
         Arc::clone(&provider),
         meta_context.clone(),
-    ).await?;
+    ).await.stage(Stage::BasisNode)?;
 
     {
         let mut lock = write_lock!(meta_context);
         lock.update_basis_nodes(basis_nodes);
     }
 
+    read_lock!(meta_context).token_usage.enforce_budget().stage(Stage::BasisNode)?;
+
     log::info!("Generating basis networks");
     let basis_networks = get_basis_networks(
         Arc::clone(&provider),
         meta_context.clone(),
-    ).await?;
+    ).await.stage(Stage::BasisNetwork)?;
 
     {
         let mut lock = write_lock!(meta_context);
         lock.update_basis_networks(basis_networks);
     }
 
+    read_lock!(meta_context).token_usage.enforce_budget().stage(Stage::BasisNetwork)?;
+
+    log::info!("LLM usage:\n{}", read_lock!(meta_context).token_usage.get_report());
+
     Ok(meta_context)
 }
 
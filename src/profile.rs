@@ -1,13 +1,215 @@
 use serde::{Serialize, Deserialize};
-use std::collections::{HashSet};
+use serde_json::json;
+use xmltree::{Element, XMLNode};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
 
 use crate::prelude::*;
+use crate::config::{CONFIG, SimilarityMeasure};
+use crate::document::Document;
+use crate::llm::LLM;
+use crate::minhash::{MinHashSignature, LshIndex};
+use crate::token_usage::TokenUsage;
 use crate::transformation::{
     XMLElementTransformation,
     HashTransformation,
-    Runtime
+    Runtime,
+    to_js_literal,
 };
 
+// Blacklisting either of these would remove the entire document
+const PROTECTED_ELEMENTS: [&str; 2] = ["html", "body"];
+
+// Text within these elements is code or markup rather than content, and does
+// not keep them from being blacklisted
+const NON_CONTENT_ELEMENTS: [&str; 5] = ["script", "style", "noscript", "template", "svg"];
+
+/// Whether a profile was written by hand or reviewed, proposed by the LLM and
+/// awaiting review, or the placeholder used when no proposal was available
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum ProfileStatus {
+    #[default]
+    Placeholder,
+    Proposed,
+    Reviewed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileProposal {
+    pub description: String,
+    pub meaningful_fields: Vec<String>,
+    pub blacklisted_elements: Vec<String>,
+    pub blacklisted_attributes: Vec<String>,
+    pub justification: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileMatch {
+    pub profile: Profile,
+    pub score: f64,
+    pub accepted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileMatchExplanation {
+    pub shared: Vec<String>,
+    pub document_only: Vec<String>,
+    pub profile_only: usize,
+}
+
+/// In-memory index of profiles for providers, which only compares a
+/// document against the profiles sharing an LSH bucket with it, and against
+/// all profiles when none of those is similar enough
+pub struct ProfileIndex {
+    profiles: HashMap<ID, Profile>,
+    lsh: LshIndex,
+    frequencies: FeatureFrequencies,
+}
+
+impl Default for ProfileIndex {
+    fn default() -> Self {
+        ProfileIndex::new()
+    }
+}
+
+impl ProfileIndex {
+    pub fn new() -> Self {
+        let bands = read_lock!(CONFIG).matching.lsh_bands;
+
+        ProfileIndex {
+            profiles: HashMap::new(),
+            lsh: LshIndex::new(bands),
+            frequencies: FeatureFrequencies::default(),
+        }
+    }
+
+    pub fn from_profiles(profiles: Vec<Profile>) -> Self {
+        log::trace!("In from_profiles");
+
+        let mut index = ProfileIndex::new();
+
+        for profile in profiles.into_iter() {
+            index.insert(profile);
+        }
+
+        index
+    }
+
+    pub fn insert(&mut self, profile: Profile) {
+        if let Some(previous) = self.profiles.get(&profile.id) {
+            self.frequencies.remove(previous);
+            self.lsh.remove(&profile.id);
+        }
+
+        self.frequencies.insert(&profile);
+        self.lsh.insert(&profile.id, &profile.get_signature());
+        self.profiles.insert(profile.id.clone(), profile);
+    }
+
+    pub fn get_similar_profile(&self, features: &HashMap<Hash, f64>) -> Option<Profile> {
+        log::trace!("In get_similar_profile");
+
+        self.get_profile_candidates(features, 1)
+            .into_iter()
+            .find(|candidate| candidate.accepted)
+            .map(|candidate| candidate.profile)
+    }
+
+    pub fn get_profile_candidates(&self, features: &HashMap<Hash, f64>, count: usize) -> Vec<ProfileMatch> {
+        log::trace!("In get_profile_candidates");
+
+        let lsh_candidates = self.get_lsh_candidates(features);
+        let candidates = Profile::get_profile_candidates(&lsh_candidates, features, &self.frequencies, count);
+
+        if candidates.iter().any(|candidate| candidate.accepted) || lsh_candidates.len() == self.profiles.len() {
+            return candidates;
+        }
+
+        // Buckets are keyed by unweighted signatures, so a profile can share
+        // few features with a document yet pass on the weight of those it does
+        log::debug!("No LSH candidate accepted, comparing against all profiles");
+
+        let profiles: Vec<Profile> = self.profiles.values().cloned().collect();
+
+        Profile::get_profile_candidates(&profiles, features, &self.frequencies, count)
+    }
+
+    fn get_lsh_candidates(&self, features: &HashMap<Hash, f64>) -> Vec<Profile> {
+        let signature = Profile::get_features_signature(features);
+
+        let candidates: Vec<Profile> = self.lsh
+            .query(&signature)
+            .iter()
+            .filter_map(|id| self.profiles.get(id).cloned())
+            .collect();
+
+        log::debug!("LSH candidates: {} of {} profiles", candidates.len(), self.profiles.len());
+
+        candidates
+    }
+}
+
+/// Number of known profiles containing each feature, from which features
+/// are weighted by inverse document frequency. Features shared by the
+/// profiles of many sites, such as boilerplate in the document head, say
+/// little about which page type a document is.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFrequencies {
+    profile_count: usize,
+    frequencies: HashMap<Hash, usize>,
+}
+
+impl FeatureFrequencies {
+    pub fn insert(&mut self, profile: &Profile) {
+        self.profile_count += 1;
+
+        for feature in profile.features.iter() {
+            *self.frequencies.entry(feature.clone()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn remove(&mut self, profile: &Profile) {
+        self.profile_count = self.profile_count.saturating_sub(1);
+
+        for feature in profile.features.iter() {
+            if let Some(frequency) = self.frequencies.get_mut(feature) {
+                *frequency = frequency.saturating_sub(1);
+            }
+        }
+
+        self.frequencies.retain(|_, frequency| *frequency > 0);
+    }
+
+    /// Smoothed inverse document frequency, which is 1 for a feature found in
+    /// every profile and grows as the feature becomes rarer. Features no
+    /// profile contains cannot tell profiles apart, and are weighted as if
+    /// they were common so that new site chrome does not dominate a match.
+    pub fn get_idf(&self, feature: &Hash) -> f64 {
+        match self.frequencies.get(feature) {
+            Some(frequency) => ((self.profile_count as f64 + 1.0) / (*frequency as f64 + 1.0)).ln() + 1.0,
+            None => 1.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct AttributeInventory {
+    count: usize,
+    examples: Vec<String>,
+}
+
+/// Elements and attributes occurring in a document, with example values
+#[derive(Default)]
+struct DocumentInventory {
+    elements: BTreeMap<String, usize>,
+    attributes: BTreeMap<String, AttributeInventory>,
+    /// The fields found within any occurrence of each element, including
+    /// "text" for content text
+    element_fields: BTreeMap<String, BTreeSet<String>>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Profile {
     pub id: ID,
@@ -16,33 +218,198 @@ pub struct Profile {
     pub xml_element_transformation: Option<XMLElementTransformation>,
     pub hash_transformation: Option<HashTransformation>,
     pub meaningful_fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub status: ProfileStatus,
+    #[serde(default)]
+    pub justification: Option<String>,
+    #[serde(default)]
+    pub signature: Option<MinHashSignature>,
+    #[serde(default = "get_initial_version")]
+    pub version: usize,
+    /// Site the profile was learned from, usually the host of the document
+    /// origin, grouping the versions of a profile
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub previous_version: Option<ID>,
+    /// Structural weight of each feature, see `Document::get_feature_paths`.
+    /// Features without a weight count as 1.
+    #[serde(default)]
+    pub feature_weights: HashMap<Hash, f64>,
 }
 
 impl Profile {
+    /// Returns the most similar profile whose similarity exceeds the
+    /// configured threshold
     pub fn get_similar_profile(
-        profiles: &Vec<Profile>,
-        features: &HashSet<Hash>
+        profiles: &[Profile],
+        features: &HashMap<Hash, f64>,
+        frequencies: &FeatureFrequencies,
     ) -> Option<Profile> {
-        profiles.iter()
-            .find(|profile| {
-                let similarity = jaccard_similarity(features, &profile.features);
+        log::trace!("In get_similar_profile");
+
+        Profile::get_profile_candidates(profiles, features, frequencies, 1)
+            .into_iter()
+            .find(|candidate| candidate.accepted)
+            .map(|candidate| candidate.profile)
+    }
+
+    /// Ranks profiles by similarity to a feature set, returning at most
+    /// `count` candidates with the best match first
+    pub fn get_profile_candidates(
+        profiles: &[Profile],
+        features: &HashMap<Hash, f64>,
+        frequencies: &FeatureFrequencies,
+        count: usize,
+    ) -> Vec<ProfileMatch> {
+        log::trace!("In get_profile_candidates");
+
+        let threshold = read_lock!(CONFIG).matching.similarity_threshold;
+
+        let mut candidates: Vec<ProfileMatch> = profiles.iter()
+            .map(|profile| {
+                let score = profile.get_similarity(features, frequencies);
 
-                log::debug!("similarity: {}", similarity);
+                log::debug!("similarity of profile {}: {}", profile.id.to_string(), score);
 
-                similarity > 0.8
+                ProfileMatch {
+                    profile: profile.clone(),
+                    score,
+                    accepted: score > threshold,
+                }
             })
-            .map(|profile| profile.clone())
+            .collect();
+
+        candidates.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.profile.id.to_string().cmp(&b.profile.id.to_string()))
+        });
+        candidates.truncate(count);
+
+        candidates
+    }
+
+    /// Similarity between the features of a document and this profile, using
+    /// the configured measure
+    pub fn get_similarity(
+        &self,
+        features: &HashMap<Hash, f64>,
+        frequencies: &FeatureFrequencies,
+    ) -> f64 {
+        let similarity_measure = read_lock!(CONFIG).matching.similarity_measure.clone();
+
+        match similarity_measure {
+            SimilarityMeasure::Jaccard => {
+                let features: HashSet<Hash> = features.keys().cloned().collect();
+
+                jaccard_similarity(&features, &self.features)
+            },
+            SimilarityMeasure::Weighted if self.feature_weights.is_empty() => {
+                // Profiles saved before features were weighted are compared
+                // on inverse document frequency alone
+                let features: HashMap<Hash, f64> = features.keys().map(|feature| (feature.clone(), 1.0)).collect();
+                let profile_features: HashMap<Hash, f64> = self.features.iter().map(|feature| (feature.clone(), 1.0)).collect();
+
+                weighted_jaccard_similarity(&features, &profile_features, frequencies)
+            },
+            SimilarityMeasure::Weighted => {
+                let profile_features: HashMap<Hash, f64> = self.features
+                    .iter()
+                    .map(|feature| (feature.clone(), self.get_feature_weight(feature)))
+                    .collect();
+
+                weighted_jaccard_similarity(features, &profile_features, frequencies)
+            },
+        }
+    }
+
+    pub fn get_feature_weight(&self, feature: &Hash) -> f64 {
+        self.feature_weights.get(feature).copied().unwrap_or(1.0)
+    }
+
+    /// Returns the stored MinHash signature, or computes one if the profile
+    /// predates signatures or was signed differently
+    pub fn get_signature(&self) -> MinHashSignature {
+        let permutations = read_lock!(CONFIG).matching.minhash_permutations;
+
+        match &self.signature {
+            Some(signature) if signature.is_current(permutations) => signature.clone(),
+            _ => MinHashSignature::from_features(self.features.iter(), permutations),
+        }
+    }
+
+    pub fn get_features_signature(features: &HashMap<Hash, f64>) -> MinHashSignature {
+        let permutations = read_lock!(CONFIG).matching.minhash_permutations;
+
+        MinHashSignature::from_features(features.keys(), permutations)
+    }
+
+    /// Breaks the similarity between a document and this profile down into
+    /// the features they share and the features unique to each. Profiles only
+    /// store hashed features, so those unique to the profile are counted.
+    pub fn explain_match(&self, feature_paths: &HashMap<String, f64>) -> ProfileMatchExplanation {
+        log::trace!("In explain_match");
+
+        let mut shared = Vec::new();
+        let mut document_only = Vec::new();
+        let mut matched_features: HashSet<Hash> = HashSet::new();
+
+        for path in feature_paths.keys() {
+            let hash = Document::get_feature_hash(path);
+
+            if self.features.contains(&hash) {
+                shared.push(path.clone());
+                matched_features.insert(hash);
+            } else {
+                document_only.push(path.clone());
+            }
+        }
+
+        shared.sort();
+        document_only.sort();
+
+        ProfileMatchExplanation {
+            shared,
+            document_only,
+            profile_only: self.features.difference(&matched_features).count(),
+        }
     }
 
+    /// Proposes a profile for a document through the LLM. Proposals are saved
+    /// with a proposed status for review, and the default profile is used
+    /// whenever a proposal cannot be obtained.
     pub async fn create_profile(
-        features: &HashSet<Hash>
+        document: &Document,
+        features: &HashMap<Hash, f64>,
+        token_usage: Arc<TokenUsage>,
     ) -> Result<Profile, Errors> {
         log::trace!("In create_profile");
 
-        let profile = Profile {
+        let generate_profiles = read_lock!(CONFIG).llm.generate_profiles;
+
+        if !generate_profiles {
+            return Ok(Profile::default_profile(features));
+        }
+
+        match Profile::propose_profile(document, features, token_usage).await {
+            Ok(profile) => Ok(profile),
+            Err(err) => {
+                log::warn!("Could not propose a profile, using the default profile: {}", err);
+
+                Ok(Profile::default_profile(features))
+            }
+        }
+    }
+
+    pub fn default_profile(features: &HashMap<Hash, f64>) -> Profile {
+        log::trace!("In default_profile");
+
+        Profile {
             id: ID::new(),
             description: "Placeholder description".to_string(),
-            features: features.clone(),
+            features: features.keys().cloned().collect(),
             xml_element_transformation: Some(XMLElementTransformation {
                 id: ID::new(),
                 description: "XML element transformation applied during document preprocessing that blacklists certain elements or attributes to reduce document size and improve interpretation.".to_string(),
@@ -59,20 +426,282 @@ attributes = Object.keys(attributes)
       return acc;
   }, {});"#.to_string(),
             }),
-            hash_transformation: Some(HashTransformation {
+            hash_transformation: Some(Profile::default_hash_transformation()),
+            meaningful_fields: Some(vec!["text".to_string(), "href".to_string(), "title".to_string()]),
+            status: ProfileStatus::Placeholder,
+            justification: None,
+            signature: Some(Profile::get_features_signature(features)),
+            feature_weights: features.clone(),
+            version: get_initial_version(),
+            group: None,
+            previous_version: None,
+        }
+    }
+
+    async fn propose_profile(
+        document: &Document,
+        features: &HashMap<Hash, f64>,
+        token_usage: Arc<TokenUsage>,
+    ) -> Result<Profile, Errors> {
+        log::trace!("In propose_profile");
+
+        let (inventory, sample) = get_proposal_inputs(document)?;
+
+        let proposal = LLM::propose_profile(token_usage, inventory.to_string(), sample).await?;
+        let proposal = inventory.validate(proposal)?;
+
+        log::info!("Proposed profile: {:?}", proposal);
+
+        Ok(Profile {
+            id: ID::new(),
+            description: proposal.description.clone(),
+            features: features.keys().cloned().collect(),
+            xml_element_transformation: Some(XMLElementTransformation {
                 id: ID::new(),
-                description: "Determines the set of input strings from a node to use in identity hash calculation".to_string(),
+                description: "XML element transformation proposed for this profile that blacklists elements and attributes without meaningful content.".to_string(),
                 runtime: Runtime::QuickJS,
-                infix: r#"
-let hasherItems = Object.keys(fields).sort()"#.to_string(),
+                infix: get_blacklist_script(&proposal.blacklisted_elements, &proposal.blacklisted_attributes)?,
             }),
-            meaningful_fields: Some(vec!["text".to_string(), "href".to_string(), "title".to_string()]),
-        };
+            hash_transformation: Some(Profile::default_hash_transformation()),
+            meaningful_fields: Some(proposal.meaningful_fields),
+            status: ProfileStatus::Proposed,
+            justification: Some(proposal.justification),
+            signature: Some(Profile::get_features_signature(features)),
+            feature_weights: features.clone(),
+            version: get_initial_version(),
+            group: None,
+            previous_version: None,
+        })
+    }
+
+    /// Records the size of the proposal request that creating a profile for
+    /// the document would make, without sending it
+    pub fn estimate_proposal(document: &Document, token_usage: &TokenUsage) -> Result<(), Errors> {
+        log::trace!("In estimate_proposal");
+
+        let (inventory, sample) = get_proposal_inputs(document)?;
+
+        LLM::estimate_profile(token_usage, &inventory.to_string(), &sample);
+
+        Ok(())
+    }
 
-        Ok(profile)
+    fn default_hash_transformation() -> HashTransformation {
+        HashTransformation {
+            id: ID::new(),
+            description: "Determines the set of input strings from a node to use in identity hash calculation".to_string(),
+            runtime: Runtime::QuickJS,
+            infix: r#"
+let hasherItems = Object.keys(fields).sort()"#.to_string(),
+        }
     }
 }
 
+impl DocumentInventory {
+    /// Returns the fields found within the element
+    fn add_element(&mut self, element: &Element) -> BTreeSet<String> {
+        *self.elements.entry(element.name.clone()).or_insert(0) += 1;
+
+        let is_content = !NON_CONTENT_ELEMENTS.contains(&element.name.as_str());
+        let mut fields: BTreeSet<String> = element.attributes.keys().cloned().collect();
+
+        for (name, value) in element.attributes.iter() {
+            let attribute = self.attributes.entry(name.clone()).or_default();
+            attribute.count += 1;
+
+            let value = truncate_chars(value.trim(), 60);
+            if !value.is_empty() && attribute.examples.len() < 3 && !attribute.examples.contains(&value) {
+                attribute.examples.push(value);
+            }
+        }
+
+        for child in element.children.iter() {
+            match child {
+                XMLNode::Element(child) => {
+                    fields.extend(self.add_element(child));
+                },
+                XMLNode::Text(text) | XMLNode::CData(text) if is_content && !text.trim().is_empty() => {
+                    fields.insert("text".to_string());
+                },
+                _ => {}
+            }
+        }
+
+        if !is_content {
+            fields.remove("text");
+        }
+
+        self.element_fields
+            .entry(element.name.clone())
+            .or_default()
+            .extend(fields.iter().cloned());
+
+        fields
+    }
+
+    /// Restricts a proposal to names that occur in the document. An element
+    /// is only blacklisted when no occurrence of it holds a meaningful field,
+    /// since blacklisting an element removes everything within it.
+    fn validate(&self, proposal: ProfileProposal) -> Result<ProfileProposal, Errors> {
+        log::trace!("In validate");
+
+        let mut meaningful_fields: Vec<String> = proposal.meaningful_fields
+            .into_iter()
+            .map(|field| field.trim().to_string())
+            .filter(|field| field == "text" || self.attributes.contains_key(field))
+            .collect();
+        meaningful_fields.sort();
+        meaningful_fields.dedup();
+
+        if meaningful_fields.is_empty() {
+            return Err(Errors::LLMError("Proposed profile has no meaningful fields".to_string()));
+        }
+
+        let mut blacklisted_elements: Vec<String> = proposal.blacklisted_elements
+            .into_iter()
+            .map(|element| element.trim().to_string())
+            .filter(|element| self.elements.contains_key(element))
+            .filter(|element| !PROTECTED_ELEMENTS.contains(&element.as_str()))
+            .filter(|element| {
+                let meaningful_field = self.element_fields
+                    .get(element)
+                    .and_then(|fields| fields.iter().find(|field| meaningful_fields.contains(field)));
+
+                if let Some(field) = meaningful_field {
+                    log::warn!("Not blacklisting element {}, which holds meaningful field {}", element, field);
+                }
+
+                meaningful_field.is_none()
+            })
+            .collect();
+        blacklisted_elements.sort();
+        blacklisted_elements.dedup();
+
+        let mut blacklisted_attributes: Vec<String> = proposal.blacklisted_attributes
+            .into_iter()
+            .map(|attribute| attribute.trim().to_string())
+            .filter(|attribute| self.attributes.contains_key(attribute))
+            .filter(|attribute| !meaningful_fields.contains(attribute))
+            .collect();
+        blacklisted_attributes.sort();
+        blacklisted_attributes.dedup();
+
+        Ok(ProfileProposal {
+            description: proposal.description,
+            meaningful_fields,
+            blacklisted_elements,
+            blacklisted_attributes,
+            justification: proposal.justification,
+        })
+    }
+}
+
+impl fmt::Display for DocumentInventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Elements:")?;
+        for (name, count) in self.elements.iter() {
+            writeln!(f, "  {} ({})", name, count)?;
+        }
+
+        writeln!(f, "Attributes:")?;
+        for (name, attribute) in self.attributes.iter() {
+            let examples: Vec<String> = attribute.examples
+                .iter()
+                .map(|example| format!("{:?}", example))
+                .collect();
+
+            writeln!(f, "  {} ({}): {}", name, attribute.count, examples.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Generates the XML element transformation for a set of blacklists
+fn get_blacklist_script(elements: &[String], attributes: &[String]) -> Result<String, Errors> {
+    let elements = to_js_literal(&json!(elements))?;
+    let attributes = to_js_literal(&json!(attributes))?;
+
+    Ok(format!(r#"
+const BLACKLISTED_ATTRIBUTES = new Set({});
+const BLACKLISTED_ELEMENTS = new Set({});
+if (BLACKLISTED_ELEMENTS.has(element)) element = null;
+attributes = Object.keys(attributes)
+  .filter(item => !BLACKLISTED_ATTRIBUTES.has(item))
+  .reduce((acc, key) => {{
+      acc[key] = attributes[key];
+      return acc;
+  }}, {{}});"#, attributes, elements))
+}
+
+fn get_proposal_inputs(document: &Document) -> Result<(DocumentInventory, String), Errors> {
+    let root = document.get_document_node()?;
+    let root = match root.get_xml_node() {
+        XMLNode::Element(element) => element.clone(),
+        _ => return Err(Errors::UnexpectedDocumentType),
+    };
+
+    let mut inventory = DocumentInventory::default();
+    inventory.add_element(&root);
+
+    let sample_length = read_lock!(CONFIG).llm.profile_sample_length;
+    let sample = get_sample(&root, sample_length)?;
+
+    Ok((inventory, sample))
+}
+
+fn get_sample(root: &Element, length: usize) -> Result<String, Errors> {
+    let mut buffer = Vec::new();
+
+    root.write(&mut buffer).map_err(|err| {
+        log::error!("Could not write document sample: {}", err);
+        Errors::XmlParseError
+    })?;
+
+    let sample = String::from_utf8_lossy(&buffer);
+
+    Ok(truncate_chars(&sample, length))
+}
+
+fn truncate_chars(value: &str, length: usize) -> String {
+    match value.char_indices().nth(length) {
+        Some((index, _)) => format!("{}...", &value[..index]),
+        None => value.to_string(),
+    }
+}
+
+fn get_initial_version() -> usize {
+    1
+}
+
+/// Weighted Jaccard similarity, with the weight of each feature scaled by its
+/// inverse document frequency
+fn weighted_jaccard_similarity(
+    features_a: &HashMap<Hash, f64>,
+    features_b: &HashMap<Hash, f64>,
+    frequencies: &FeatureFrequencies,
+) -> f64 {
+    let mut minimums = 0.0;
+    let mut maximums = 0.0;
+
+    let union: HashSet<&Hash> = features_a.keys().chain(features_b.keys()).collect();
+
+    for feature in union.into_iter() {
+        let idf = frequencies.get_idf(feature);
+        let weight_a = features_a.get(feature).copied().unwrap_or(0.0) * idf;
+        let weight_b = features_b.get(feature).copied().unwrap_or(0.0) * idf;
+
+        minimums += weight_a.min(weight_b);
+        maximums += weight_a.max(weight_b);
+    }
+
+    if maximums == 0.0 {
+        return 1.0;
+    }
+
+    minimums / maximums
+}
+
 fn jaccard_similarity(set_a: &HashSet<Hash>, set_b: &HashSet<Hash>) -> f64 {
     let intersection: HashSet<_> = set_a.intersection(set_b).collect();
     let union: HashSet<_> = set_a.union(set_b).collect();
@@ -83,3 +712,69 @@ fn jaccard_similarity(set_a: &HashSet<Hash>, set_b: &HashSet<Hash>) -> f64 {
 
     intersection.len() as f64 / union.len() as f64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(weights: &[(&str, f64)]) -> HashMap<Hash, f64> {
+        weights.iter().map(|(feature, weight)| (Hash::from_str(feature), *weight)).collect()
+    }
+
+    #[test]
+    fn weighted_jaccard_similarity_compares_weights() {
+        let frequencies = FeatureFrequencies::default();
+        let a = features(&[("x", 2.0), ("y", 1.0)]);
+        let b = features(&[("x", 1.0), ("y", 1.0)]);
+        let c = features(&[("z", 1.0)]);
+
+        assert_eq!(weighted_jaccard_similarity(&a, &a, &frequencies), 1.0);
+        assert!((weighted_jaccard_similarity(&a, &b, &frequencies) - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(weighted_jaccard_similarity(&a, &c, &frequencies), 0.0);
+        assert_eq!(weighted_jaccard_similarity(&HashMap::new(), &HashMap::new(), &frequencies), 1.0);
+    }
+
+    #[test]
+    fn weighted_jaccard_similarity_favours_rare_features() {
+        let mut frequencies = FeatureFrequencies::default();
+        frequencies.insert(&Profile::default_profile(&features(&[("common", 1.0), ("rare", 1.0)])));
+        frequencies.insert(&Profile::default_profile(&features(&[("common", 1.0)])));
+
+        let document = features(&[("common", 1.0), ("rare", 1.0)]);
+        let without_rare = features(&[("common", 1.0)]);
+        let without_common = features(&[("rare", 1.0)]);
+
+        let rare_idf = (3.0f64 / 2.0).ln() + 1.0;
+
+        assert_eq!(frequencies.get_idf(&Hash::from_str("common")), 1.0);
+        assert!((weighted_jaccard_similarity(&document, &without_rare, &frequencies) - 1.0 / (1.0 + rare_idf)).abs() < 1e-9);
+        assert!(
+            weighted_jaccard_similarity(&document, &without_common, &frequencies)
+                > weighted_jaccard_similarity(&document, &without_rare, &frequencies)
+        );
+    }
+
+    #[test]
+    fn profiles_missed_by_lsh_are_found_by_weight() {
+        let shared = [("main", 9.0)];
+        let profile_only: Vec<(String, f64)> = (0..19).map(|i| (format!("profile {}", i), 0.05)).collect();
+        let document_only: Vec<(String, f64)> = (0..19).map(|i| (format!("document {}", i), 0.05)).collect();
+
+        let with = |others: &[(String, f64)]| {
+            let mut weights: Vec<(&str, f64)> = shared.to_vec();
+            weights.extend(others.iter().map(|(feature, weight)| (feature.as_str(), *weight)));
+            features(&weights)
+        };
+
+        let profile = Profile::default_profile(&with(&profile_only));
+        let document = with(&document_only);
+        let index = ProfileIndex::from_profiles(vec![profile.clone()]);
+
+        let threshold = read_lock!(CONFIG).matching.similarity_threshold;
+        let similarity = profile.get_similarity(&document, &index.frequencies);
+
+        assert!(similarity > threshold && similarity < threshold + 0.05, "similarity {}", similarity);
+        assert!(index.get_lsh_candidates(&document).is_empty());
+        assert_eq!(index.get_similar_profile(&document).map(|profile| profile.id), Some(profile.id));
+    }
+}
@@ -0,0 +1,364 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+
+use crate::prelude::*;
+use crate::document::Document;
+use crate::provider::Provider;
+use crate::schema_node::SchemaNode;
+
+/// Schema merged across every run of a profile. Properties that appeared in
+/// every run of their parent are required; the rest are optional.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileSchema {
+    pub id: ID,
+    pub profile_id: ID,
+    pub runs: usize,
+    pub properties: HashMap<String, ProfileSchemaProperty>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileSchemaProperty {
+    pub name: String,
+    pub description: String,
+    pub data_type: String,
+    pub occurrences: usize,
+    pub required: bool,
+    pub properties: HashMap<String, ProfileSchemaProperty>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SchemaViolation {
+    UnknownRoot(String),
+    MissingProperty(String),
+    UnexpectedProperty(String),
+    TypeMismatch {
+        path: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl ProfileSchema {
+    pub fn new(profile_id: &ID) -> Self {
+        ProfileSchema {
+            id: ID::new(),
+            profile_id: profile_id.clone(),
+            runs: 0,
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn merge(&mut self, schema: &HashMap<String, SchemaNode>) {
+        log::trace!("In merge");
+
+        self.runs += 1;
+
+        merge_properties(&mut self.properties, schema);
+        update_required(&mut self.properties, self.runs);
+    }
+
+    pub fn validate(&self, document: &Document) -> Result<Vec<SchemaViolation>, Errors> {
+        log::trace!("In validate");
+
+        let mut violations = Vec::new();
+
+        let schema = match &document.schema {
+            Some(schema) => schema,
+            None => return Ok(violations),
+        };
+
+        let data: Value = serde_json::from_str(&document.data).map_err(|err| {
+            log::error!("Could not parse document data: {}", err);
+            Errors::UnexpectedDocumentType
+        })?;
+
+        for root_name in schema.keys() {
+            match self.properties.get(root_name) {
+                Some(root) => validate_object(&data, &root.properties, root_name, &mut violations),
+                None => violations.push(SchemaViolation::UnknownRoot(root_name.clone())),
+            }
+        }
+
+        Ok(violations)
+    }
+}
+
+impl ProfileSchemaProperty {
+    fn from_schema_node(schema_node: &SchemaNode) -> Self {
+        ProfileSchemaProperty {
+            name: schema_node.name.clone(),
+            description: schema_node.description.clone(),
+            data_type: schema_node.data_type.clone(),
+            occurrences: 0,
+            required: false,
+            properties: HashMap::new(),
+        }
+    }
+}
+
+pub async fn unify_profile_schema<P: Provider>(
+    provider: Arc<P>,
+    meta_context: Arc<RwLock<MetaContext>>,
+) -> Result<Vec<SchemaViolation>, Errors> {
+    log::trace!("In unify_profile_schema");
+
+    let (profile, document) = {
+        let lock = read_lock!(meta_context);
+        (
+            lock.profile.clone().ok_or(Errors::ProfileNotProvided)?,
+            lock.document.clone().ok_or(Errors::DocumentNotProvided)?,
+        )
+    };
+
+    let schema = document.schema.clone().ok_or(Errors::DocumentNotProvided)?;
+
+    let mut profile_schema = match provider.get_profile_schema(&profile.id).await? {
+        Some(profile_schema) => profile_schema,
+        None => ProfileSchema::new(&profile.id),
+    };
+
+    // Outputs are held to the contract established by previous runs, before
+    // the current run is allowed to loosen it.
+    let violations = if profile_schema.runs > 0 {
+        profile_schema.validate(&document)?
+    } else {
+        Vec::new()
+    };
+
+    for violation in violations.iter() {
+        log::warn!("Schema violation for profile {}: {:?}", profile.id.to_string(), violation);
+    }
+
+    profile_schema.merge(&schema);
+
+    provider.save_profile_schema(&profile_schema).await?;
+
+    {
+        let mut lock = write_lock!(meta_context);
+        lock.update_profile_schema(Arc::new(profile_schema), violations.clone());
+    }
+
+    Ok(violations)
+}
+
+fn merge_properties(
+    properties: &mut HashMap<String, ProfileSchemaProperty>,
+    schema: &HashMap<String, SchemaNode>,
+) {
+    for (key, schema_node) in schema.iter() {
+        let property = properties
+            .entry(key.clone())
+            .or_insert_with(|| ProfileSchemaProperty::from_schema_node(schema_node));
+
+        property.occurrences += 1;
+
+        if property.description.is_empty() {
+            property.description = schema_node.description.clone();
+        }
+
+        // A property that held several values in any run is an array
+        if property.data_type != schema_node.data_type && schema_node.data_type == "array" {
+            property.data_type = schema_node.data_type.clone();
+        }
+
+        merge_properties(&mut property.properties, &schema_node.properties);
+    }
+}
+
+fn update_required(properties: &mut HashMap<String, ProfileSchemaProperty>, parent_occurrences: usize) {
+    for property in properties.values_mut() {
+        property.required = property.occurrences >= parent_occurrences;
+
+        update_required(&mut property.properties, property.occurrences);
+    }
+}
+
+fn get_value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn validate_object(
+    value: &Value,
+    properties: &HashMap<String, ProfileSchemaProperty>,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => {
+            violations.push(SchemaViolation::TypeMismatch {
+                path: path.to_string(),
+                expected: "object".to_string(),
+                found: get_value_type(value).to_string(),
+            });
+            return;
+        }
+    };
+
+    for (key, property) in properties.iter() {
+        if property.required && !object.contains_key(key) {
+            violations.push(SchemaViolation::MissingProperty(format!("{}.{}", path, key)));
+        }
+    }
+
+    for (key, value) in object.iter() {
+        let property_path = format!("{}.{}", path, key);
+
+        match properties.get(key) {
+            Some(property) => validate_value(value, property, &property_path, violations),
+            None => violations.push(SchemaViolation::UnexpectedProperty(property_path)),
+        }
+    }
+}
+
+fn validate_value(
+    value: &Value,
+    property: &ProfileSchemaProperty,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    match (property.data_type.as_str(), value) {
+        // Arrays only become arrays when a run holds several values, so a
+        // single value is also acceptable
+        ("array", Value::Array(items)) => {
+            for item in items.iter() {
+                if item.is_object() {
+                    validate_object(item, &property.properties, path, violations);
+                }
+            }
+        },
+        ("array", Value::Object(_)) | ("object", _) => {
+            validate_object(value, &property.properties, path, violations);
+        },
+        ("array", _) => {},
+        (expected, value) => {
+            let found = get_value_type(value);
+
+            if expected != found {
+                violations.push(SchemaViolation::TypeMismatch {
+                    path: path.to_string(),
+                    expected: expected.to_string(),
+                    found: found.to_string(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::lineage::Lineage;
+
+    fn node(name: &str, data_type: &str, children: Vec<SchemaNode>) -> SchemaNode {
+        let mut node = SchemaNode::new(name, &format!("The {}", name), &Lineage::new(), data_type);
+        node.properties = children.into_iter().map(|child| (child.name.clone(), child)).collect();
+        node
+    }
+
+    fn schema(root: SchemaNode) -> HashMap<String, SchemaNode> {
+        HashMap::from([(root.name.clone(), root)])
+    }
+
+    fn document(root: SchemaNode, data: Value) -> Document {
+        let mut document = Document::from_string(data.to_string(), &None).unwrap();
+        document.schema = Some(schema(root));
+        document
+    }
+
+    fn listing_schema() -> ProfileSchema {
+        let mut profile_schema = ProfileSchema::new(&ID::new());
+        profile_schema.merge(&schema(node("listing", "object", vec![
+            node("title", "string", vec![]),
+            node("price", "number", vec![]),
+            node("tag", "string", vec![]),
+        ])));
+        profile_schema.merge(&schema(node("listing", "object", vec![
+            node("title", "string", vec![]),
+            node("tag", "array", vec![]),
+        ])));
+        profile_schema
+    }
+
+    #[test]
+    fn merge_requires_properties_found_in_every_run() {
+        let profile_schema = listing_schema();
+        let listing = &profile_schema.properties["listing"];
+
+        assert_eq!(profile_schema.runs, 2);
+        assert!(listing.required);
+        assert!(listing.properties["title"].required);
+        assert!(!listing.properties["price"].required);
+        assert_eq!(listing.properties["price"].occurrences, 1);
+        assert_eq!(listing.properties["tag"].data_type, "array");
+        assert_eq!(listing.description, "The listing");
+    }
+
+    #[test]
+    fn validate_accepts_conforming_documents() {
+        let profile_schema = listing_schema();
+
+        let many_tags = document(node("listing", "object", vec![]), json!({
+            "title": "Lamp",
+            "price": 12,
+            "tag": ["home", "light"],
+        }));
+        let single_tag = document(node("listing", "object", vec![]), json!({
+            "title": "Lamp",
+            "tag": "home",
+        }));
+
+        assert!(profile_schema.validate(&many_tags).unwrap().is_empty());
+        assert!(profile_schema.validate(&single_tag).unwrap().is_empty());
+    }
+
+    #[test]
+    fn validate_reports_violations() {
+        let profile_schema = listing_schema();
+
+        let listing = document(node("listing", "object", vec![]), json!({
+            "price": "twelve",
+            "tag": "home",
+            "colour": "red",
+        }));
+        let mut violations: Vec<String> = profile_schema
+            .validate(&listing)
+            .unwrap()
+            .iter()
+            .map(|violation| format!("{:?}", violation))
+            .collect();
+        violations.sort();
+
+        assert_eq!(violations, vec![
+            "MissingProperty(\"listing.title\")".to_string(),
+            "TypeMismatch { path: \"listing.price\", expected: \"number\", found: \"string\" }".to_string(),
+            "UnexpectedProperty(\"listing.colour\")".to_string(),
+        ]);
+
+        let article = document(node("article", "object", vec![]), json!({}));
+
+        assert!(matches!(
+            profile_schema.validate(&article).unwrap().as_slice(),
+            [SchemaViolation::UnknownRoot(root)] if root == "article"
+        ));
+    }
+
+    #[test]
+    fn validate_skips_documents_without_schema() {
+        let profile_schema = listing_schema();
+        let document = Document::from_string("not json".to_string(), &None).unwrap();
+
+        assert!(profile_schema.validate(&document).unwrap().is_empty());
+    }
+}
@@ -2,22 +2,28 @@ use async_trait::async_trait;
 use std::sync::Arc;
 use tokio::fs as async_fs;
 use tokio::sync::RwLock as AsyncRwLock;
-use std::collections::{HashSet};
+use std::collections::{HashMap};
 use serde_yaml;
 
 use crate::prelude::*;
-use crate::profile::Profile;
+use crate::profile::{Profile, ProfileMatch, ProfileIndex};
 use crate::basis_node::BasisNode;
 use crate::basis_network::BasisNetwork;
 use crate::basis_graph::BasisGraph;
 use crate::schema::Schema;
+use crate::profile_schema::ProfileSchema;
 
 #[async_trait]
 pub trait Provider: Send + Sync + Sized + 'static {
     async fn get_profile(
         &self,
-        features: &HashSet<Hash>
+        features: &HashMap<Hash, f64>
     ) -> Result<Option<Profile>, Errors>;
+    async fn get_profile_candidates(
+        &self,
+        features: &HashMap<Hash, f64>,
+        count: usize,
+    ) -> Result<Vec<ProfileMatch>, Errors>;
     async fn save_profile(
         &self,
         profile: &Profile
@@ -26,6 +32,10 @@ pub trait Provider: Send + Sync + Sized + 'static {
         &self,
         lineage: &Lineage
     ) -> Result<Option<BasisNode>, Errors>;
+    async fn get_basis_nodes_by_profile(
+        &self,
+        profile_id: &ID
+    ) -> Result<Vec<BasisNode>, Errors>;
     async fn save_basis_node(
         &self,
         lineage: &Lineage,
@@ -49,11 +59,20 @@ pub trait Provider: Send + Sync + Sized + 'static {
         lineage: &Lineage,
         basis_graph: BasisGraph
     ) -> Result<(), Errors>;
+    async fn get_profile_schema(
+        &self,
+        profile_id: &ID
+    ) -> Result<Option<ProfileSchema>, Errors>;
+    async fn save_profile_schema(
+        &self,
+        profile_schema: &ProfileSchema
+    ) -> Result<(), Errors>;
 }
 
 pub struct YamlFileProvider {
     file_path: String,
     cache: Arc<AsyncRwLock<Option<serde_yaml::Value>>>,
+    profile_index: Arc<AsyncRwLock<Option<ProfileIndex>>>,
 }
 
 impl YamlFileProvider {
@@ -61,14 +80,32 @@ impl YamlFileProvider {
         Self {
             file_path,
             cache: Arc::new(AsyncRwLock::new(None)),
+            profile_index: Arc::new(AsyncRwLock::new(None)),
+        }
+    }
+
+    /// Builds the profile index on first use, so stored profiles are only
+    /// deserialized once
+    async fn load_profile_index(&self) -> Result<(), Errors> {
+        if self.profile_index.read().await.is_some() {
+            return Ok(());
+        }
+
+        let profiles = self.get_profiles().await?;
+        let mut profile_index = self.profile_index.write().await;
+
+        if profile_index.is_none() {
+            *profile_index = Some(ProfileIndex::from_profiles(profiles));
         }
+
+        Ok(())
     }
 
     async fn load_data(&self) -> Result<serde_yaml::Value, Errors> {
         let mut cache = self.cache.write().await;
         if cache.is_none() {
-            let data = async_fs::read_to_string(&self.file_path).await.map_err(|_| Errors::FileReadError)?;
-            let yaml: serde_yaml::Value = serde_yaml::from_str(&data).map_err(|_| Errors::YamlParseError)?;
+            let data = async_fs::read_to_string(&self.file_path).await?;
+            let yaml: serde_yaml::Value = serde_yaml::from_str(&data)?;
             *cache = Some(yaml.clone());
             Ok(yaml)
         } else {
@@ -76,9 +113,23 @@ impl YamlFileProvider {
         }
     }
 
+    async fn get_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        let yaml = self.load_data().await?;
+
+        yaml.get("profiles")
+            .and_then(|dp| {
+                let deserialized: Result<Vec<Profile>, _> = serde_yaml::from_value(dp.clone());
+                if let Err(ref err) = deserialized {
+                    log::error!("Deserialization error: {:?}", err);
+                }
+                deserialized.ok()
+            })
+            .ok_or(Errors::YamlParseError)
+    }
+
     async fn save_data(&self, yaml: &serde_yaml::Value) -> Result<(), Errors> {
-        let new_yaml_str = serde_yaml::to_string(yaml).map_err(|_| Errors::UnexpectedError)?;
-        async_fs::write(&self.file_path, new_yaml_str).await.map_err(|_| Errors::UnexpectedError)?;
+        let new_yaml_str = serde_yaml::to_string(yaml)?;
+        async_fs::write(&self.file_path, new_yaml_str).await?;
         let mut cache = self.cache.write().await;
         *cache = Some(yaml.clone());
         Ok(())
@@ -89,28 +140,27 @@ impl YamlFileProvider {
 impl Provider for YamlFileProvider {
     async fn get_profile(
         &self,
-        features: &HashSet<Hash>
+        features: &HashMap<Hash, f64>
     ) -> Result<Option<Profile>, Errors> {
-        let yaml = self.load_data().await?;
+        self.load_profile_index().await?;
 
-        let profiles: Vec<Profile> = yaml.get("profiles")
-            .and_then(|dp| {
-                let deserialized: Result<Vec<Profile>, _> = serde_yaml::from_value(dp.clone());
-                if let Err(ref err) = deserialized {
-                    log::error!("Deserialization error: {:?}", err);
-                }
-                deserialized.ok()
-            })
-            .ok_or(Errors::YamlParseError)?;
+        let profile_index = self.profile_index.read().await;
+        let profile_index = profile_index.as_ref().ok_or(Errors::UnexpectedError)?;
 
-        if let Some(target_profile) = Profile::get_similar_profile(
-            &profiles,
-            features
-        ) {
-            Ok(Some(target_profile))
-        } else {
-            Ok(None)
-        }
+        Ok(profile_index.get_similar_profile(features))
+    }
+
+    async fn get_profile_candidates(
+        &self,
+        features: &HashMap<Hash, f64>,
+        count: usize,
+    ) -> Result<Vec<ProfileMatch>, Errors> {
+        self.load_profile_index().await?;
+
+        let profile_index = self.profile_index.read().await;
+        let profile_index = profile_index.as_ref().ok_or(Errors::UnexpectedError)?;
+
+        Ok(profile_index.get_profile_candidates(features, count))
     }
 
     async fn save_profile(
@@ -123,10 +173,16 @@ impl Provider for YamlFileProvider {
             .and_then(|profiles_value| profiles_value.as_sequence_mut())
             .ok_or(Errors::YamlParseError)?;
 
-        let new_profile_yaml = serde_yaml::to_value(&profile).map_err(|_| Errors::UnexpectedError)?;
+        let new_profile_yaml = serde_yaml::to_value(&profile)?;
         profiles.push(new_profile_yaml);
 
-        self.save_data(&yaml).await
+        self.save_data(&yaml).await?;
+
+        if let Some(profile_index) = self.profile_index.write().await.as_mut() {
+            profile_index.insert(profile.clone());
+        }
+
+        Ok(())
     }
 
     async fn get_basis_node_by_lineage(
@@ -154,6 +210,28 @@ impl Provider for YamlFileProvider {
         Ok(None)
     }
 
+    async fn get_basis_nodes_by_profile(
+        &self,
+        profile_id: &ID
+    ) -> Result<Vec<BasisNode>, Errors> {
+        let yaml = self.load_data().await?;
+
+        let basis_nodes: Vec<BasisNode> = yaml.get("basis_nodes")
+            .and_then(|bn| {
+                let deserialized: Result<Vec<BasisNode>, _> = serde_yaml::from_value(bn.clone());
+                if let Err(ref err) = deserialized {
+                    log::error!("Deserialization error: {:?}", err);
+                }
+                deserialized.ok()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(basis_nodes
+            .into_iter()
+            .filter(|basis_node| basis_node.profile_id.as_ref() == Some(profile_id))
+            .collect())
+    }
+
     async fn save_basis_node(
         &self,
         _lineage: &Lineage,
@@ -161,8 +239,7 @@ impl Provider for YamlFileProvider {
     ) -> Result<(), Errors> {
         let mut yaml = self.load_data().await?;
 
-        let serialized_basis_node = serde_yaml::to_value(&basis_node)
-            .map_err(|_| Errors::UnexpectedError)?;
+        let serialized_basis_node = serde_yaml::to_value(&basis_node)?;
 
         if let Some(basis_nodes) = yaml.get_mut("basis_nodes") {
             basis_nodes.as_sequence_mut()
@@ -209,8 +286,7 @@ impl Provider for YamlFileProvider {
     ) -> Result<(), Errors> {
         let mut yaml = self.load_data().await?;
 
-        let serialized_basis_network = serde_yaml::to_value(&basis_network)
-            .map_err(|_| Errors::UnexpectedError)?;
+        let serialized_basis_network = serde_yaml::to_value(&basis_network)?;
 
         if let Some(basis_networks) = yaml.get_mut("basis_networks") {
             basis_networks.as_sequence_mut()
@@ -257,8 +333,7 @@ impl Provider for YamlFileProvider {
     ) -> Result<(), Errors> {
         let mut yaml = self.load_data().await?;
 
-        let serialized_basis_graph = serde_yaml::to_value(&basis_graph)
-            .map_err(|_| Errors::UnexpectedError)?;
+        let serialized_basis_graph = serde_yaml::to_value(&basis_graph)?;
 
         if let Some(basis_graphs) = yaml.get_mut("basis_graphs") {
             basis_graphs.as_sequence_mut()
@@ -272,6 +347,57 @@ impl Provider for YamlFileProvider {
 
         self.save_data(&yaml).await
     }
+
+    async fn get_profile_schema(
+        &self,
+        profile_id: &ID
+    ) -> Result<Option<ProfileSchema>, Errors> {
+        let yaml = self.load_data().await?;
+
+        let profile_schemas: Vec<ProfileSchema> = yaml.get("profile_schemas")
+            .and_then(|ps| {
+                let deserialized: Result<Vec<ProfileSchema>, _> = serde_yaml::from_value(ps.clone());
+                if let Err(ref err) = deserialized {
+                    log::error!("Deserialization error: {:?}", err);
+                }
+                deserialized.ok()
+            })
+            .unwrap_or_else(Vec::new);
+
+        for profile_schema in profile_schemas {
+            if profile_schema.profile_id == *profile_id {
+                return Ok(Some(profile_schema));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn save_profile_schema(
+        &self,
+        profile_schema: &ProfileSchema
+    ) -> Result<(), Errors> {
+        let mut yaml = self.load_data().await?;
+
+        let serialized_profile_schema = serde_yaml::to_value(profile_schema)?;
+
+        if let Some(profile_schemas) = yaml.get_mut("profile_schemas") {
+            let profile_schemas = profile_schemas.as_sequence_mut()
+                .ok_or(Errors::YamlParseError)?;
+
+            // A profile has a single merged schema, so an existing entry is replaced
+            profile_schemas.retain(|existing| {
+                existing.get("profile_id") != serialized_profile_schema.get("profile_id")
+            });
+            profile_schemas.push(serialized_profile_schema);
+        } else {
+            yaml["profile_schemas"] = serde_yaml::Value::Sequence(
+                vec![serialized_profile_schema]
+            );
+        }
+
+        self.save_data(&yaml).await
+    }
 }
 
 pub struct VoidProvider;
@@ -280,11 +406,19 @@ pub struct VoidProvider;
 impl Provider for VoidProvider {
     async fn get_profile(
         &self,
-        _features: &HashSet<Hash>
+        _features: &HashMap<Hash, f64>
     ) -> Result<Option<Profile>, Errors> {
         Ok(None)
     }
 
+    async fn get_profile_candidates(
+        &self,
+        _features: &HashMap<Hash, f64>,
+        _count: usize,
+    ) -> Result<Vec<ProfileMatch>, Errors> {
+        Ok(Vec::new())
+    }
+
     async fn save_profile(
         &self,
         _profile: &Profile
@@ -299,6 +433,13 @@ impl Provider for VoidProvider {
         Ok(None)
     }
 
+    async fn get_basis_nodes_by_profile(
+        &self,
+        _profile_id: &ID
+    ) -> Result<Vec<BasisNode>, Errors> {
+        Ok(Vec::new())
+    }
+
     async fn save_basis_node(
         &self,
         _lineage: &Lineage,
@@ -336,5 +477,18 @@ impl Provider for VoidProvider {
     ) -> Result<(), Errors> {
         Ok(())
     }
-}
 
+    async fn get_profile_schema(
+        &self,
+        _profile_id: &ID
+    ) -> Result<Option<ProfileSchema>, Errors> {
+        Ok(None)
+    }
+
+    async fn save_profile_schema(
+        &self,
+        _profile_schema: &ProfileSchema
+    ) -> Result<(), Errors> {
+        Ok(())
+    }
+}
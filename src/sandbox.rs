@@ -0,0 +1,334 @@
+use libquickjs_sys as q;
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_void};
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+
+#[derive(Clone, Debug)]
+pub struct SandboxLimits {
+    pub time_limit: Duration,
+    pub memory_limit: usize,
+    pub stack_size: usize,
+}
+
+impl SandboxLimits {
+    pub fn from_config() -> Self {
+        let lock = read_lock!(CONFIG);
+
+        SandboxLimits {
+            time_limit: Duration::from_millis(lock.runtimes.quickjs_time_limit_ms),
+            memory_limit: lock.runtimes.quickjs_memory_limit,
+            stack_size: lock.runtimes.quickjs_stack_size,
+        }
+    }
+}
+
+struct SandboxState {
+    deadline: Cell<Option<Instant>>,
+    interrupted: Cell<bool>,
+    allocation_refused: Cell<bool>,
+}
+
+/// A QuickJS runtime with its own memory limit and an interrupt handler that
+/// aborts any evaluation running past its time limit. A sandbox is created for
+/// each evaluation, so nothing a script does to its globals or intrinsics is
+/// seen by later scripts. QuickJS also measures stack use from the frame that
+/// created the runtime, which a long-lived runtime would get wrong.
+pub struct QuickJsSandbox {
+    runtime: *mut q::JSRuntime,
+    context: *mut q::JSContext,
+    limits: SandboxLimits,
+    state: Box<SandboxState>,
+}
+
+unsafe extern "C" fn interrupt_handler(_runtime: *mut q::JSRuntime, opaque: *mut c_void) -> c_int {
+    let state = &*(opaque as *const SandboxState);
+
+    match state.deadline.get() {
+        Some(deadline) if Instant::now() >= deadline => {
+            state.interrupted.set(true);
+            1
+        },
+        _ => 0,
+    }
+}
+
+// QuickJS accounts each allocation as its usable size plus this overhead
+const MALLOC_OVERHEAD: usize = 8;
+
+unsafe fn usable_size(pointer: *const c_void) -> usize {
+    libc::malloc_usable_size(pointer as *mut c_void) + MALLOC_OVERHEAD
+}
+
+// The allocator mirrors the one QuickJS uses by default, but records when it
+// refuses an allocation, so that running out of memory can be told apart from
+// a script that merely throws an error saying so
+unsafe fn refuse_allocation(malloc_state: &q::JSMallocState) -> *mut c_void {
+    let state = &*(malloc_state.opaque as *const SandboxState);
+    state.allocation_refused.set(true);
+
+    std::ptr::null_mut()
+}
+
+unsafe extern "C" fn sandbox_malloc(malloc_state: *mut q::JSMallocState, size: q::size_t) -> *mut c_void {
+    let malloc_state = &mut *malloc_state;
+    let size = size as usize;
+
+    if (malloc_state.malloc_size as usize).saturating_add(size) > malloc_state.malloc_limit as usize {
+        return refuse_allocation(malloc_state);
+    }
+
+    let pointer = libc::malloc(size);
+    if pointer.is_null() {
+        return refuse_allocation(malloc_state);
+    }
+
+    malloc_state.malloc_count += 1;
+    malloc_state.malloc_size += usable_size(pointer) as q::size_t;
+
+    pointer
+}
+
+unsafe extern "C" fn sandbox_free(malloc_state: *mut q::JSMallocState, pointer: *mut c_void) {
+    if pointer.is_null() {
+        return;
+    }
+
+    let malloc_state = &mut *malloc_state;
+    malloc_state.malloc_count -= 1;
+    malloc_state.malloc_size -= usable_size(pointer) as q::size_t;
+
+    libc::free(pointer);
+}
+
+unsafe extern "C" fn sandbox_realloc(
+    malloc_state: *mut q::JSMallocState,
+    pointer: *mut c_void,
+    size: q::size_t,
+) -> *mut c_void {
+    if pointer.is_null() {
+        if size == 0 {
+            return std::ptr::null_mut();
+        }
+
+        return sandbox_malloc(malloc_state, size);
+    }
+
+    if size == 0 {
+        sandbox_free(malloc_state, pointer);
+        return std::ptr::null_mut();
+    }
+
+    let malloc_state = &mut *malloc_state;
+    let size = size as usize;
+    let old_size = usable_size(pointer);
+
+    if (malloc_state.malloc_size as usize).saturating_add(size) > (malloc_state.malloc_limit as usize).saturating_add(old_size) {
+        return refuse_allocation(malloc_state);
+    }
+
+    let pointer = libc::realloc(pointer, size);
+    if pointer.is_null() {
+        return refuse_allocation(malloc_state);
+    }
+
+    malloc_state.malloc_size = malloc_state.malloc_size + usable_size(pointer) as q::size_t - old_size as q::size_t;
+
+    pointer
+}
+
+unsafe extern "C" fn sandbox_malloc_usable_size(pointer: *const c_void) -> q::size_t {
+    libc::malloc_usable_size(pointer as *mut c_void) as q::size_t
+}
+
+static MALLOC_FUNCTIONS: q::JSMallocFunctions = q::JSMallocFunctions {
+    js_malloc: Some(sandbox_malloc),
+    js_free: Some(sandbox_free),
+    js_realloc: Some(sandbox_realloc),
+    js_malloc_usable_size: Some(sandbox_malloc_usable_size),
+};
+
+impl QuickJsSandbox {
+    pub fn new(limits: SandboxLimits) -> Result<Self, Errors> {
+        log::trace!("In QuickJsSandbox::new");
+
+        let state = Box::new(SandboxState {
+            deadline: Cell::new(None),
+            interrupted: Cell::new(false),
+            allocation_refused: Cell::new(false),
+        });
+        let opaque = &*state as *const SandboxState as *mut c_void;
+
+        unsafe {
+            let runtime = q::JS_NewRuntime2(&MALLOC_FUNCTIONS, opaque);
+            if runtime.is_null() {
+                return Err(Errors::TransformationError("Could not create QuickJS runtime".to_string()));
+            }
+
+            q::JS_SetMemoryLimit(runtime, limits.memory_limit as _);
+            q::JS_SetMaxStackSize(runtime, limits.stack_size as _);
+            q::JS_SetInterruptHandler(
+                runtime,
+                Some(interrupt_handler),
+                opaque,
+            );
+
+            let context = q::JS_NewContext(runtime);
+            if context.is_null() {
+                q::JS_FreeRuntime(runtime);
+                return Err(Errors::TransformationError("Could not create QuickJS context".to_string()));
+            }
+
+            Ok(QuickJsSandbox {
+                runtime,
+                context,
+                limits,
+                state,
+            })
+        }
+    }
+
+    /// Evaluates a script and returns its completion value as a string
+    pub fn eval_to_string(&self, script: &str) -> Result<String, Errors> {
+        log::trace!("In eval_to_string");
+
+        let code = CString::new(script).map_err(|_| {
+            Errors::TransformationError("Script contains a null byte".to_string())
+        })?;
+        let filename = CString::new("transformation").map_err(|_| Errors::UnexpectedError)?;
+
+        self.state.interrupted.set(false);
+        self.state.allocation_refused.set(false);
+        self.state.deadline.set(Some(Instant::now() + self.limits.time_limit));
+
+        let result = unsafe {
+            let value = q::JS_Eval(
+                self.context,
+                code.as_ptr(),
+                script.len() as _,
+                filename.as_ptr(),
+                q::JS_EVAL_TYPE_GLOBAL as c_int,
+            );
+
+            if q::JS_IsException(value) {
+                let exception = q::JS_GetException(self.context);
+                let message = self.value_to_string(exception);
+                q::JS_FreeValue(self.context, exception);
+
+                Err(message.unwrap_or_else(|| "unknown exception".to_string()))
+            } else {
+                let output = self.value_to_string(value);
+                q::JS_FreeValue(self.context, value);
+
+                output.ok_or_else(|| "could not convert result to string".to_string())
+            }
+        };
+
+        self.state.deadline.set(None);
+
+        result.map_err(|message| {
+            if self.state.interrupted.get() {
+                Errors::TransformationError(format!(
+                    "Script did not finish within {}ms",
+                    self.limits.time_limit.as_millis()
+                ))
+            } else if self.state.allocation_refused.get() {
+                Errors::TransformationError(format!(
+                    "Script exceeded the memory limit of {} bytes",
+                    self.limits.memory_limit
+                ))
+            } else {
+                Errors::TransformationError(format!("Could not evaluate script: {}", message))
+            }
+        })
+    }
+
+    unsafe fn value_to_string(&self, value: q::JSValue) -> Option<String> {
+        let mut length: q::size_t = 0;
+        let pointer = q::JS_ToCStringLen2(self.context, &mut length, value, 0);
+
+        if pointer.is_null() {
+            return None;
+        }
+
+        let string = CStr::from_ptr(pointer).to_string_lossy().into_owned();
+        q::JS_FreeCString(self.context, pointer);
+
+        Some(string)
+    }
+}
+
+impl Drop for QuickJsSandbox {
+    fn drop(&mut self) {
+        unsafe {
+            q::JS_FreeContext(self.context);
+            q::JS_FreeRuntime(self.runtime);
+        }
+    }
+}
+
+/// Evaluates a script in a sandbox of its own, with the limits from the
+/// configuration
+pub fn evaluate_in_sandbox(script: &str) -> Result<String, Errors> {
+    log::trace!("In evaluate_in_sandbox");
+
+    QuickJsSandbox::new(SandboxLimits::from_config())?.eval_to_string(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> QuickJsSandbox {
+        QuickJsSandbox::new(SandboxLimits {
+            time_limit: Duration::from_millis(200),
+            memory_limit: 8 * 1024 * 1024,
+            stack_size: 256 * 1024,
+        }).unwrap()
+    }
+
+    fn error_message(result: Result<String, Errors>) -> String {
+        match result {
+            Err(Errors::TransformationError(message)) => message,
+            other => panic!("Expected a transformation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scripts_evaluate_to_strings() {
+        assert_eq!(sandbox().eval_to_string("JSON.stringify({ a: [1, 2].map(x => x * 2) })").unwrap(), r#"{"a":[2,4]}"#);
+    }
+
+    #[test]
+    fn endless_loops_are_interrupted() {
+        let message = error_message(sandbox().eval_to_string("while (true) {}"));
+
+        assert_eq!(message, "Script did not finish within 200ms");
+    }
+
+    #[test]
+    fn large_allocations_exceed_the_memory_limit() {
+        let message = error_message(sandbox().eval_to_string("new ArrayBuffer(64 * 1024 * 1024)"));
+        assert_eq!(message, "Script exceeded the memory limit of 8388608 bytes");
+
+        let message = error_message(sandbox().eval_to_string("let items = []; while (true) items.push('x'.repeat(1024));"));
+        assert_eq!(message, "Script exceeded the memory limit of 8388608 bytes");
+    }
+
+    #[test]
+    fn thrown_errors_are_not_mistaken_for_limits() {
+        let message = error_message(sandbox().eval_to_string("throw new InternalError('out of memory')"));
+
+        assert_eq!(message, "Could not evaluate script: InternalError: out of memory");
+    }
+
+    #[test]
+    fn scripts_do_not_see_changes_made_by_earlier_scripts() {
+        evaluate_in_sandbox("globalThis.leaked = 1; Array.prototype.map = null; 'done'").unwrap();
+
+        assert_eq!(evaluate_in_sandbox("typeof leaked + [1].map(x => x)").unwrap(), "undefined1");
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use crate::prelude::*;
+use crate::config::{CONFIG, BudgetBehavior};
+
+/// The kind of decision an LLM request was made for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LlmStage {
+    Summary,
+    Profile,
+    FieldDecisions,
+    Elimination,
+    Peripheral,
+    Primary,
+    Recursion,
+    Associations,
+}
+
+impl fmt::Display for LlmStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LlmStage::Summary => "summary",
+            LlmStage::Profile => "profile",
+            LlmStage::FieldDecisions => "field decisions",
+            LlmStage::Elimination => "elimination",
+            LlmStage::Peripheral => "peripheral",
+            LlmStage::Primary => "primary",
+            LlmStage::Recursion => "recursion",
+            LlmStage::Associations => "associations",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TokenCount {
+    pub requests: usize,
+    pub cached_requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+impl TokenCount {
+    pub fn get_total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Cost in dollars at the configured prices per million tokens
+    pub fn get_cost(&self) -> f64 {
+        let lock = read_lock!(CONFIG);
+
+        (self.prompt_tokens as f64 * lock.llm.prompt_token_cost
+            + self.completion_tokens as f64 * lock.llm.completion_token_cost)
+            / 1_000_000.0
+    }
+
+    fn add(&mut self, other: &TokenCount) {
+        self.requests += other.requests;
+        self.cached_requests += other.cached_requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// Token usage of the LLM requests made while processing a document. It is
+/// shared by every task working on the document, so it records through a
+/// shared reference.
+#[derive(Debug, Default)]
+pub struct TokenUsage {
+    stages: RwLock<BTreeMap<LlmStage, TokenCount>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TokenUsageReport {
+    pub stages: BTreeMap<LlmStage, TokenCount>,
+    pub total: TokenCount,
+    pub cost: f64,
+}
+
+impl TokenUsage {
+    pub fn new() -> Self {
+        TokenUsage::default()
+    }
+
+    pub fn record(&self, stage: LlmStage, prompt_tokens: usize, completion_tokens: usize) {
+        log::debug!("{} request used {} prompt and {} completion tokens", stage, prompt_tokens, completion_tokens);
+
+        let mut lock = write_lock!(self.stages);
+        let count = lock.entry(stage).or_default();
+
+        count.requests += 1;
+        count.prompt_tokens += prompt_tokens;
+        count.completion_tokens += completion_tokens;
+    }
+
+    /// Records a request answered from the cache, which costs nothing
+    pub fn record_cached(&self, stage: LlmStage) {
+        let mut lock = write_lock!(self.stages);
+        let count = lock.entry(stage).or_default();
+
+        count.requests += 1;
+        count.cached_requests += 1;
+    }
+
+    pub fn get_total(&self) -> TokenCount {
+        let lock = read_lock!(self.stages);
+
+        lock.values().fold(TokenCount::default(), |mut total, count| {
+            total.add(count);
+            total
+        })
+    }
+
+    pub fn get_report(&self) -> TokenUsageReport {
+        let total = self.get_total();
+
+        TokenUsageReport {
+            stages: read_lock!(self.stages).clone(),
+            cost: total.get_cost(),
+            total,
+        }
+    }
+
+    /// Fails once the usage has reached llm.max_tokens or llm.max_cost
+    pub fn check_budget(&self) -> Result<(), Errors> {
+        let (max_tokens, max_cost) = {
+            let lock = read_lock!(CONFIG);
+            (lock.llm.max_tokens, lock.llm.max_cost)
+        };
+
+        let total = self.get_total();
+
+        if let Some(max_tokens) = max_tokens {
+            if total.get_total_tokens() >= max_tokens {
+                return Err(Errors::BudgetExceeded(format!(
+                    "used {} of {} tokens",
+                    total.get_total_tokens(),
+                    max_tokens
+                )));
+            }
+        }
+
+        if let Some(max_cost) = max_cost {
+            let cost = total.get_cost();
+
+            if cost >= max_cost {
+                return Err(Errors::BudgetExceeded(format!(
+                    "spent ${:.4} of ${:.4}",
+                    cost,
+                    max_cost
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a request that failed for want of budget should leave its
+    /// result empty rather than fail its stage, as llm.budget_behavior
+    /// degrades
+    pub fn is_degraded(err: &Errors) -> bool {
+        matches!(err.root_cause(), Errors::BudgetExceeded(_)) &&
+            read_lock!(CONFIG).llm.budget_behavior == BudgetBehavior::Degrade
+    }
+
+    /// Applies llm.budget_behavior once the budget is used up. Aborting fails
+    /// the run, degrading lets it carry on with what was obtained so far.
+    pub fn enforce_budget(&self) -> Result<(), Errors> {
+        match self.check_budget() {
+            Err(err) if read_lock!(CONFIG).llm.budget_behavior == BudgetBehavior::Abort => Err(err),
+            Err(err) => {
+                log::warn!("Continuing without further LLM requests: {}", err);
+                Ok(())
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for TokenUsageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<16} {:>8} {:>8} {:>12} {:>12}", "stage", "requests", "cached", "prompt", "completion")?;
+
+        let rows = self.stages
+            .iter()
+            .map(|(stage, count)| (stage.to_string(), count))
+            .chain(std::iter::once(("total".to_string(), &self.total)));
+
+        for (name, count) in rows {
+            writeln!(
+                f,
+                "{:<16} {:>8} {:>8} {:>12} {:>12}",
+                name,
+                count.requests,
+                count.cached_requests,
+                count.prompt_tokens,
+                count.completion_tokens
+            )?;
+        }
+
+        write!(f, "estimated cost: ${:.4}", self.cost)
+    }
+}
@@ -1,10 +1,16 @@
 use std::sync::{Arc};
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::collections::{HashMap};
-use quick_js::{Context as QuickContext};
+use lazy_static::lazy_static;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::sandbox::evaluate_in_sandbox;
+use crate::worker::request_from_worker;
 use crate::id::{ID};
 use crate::json_node::{Json, JsonNode, JsonMetadata};
 use crate::data_node::DataNode;
@@ -35,69 +41,82 @@ pub struct HashTransformation {
 }
 
 impl HashTransformation {
-    fn prefix(&self, fields: HashMap<String, String>) -> String {
+    fn prefix(&self, fields: HashMap<String, String>) -> Result<String, Errors> {
         match self.runtime {
             Runtime::QuickJS => {
-                let fields_js: Vec<String> = fields
-                    .into_iter()
-                    .map(|(key, value)| {
-                        let escaped_value = value.replace("\"", "\\\"");
-
-                        if key == "text" {
-                            format!("'{}': '<omitted>'", key)
-                        } else {
-                            format!("'{}': \"{}\"", key, escaped_value)
-                        }
-                    })
-                    .collect();
-                format!("let fields = {{ {} }};", fields_js.join(", "))
+                let fields = to_js_literal(&json!(fields))?;
+
+                Ok(format!("let fields = {};", fields))
             },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+            Runtime::NodeJS => Ok("let fields = input.fields;".to_string()),
+            Runtime::Python => Ok("fields = input_data['fields']".to_string()),
+            Runtime::AWK => Ok(String::new()),
         }
     }
 
-    fn suffix(&self) -> String {
+    fn suffix(&self) -> Result<String, Errors> {
         match self.runtime {
-            Runtime::QuickJS => {
-                format!("JSON.stringify({{ hasherItems }})")
-            },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+            Runtime::QuickJS => Ok("return JSON.stringify({ hasherItems });".to_string()),
+            Runtime::NodeJS => Ok("return { hasherItems };".to_string()),
+            Runtime::Python => Ok("return {'hasherItems': hasherItems}".to_string()),
+            Runtime::AWK => Ok(String::new()),
         }
     }
 
     pub fn transform(
         &self,
         fields: HashMap<String, String>
-    ) -> Hash {
+    ) -> Result<Hash, Errors> {
         log::trace!("In transform");
 
-        let prefix = self.prefix(fields.clone());
-        let suffix = self.suffix();
-        let script = format!("{}\n{}\n{}", prefix, self.infix, suffix);
+        let mut hash = Hash::from_items(self.get_hash_items(fields)?);
+        hash.finalize();
 
-        log::debug!("script: {}", script);
+        Ok(hash)
+    }
 
-        match self.runtime {
-            Runtime::QuickJS => {
-                let quick_context = QuickContext::new().unwrap();
-                let result = quick_context.eval_as::<String>(&script).unwrap();
-                let parsed: Value = serde_json::from_str(&result).unwrap();
-                let hasher_items = parsed.get("hasherItems").unwrap();
-
-                if let Some(array) = hasher_items.as_array() {
-                    let hasher_items_vec = array
-                        .iter()
-                        .filter_map(|v| v.as_str().map(String::from))
-                        .collect::<Vec<String>>();
-
-                    let mut hash = Hash::from_items(hasher_items_vec);
-                    hash.finalize();
-                    return hash;
+    /// Runs the script and returns the strings it selected for hashing
+    pub fn get_hash_items(
+        &self,
+        fields: HashMap<String, String>
+    ) -> Result<Vec<String>, Errors> {
+        log::trace!("In get_hash_items");
+
+        // Text content never contributes to the hash, whichever runtime is used
+        let fields: HashMap<String, String> = fields
+            .into_iter()
+            .map(|(key, value)| {
+                if key == "text" {
+                    (key, "<omitted>".to_string())
                 } else {
-                    panic!("Expected 'hasherItems' to be an array");
+                    (key, value)
                 }
-            },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+            })
+            .collect();
+
+        let prefix = self.prefix(fields.clone())?;
+        let suffix = self.suffix()?;
+        let script = format!("{}\n{}\n{}", prefix, self.infix, suffix);
+
+        log::debug!("script: {}", script);
+
+        let result = match self.runtime {
+            Runtime::QuickJS => evaluate_quick_js(&script)?,
+            _ => run_subprocess(&self.runtime, &script, &json!({ "fields": fields }))?,
+        };
+
+        let parsed: Value = serde_json::from_str(&result)?;
+        let hasher_items = parsed.get("hasherItems").ok_or_else(|| {
+            Errors::TransformationError("Hash transformation did not return 'hasherItems'".to_string())
+        })?;
+
+        if let Some(array) = hasher_items.as_array() {
+            Ok(array
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect::<Vec<String>>())
+        } else {
+            Err(Errors::TransformationError("Expected 'hasherItems' to be an array".to_string()))
         }
     }
 }
@@ -111,29 +130,26 @@ pub struct XMLElementTransformation {
 }
 
 impl XMLElementTransformation {
-    fn prefix(&self, element: String, attributes: HashMap<String, String>) -> String {
-        let element_code = format!("let element = '{}';", element);
-
-        let attributes_code = {
-            let attributes_list: Vec<String> = attributes
-                .into_iter()
-                .map(|(key, value)| {
-                    let escaped_value = value.replace("\"", "\\\"");
-                    format!("\"{}\": \"{}\"", key, escaped_value)
-                })
-                .collect();
-            format!("let attributes = {{ {} }};", attributes_list.join(", "))
-        };
+    fn prefix(&self, element: String, attributes: HashMap<String, String>) -> Result<String, Errors> {
+        match self.runtime {
+            Runtime::QuickJS => {
+                let element = to_js_literal(&json!(element))?;
+                let attributes = to_js_literal(&json!(attributes))?;
 
-        format!("{}\n{}", element_code, attributes_code)
+                Ok(format!("let element = {};\nlet attributes = {};", element, attributes))
+            },
+            Runtime::NodeJS => Ok("let element = input.element;\nlet attributes = input.attributes;".to_string()),
+            Runtime::Python => Ok("element = input_data['element']\nattributes = input_data['attributes']".to_string()),
+            Runtime::AWK => Ok(String::new()),
+        }
     }
 
-    fn suffix(&self) -> String {
+    fn suffix(&self) -> Result<String, Errors> {
         match self.runtime {
-            Runtime::QuickJS => {
-                format!("JSON.stringify({{ element, attributes }})")
-            },
-            _ => panic!("unexpected runtime: {:?}", self.runtime),
+            Runtime::QuickJS => Ok("return JSON.stringify({ element, attributes });".to_string()),
+            Runtime::NodeJS => Ok("return { element, attributes };".to_string()),
+            Runtime::Python => Ok("return {'element': element, 'attributes': attributes}".to_string()),
+            Runtime::AWK => Ok(String::new()),
         }
     }
     
@@ -141,45 +157,221 @@ impl XMLElementTransformation {
         &self,
         element: String,
         attributes: HashMap<String, String>
-    ) -> (
+    ) -> Result<(
         Option<String>,
         HashMap<String, String>
-    ) {
+    ), Errors> {
         log::trace!("In transform");
 
-        let prefix = self.prefix(element, attributes);
-        let suffix = self.suffix();
+        let input = json!({
+            "element": element,
+            "attributes": attributes,
+        });
+
+        let prefix = self.prefix(element, attributes)?;
+        let suffix = self.suffix()?;
 
         let code = format!("{}\n{}\n{}", prefix, self.infix, suffix);
 
-        match self.runtime {
-            Runtime::QuickJS => {
-                let quick_context = QuickContext::new().unwrap();
+        let result = match self.runtime {
+            Runtime::QuickJS => evaluate_quick_js(&code)?,
+            _ => run_subprocess(&self.runtime, &code, &input)?,
+        };
+
+        let parsed: Value = serde_json::from_str(&result)?;
+
+        let transformed_element = parsed.get("element").and_then(|e|
+            e.as_str().map(String::from));
+
+        let transformed_attributes = parsed.get("attributes")
+            .and_then(|attr| attr.as_object())
+            .map(|attr_obj| {
+                attr_obj.iter().map(|(k, v)| {
+                    (k.clone(), v.as_str().unwrap_or("").to_string())
+                }).collect::<HashMap<String, String>>()
+            }).unwrap_or_default();
+
+        Ok((transformed_element, transformed_attributes))
+    }
+}
+
+// Subprocess runtimes run as long-lived workers, which read one JSON input
+// per line from stdin and answer each with one line on stdout. Node.js and
+// Python scripts run as the body of a function called for each input, whose
+// result or error is written back as an envelope, while anything a script
+// prints goes to stderr. AWK scripts handle each input line themselves and
+// must print exactly one line of JSON for it; the output is flushed after
+// every line, so they should not skip rules with next.
+const NODE_WORKER: &str = r#"const readline = require('readline');
+console.log = console.error;
+const transform = (input) => {
+{script}
+};
+readline.createInterface({ input: process.stdin }).on('line', (line) => {
+  let response;
+  try {
+    response = { output: transform(JSON.parse(line)) };
+  } catch (err) {
+    response = { error: String(err) };
+  }
+  process.stdout.write(JSON.stringify(response) + '\n');
+});"#;
+
+const PYTHON_WORKER: &str = r#"import json, sys
+output = sys.stdout
+sys.stdout = sys.stderr
+def transform(input_data):
+{script}
+for line in sys.stdin:
+    try:
+        response = {'output': transform(json.loads(line))}
+    except Exception as err:
+        response = {'error': str(err)}
+    output.write(json.dumps(response) + '\n')
+    output.flush()"#;
+
+const AWK_WORKER: &str = "{script}\n{ fflush() }";
+
+/// Serializes a value for embedding in a script. JSON is valid JavaScript,
+/// apart from the line and paragraph separators which older engines treat as
+/// line terminators inside string literals.
+pub fn to_js_literal(value: &Value) -> Result<String, Errors> {
+    let literal = serde_json::to_string(value)?;
+
+    Ok(literal
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029"))
+}
 
-                let result =  quick_context.eval_as::<String>(&code).unwrap();
+fn evaluate_quick_js(script: &str) -> Result<String, Errors> {
+    // Scripts end by returning their output, so they run as a function body
+    let script = format!("(function() {{\n{}\n}})()", script);
 
-                let parsed: Value = serde_json::from_str(&result).unwrap();
+    evaluate_in_sandbox(&script)
+}
 
-                let transformed_element = parsed.get("element").and_then(|e|
-                    e.as_str().map(String::from));
+lazy_static! {
+    static ref AWK_OPTIONS: Mutex<HashMap<String, Vec<String>>> = Mutex::new(HashMap::new());
+}
 
-                let transformed_attributes = parsed.get("attributes")
-                    .and_then(|attr| attr.as_object())
-                    .map(|attr_obj| {
-                        attr_obj.iter().map(|(k, v)| {
-                            (k.clone(), v.as_str().unwrap_or("").to_string())
-                        }).collect::<HashMap<String, String>>()
-                    }).unwrap_or_default();
+// mawk reads its input in whole blocks, so that it would wait for more input
+// than a worker is sent, unless it runs interactively. Other awks do not know
+// the option, so each command is asked once whether it accepts it.
+fn get_awk_options(program: &str) -> Vec<String> {
+    let mut awk_options = AWK_OPTIONS.lock().unwrap();
+
+    awk_options
+        .entry(program.to_string())
+        .or_insert_with(|| {
+            let interactive = vec!["-W".to_string(), "interactive".to_string()];
+
+            let accepted = Command::new(program)
+                .args(&interactive)
+                .arg("BEGIN {}")
+                .stdin(Stdio::null())
+                .output()
+                .map(|output| output.status.success() && output.stderr.is_empty())
+                .unwrap_or(false);
+
+            if accepted { interactive } else { Vec::new() }
+        })
+        .clone()
+}
 
-                (transformed_element, transformed_attributes)
+fn run_subprocess(runtime: &Runtime, script: &str, input: &Value) -> Result<String, Errors> {
+    log::trace!("In run_subprocess");
+
+    let (program, args, timeout) = {
+        let lock = read_lock!(CONFIG);
+        let (program, args) = match runtime {
+            Runtime::NodeJS => (
+                lock.runtimes.node_command.clone(),
+                vec!["-e".to_string(), NODE_WORKER.replacen("{script}", script, 1)],
+            ),
+            Runtime::Python => {
+                let script: Vec<String> = script.lines().map(|line| format!("    {}", line)).collect();
+
+                (
+                    lock.runtimes.python_command.clone(),
+                    vec!["-c".to_string(), PYTHON_WORKER.replacen("{script}", &script.join("\n"), 1)],
+                )
             },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
-        }
+            Runtime::AWK => {
+                let mut args = get_awk_options(&lock.runtimes.awk_command);
+                args.push(AWK_WORKER.replacen("{script}", script, 1));
+
+                (lock.runtimes.awk_command.clone(), args)
+            },
+            Runtime::QuickJS => return Err(Errors::TransformationError(
+                "QuickJS does not run as a subprocess".to_string()
+            )),
+        };
+
+        (program, args, Duration::from_millis(lock.runtimes.timeout_ms))
+    };
+
+    let line = request_from_worker(&program, &args, &input.to_string(), timeout)?;
+
+    if let Runtime::AWK = runtime {
+        return Ok(line);
     }
+
+    let response: Value = serde_json::from_str(&line)?;
+
+    if let Some(error) = response.get("error") {
+        return Err(Errors::TransformationError(format!(
+            "{} script failed: {}",
+            program,
+            error.as_str().unwrap_or_default()
+        )));
+    }
+
+    let output = response.get("output").ok_or_else(|| {
+        Errors::TransformationError(format!("{} script did not return an output", program))
+    })?;
+
+    Ok(output.to_string())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FieldMetadata {
+    /// Share of the sampled LLM answers that agreed on the decision
+    #[serde(default = "get_default_confidence")]
+    pub confidence: f64,
+    /// Set when too few samples agreed for the decision to be trusted
+    #[serde(default)]
+    pub needs_review: bool,
+    /// Set when the content the decision was made from reads like
+    /// instructions to the LLM
+    #[serde(default)]
+    pub suspicious_content: bool,
+    /// Set when the field was found unmeaningful. Eliminated fields are only
+    /// kept when their decision needs review, and are never applied.
+    #[serde(default)]
+    pub eliminated: bool,
+}
+
+impl FieldMetadata {
+    pub fn from_confidence(confidence: f64) -> Self {
+        FieldMetadata {
+            confidence,
+            needs_review: confidence < read_lock!(CONFIG).llm.review_confidence_threshold,
+            suspicious_content: false,
+            eliminated: false,
+        }
+    }
+
+    pub fn with_suspicious_content(self, suspicious_content: bool) -> Self {
+        FieldMetadata {
+            needs_review: self.needs_review || suspicious_content,
+            suspicious_content,
+            ..self
+        }
+    }
+}
+
+fn get_default_confidence() -> f64 {
+    1.0
 }
 
 
@@ -220,3 +412,43 @@ impl FieldTransformation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_js_literal_escapes_line_separators() {
+        let literal = to_js_literal(&json!({ "text": "a\u{2028}b\u{2029}c" })).unwrap();
+
+        assert_eq!(literal, r#"{"text":"a\u2028b\u2029c"}"#);
+
+        let parsed: Value = serde_json::from_str(&literal).unwrap();
+
+        assert_eq!(parsed["text"], "a\u{2028}b\u{2029}c");
+    }
+
+    #[test]
+    fn to_js_literal_keeps_json_escapes() {
+        let value = json!({ "text": "\"quoted\"\n</script>\\" });
+
+        assert_eq!(to_js_literal(&value).unwrap(), serde_json::to_string(&value).unwrap());
+    }
+
+    #[test]
+    fn subprocess_runtimes_answer_each_input() {
+        let transformation = XMLElementTransformation {
+            id: ID::new(),
+            description: "Renames every element to span".to_string(),
+            runtime: Runtime::AWK,
+            infix: r#"{ count++; print "{\"element\": \"span\", \"attributes\": {\"count\": \"" count "\"}}" }"#.to_string(),
+        };
+
+        let first = transformation.transform("div".to_string(), HashMap::new()).unwrap();
+        let second = transformation.transform("p".to_string(), HashMap::new()).unwrap();
+
+        // Both inputs were answered by the same worker
+        assert_eq!(first, (Some("span".to_string()), HashMap::from([("count".to_string(), "1".to_string())])));
+        assert_eq!(second, (Some("span".to_string()), HashMap::from([("count".to_string(), "2".to_string())])));
+    }
+}
@@ -0,0 +1,442 @@
+use serde::{Serialize, Deserialize};
+use xmltree::XMLNode;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::document::Document;
+use crate::document_node::DocumentNode;
+use crate::profile::Profile;
+use crate::provider::Provider;
+use crate::transformation::{XMLElementTransformation, HashTransformation};
+
+/// Outcome of running a profile's transformations against a single node of
+/// the original document. Paths refer to the untransformed document so that
+/// reports produced by different scripts can be compared node by node.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NodeReport {
+    pub path: String,
+    pub element: Option<String>,
+    pub transformed_element: Option<String>,
+    pub dropped: bool,
+    pub dropped_attributes: Vec<String>,
+    pub hash_items: Vec<String>,
+    pub hash: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TransformationSummary {
+    pub nodes: usize,
+    pub dropped_elements: usize,
+    pub dropped_attributes: usize,
+    pub distinct_hashes: usize,
+    pub errors: usize,
+}
+
+/// Report of what a profile's XML element and hash transformations do to a
+/// sample document, without running the rest of the pipeline
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransformationReport {
+    pub profile_id: ID,
+    pub summary: TransformationSummary,
+    pub nodes: Vec<NodeReport>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NodeChange {
+    /// The node is only reached by one of the two reports, because an
+    /// ancestor was dropped by the other
+    Added(String),
+    Removed(String),
+    Dropped(String),
+    Restored(String),
+    AttributesChanged {
+        path: String,
+        dropped: Vec<String>,
+        restored: Vec<String>,
+    },
+    HashItemsChanged {
+        path: String,
+        before: Vec<String>,
+        after: Vec<String>,
+    },
+    ErrorChanged {
+        path: String,
+        before: Option<String>,
+        after: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransformationReportDiff {
+    pub before: TransformationSummary,
+    pub after: TransformationSummary,
+    pub changes: Vec<NodeChange>,
+}
+
+impl TransformationReport {
+    pub fn from_profile(profile: &Profile, text: String) -> Result<Self, Errors> {
+        log::trace!("In from_profile");
+
+        let xml_element_transformation = profile.xml_element_transformation.as_ref().ok_or_else(|| {
+            Errors::IncompleteProfile("XML element transformation is missing".to_string())
+        })?;
+        let hash_transformation = profile.hash_transformation.as_ref().ok_or_else(|| {
+            Errors::IncompleteProfile("hash transformation is missing".to_string())
+        })?;
+
+        let document = Document::from_string(text, &None)?;
+
+        let mut report = TransformationReport::run(
+            &document,
+            xml_element_transformation,
+            hash_transformation,
+        )?;
+        report.profile_id = profile.id.clone();
+
+        Ok(report)
+    }
+
+    pub fn run(
+        document: &Document,
+        xml_element_transformation: &XMLElementTransformation,
+        hash_transformation: &HashTransformation,
+    ) -> Result<Self, Errors> {
+        log::trace!("In run");
+
+        let root = document.get_document_node()?;
+        let root_path = match root.get_xml_node() {
+            XMLNode::Element(element) => format!("/{}", element.name),
+            _ => return Err(Errors::UnexpectedDocumentType),
+        };
+
+        let mut nodes = Vec::new();
+
+        // The root is traversed without being transformed, as in the pipeline
+        report_node(
+            &root,
+            root_path,
+            None,
+            xml_element_transformation,
+            hash_transformation,
+            &mut nodes,
+        );
+
+        Ok(TransformationReport {
+            profile_id: ID::new(),
+            summary: summarize(&nodes),
+            nodes,
+        })
+    }
+
+    pub fn diff(&self, other: &TransformationReport) -> TransformationReportDiff {
+        log::trace!("In diff");
+
+        let before: HashMap<&str, &NodeReport> = self.nodes
+            .iter()
+            .map(|node| (node.path.as_str(), node))
+            .collect();
+        let after: HashMap<&str, &NodeReport> = other.nodes
+            .iter()
+            .map(|node| (node.path.as_str(), node))
+            .collect();
+
+        let mut changes = Vec::new();
+
+        for node in self.nodes.iter() {
+            match after.get(node.path.as_str()) {
+                Some(other_node) => diff_node(node, other_node, &mut changes),
+                None => changes.push(NodeChange::Removed(node.path.clone())),
+            }
+        }
+
+        for node in other.nodes.iter() {
+            if !before.contains_key(node.path.as_str()) {
+                changes.push(NodeChange::Added(node.path.clone()));
+            }
+        }
+
+        TransformationReportDiff {
+            before: self.summary.clone(),
+            after: other.summary.clone(),
+            changes,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Errors> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl TransformationReportDiff {
+    pub fn to_json(&self) -> Result<String, Errors> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Finds the profile the pipeline would use for a document, falling back to
+/// the default profile. Nothing is saved to the provider.
+pub async fn get_profile_for_text<P: Provider>(
+    provider: Arc<P>,
+    text: String,
+) -> Result<Profile, Errors> {
+    log::trace!("In get_profile_for_text");
+
+    let document = Document::from_string(text, &None)?;
+    let features = document.get_features().ok_or(Errors::UnexpectedDocumentType)?;
+
+    match provider.get_profile(&features).await? {
+        Some(profile) => Ok(profile),
+        None => {
+            log::info!("No profile matches the document, using the default profile");
+            Ok(Profile::default_profile(&features))
+        }
+    }
+}
+
+/// Copies a profile, replacing the scripts of its transformations. The
+/// replaced transformations get new ids, as they are no longer the originals.
+pub fn replace_scripts(
+    profile: &Profile,
+    xml_element_script: Option<String>,
+    hash_script: Option<String>,
+) -> Profile {
+    let mut profile = profile.clone();
+
+    if let (Some(script), Some(transformation)) = (xml_element_script, profile.xml_element_transformation.as_mut()) {
+        transformation.id = ID::new();
+        transformation.infix = script;
+    }
+
+    if let (Some(script), Some(transformation)) = (hash_script, profile.hash_transformation.as_mut()) {
+        transformation.id = ID::new();
+        transformation.infix = script;
+    }
+
+    profile
+}
+
+fn report_node(
+    document_node: &DocumentNode,
+    path: String,
+    element: Option<String>,
+    xml_element_transformation: &XMLElementTransformation,
+    hash_transformation: &HashTransformation,
+    nodes: &mut Vec<NodeReport>,
+) {
+    let transformed_element = match document_node.get_xml_node() {
+        XMLNode::Element(element_node) => Some(element_node.name.clone()),
+        _ => None,
+    };
+
+    let mut node_report = NodeReport {
+        path: path.clone(),
+        element: element.or_else(|| transformed_element.clone()),
+        transformed_element,
+        dropped: false,
+        dropped_attributes: Vec::new(),
+        hash_items: Vec::new(),
+        hash: None,
+        error: None,
+    };
+
+    match hash_transformation.get_hash_items(document_node.get_fields()) {
+        Ok(hash_items) => {
+            let mut hash = Hash::from_items(hash_items.clone());
+            hash.finalize();
+
+            node_report.hash_items = hash_items;
+            node_report.hash = hash.to_string();
+        },
+        Err(err) => node_report.error = Some(err.to_string()),
+    }
+
+    nodes.push(node_report);
+
+    let children = match document_node.get_xml_node() {
+        XMLNode::Element(element_node) => &element_node.children,
+        _ => return,
+    };
+
+    let mut sibling_counts: HashMap<String, usize> = HashMap::new();
+
+    for child in children.iter() {
+        let (name, original_attributes): (String, HashSet<String>) = match child {
+            XMLNode::Element(child_element) => (
+                child_element.name.clone(),
+                child_element.attributes.keys().cloned().collect(),
+            ),
+            XMLNode::Text(_) => ("text()".to_string(), HashSet::new()),
+            _ => continue,
+        };
+
+        let count = sibling_counts.entry(name.clone()).or_insert(0);
+        *count += 1;
+
+        let child_path = format!("{}/{}[{}]", path, name, count);
+        let child_element = match child {
+            XMLNode::Element(_) => Some(name),
+            _ => None,
+        };
+
+        match DocumentNode::from_transformations(child.clone(), xml_element_transformation.clone()) {
+            Ok(Some(transformed_node)) => {
+                let kept_attributes: HashSet<String> = match transformed_node.get_xml_node() {
+                    XMLNode::Element(element_node) => element_node.attributes.keys().cloned().collect(),
+                    _ => HashSet::new(),
+                };
+
+                let first = nodes.len();
+
+                report_node(
+                    &transformed_node,
+                    child_path,
+                    child_element,
+                    xml_element_transformation,
+                    hash_transformation,
+                    nodes,
+                );
+
+                let mut dropped_attributes: Vec<String> = original_attributes
+                    .difference(&kept_attributes)
+                    .cloned()
+                    .collect();
+                dropped_attributes.sort();
+
+                nodes[first].dropped_attributes = dropped_attributes;
+            },
+            Ok(None) => {
+                nodes.push(NodeReport {
+                    path: child_path,
+                    element: child_element,
+                    transformed_element: None,
+                    dropped: true,
+                    dropped_attributes: Vec::new(),
+                    hash_items: Vec::new(),
+                    hash: None,
+                    error: None,
+                });
+            },
+            Err(err) => {
+                nodes.push(NodeReport {
+                    path: child_path,
+                    element: child_element,
+                    transformed_element: None,
+                    dropped: false,
+                    dropped_attributes: Vec::new(),
+                    hash_items: Vec::new(),
+                    hash: None,
+                    error: Some(err.to_string()),
+                });
+            },
+        }
+    }
+}
+
+fn diff_node(before: &NodeReport, after: &NodeReport, changes: &mut Vec<NodeChange>) {
+    if !before.dropped && after.dropped {
+        changes.push(NodeChange::Dropped(before.path.clone()));
+        return;
+    }
+
+    if before.dropped && !after.dropped {
+        changes.push(NodeChange::Restored(before.path.clone()));
+        return;
+    }
+
+    if before.error != after.error {
+        changes.push(NodeChange::ErrorChanged {
+            path: before.path.clone(),
+            before: before.error.clone(),
+            after: after.error.clone(),
+        });
+    }
+
+    let before_dropped: HashSet<&String> = before.dropped_attributes.iter().collect();
+    let after_dropped: HashSet<&String> = after.dropped_attributes.iter().collect();
+
+    if before_dropped != after_dropped {
+        let mut dropped: Vec<String> = after_dropped.difference(&before_dropped).map(|s| s.to_string()).collect();
+        let mut restored: Vec<String> = before_dropped.difference(&after_dropped).map(|s| s.to_string()).collect();
+        dropped.sort();
+        restored.sort();
+
+        changes.push(NodeChange::AttributesChanged {
+            path: before.path.clone(),
+            dropped,
+            restored,
+        });
+    }
+
+    if before.hash_items != after.hash_items {
+        changes.push(NodeChange::HashItemsChanged {
+            path: before.path.clone(),
+            before: before.hash_items.clone(),
+            after: after.hash_items.clone(),
+        });
+    }
+}
+
+fn summarize(nodes: &[NodeReport]) -> TransformationSummary {
+    let distinct_hashes: HashSet<&String> = nodes
+        .iter()
+        .filter_map(|node| node.hash.as_ref())
+        .collect();
+
+    TransformationSummary {
+        nodes: nodes.len(),
+        dropped_elements: nodes.iter().filter(|node| node.dropped).count(),
+        dropped_attributes: nodes.iter().map(|node| node.dropped_attributes.len()).sum(),
+        distinct_hashes: distinct_hashes.len(),
+        errors: nodes.iter().filter(|node| node.error.is_some()).count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE: &str = concat!(
+        r#"<html><body><div class="note" style="color: red">"#,
+        r#"<em>Hidden</em><script>run()</script><p>Kept</p>"#,
+        r#"</div></body></html>"#,
+    );
+
+    #[test]
+    fn diff_reports_what_a_script_changes() {
+        let profile = Profile::default_profile(&HashMap::new());
+        let changed_profile = replace_scripts(
+            &profile,
+            Some(r#"if (element === "em") element = null; delete attributes["class"];"#.to_string()),
+            None,
+        );
+
+        let before = TransformationReport::from_profile(&profile, NOTE.to_string()).unwrap();
+        let after = TransformationReport::from_profile(&changed_profile, NOTE.to_string()).unwrap();
+        let diff = before.diff(&after);
+
+        let string = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(diff.changes, vec![
+            NodeChange::AttributesChanged {
+                path: "/html/body[1]/div[1]".to_string(),
+                dropped: string(&["class"]),
+                restored: string(&["style"]),
+            },
+            NodeChange::HashItemsChanged {
+                path: "/html/body[1]/div[1]".to_string(),
+                before: string(&["class", "tag"]),
+                after: string(&["style", "tag"]),
+            },
+            NodeChange::Dropped("/html/body[1]/div[1]/em[1]".to_string()),
+            NodeChange::Removed("/html/body[1]/div[1]/em[1]/text()[1]".to_string()),
+            NodeChange::Restored("/html/body[1]/div[1]/script[1]".to_string()),
+            NodeChange::Added("/html/body[1]/div[1]/script[1]/text()[1]".to_string()),
+        ]);
+
+        assert_eq!(diff.before.dropped_elements, 1);
+        assert_eq!(diff.after.dropped_elements, 1);
+        assert!(before.diff(&before).changes.is_empty());
+    }
+}
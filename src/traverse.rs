@@ -14,6 +14,7 @@ use crate::profile::Profile;
 use crate::provider::Provider;
 use crate::json_node::JsonNode;
 use crate::basis_network::{NetworkRelationship};
+use crate::network_analysis::is_nested_instance;
 use crate::schema_node::SchemaNode;
 
 pub fn traverse_document(
@@ -41,15 +42,22 @@ pub fn traverse_document(
         contexts: &mut HashMap<ID, Arc<Context>>,
         parents: Vec<Arc<RwLock<GraphNode>>>,
         profile: &Profile,
-    ) -> Arc<RwLock<GraphNode>> {
+    ) -> Result<Arc<RwLock<GraphNode>>, Errors> {
+        let meaningful_fields = profile.meaningful_fields.clone().ok_or_else(|| {
+            Errors::IncompleteProfile("meaningful fields are missing".to_string())
+        })?;
+        let hash_transformation = profile.hash_transformation.as_ref().ok_or_else(|| {
+            Errors::IncompleteProfile("hash transformation is missing".to_string())
+        })?;
+
         let data_node = Arc::new(
             DataNode::new(
-                profile.meaningful_fields.clone().unwrap(),
-                &profile.hash_transformation.clone().unwrap(),
+                meaningful_fields,
+                hash_transformation,
                 read_lock!(document_node).get_fields(),
                 read_lock!(document_node).get_description(),
                 parent_lineage,
-            )
+            )?
         );
 
         let graph_node = Arc::new(RwLock::new(
@@ -74,8 +82,10 @@ pub fn traverse_document(
         contexts.insert(read_lock!(graph_node).id.clone(), Arc::clone(&context));
 
         {
-            let children: Vec<Arc<RwLock<GraphNode>>> = read_lock!(document_node)
-                .get_children(profile.xml_element_transformation.clone())
+            let document_children = read_lock!(document_node)
+                .get_children(profile.xml_element_transformation.clone())?;
+
+            let children: Vec<Arc<RwLock<GraphNode>>> = document_children
                 .into_iter()
                 .map(|child| {
                     recurse(
@@ -87,9 +97,9 @@ pub fn traverse_document(
                         profile
                     )
                 })
-                .collect();
+                .collect::<Result<_, _>>()?;
 
-            let mut write_lock = graph_node.write().unwrap();
+            let mut write_lock = write_lock!(graph_node);
 
             let child_hashes: Vec<Hash> = children.iter()
                 .map(|child| read_lock!(child).hash.clone())
@@ -105,7 +115,7 @@ pub fn traverse_document(
             write_lock.children.extend(children);
         }
 
-        graph_node
+        Ok(graph_node)
     }
 
     let graph_root = recurse(
@@ -114,8 +124,8 @@ pub fn traverse_document(
         &Lineage::new(),
         &mut contexts,
         Vec::new(),
-        &profile
-    );
+        profile
+    )?;
 
     Ok((contexts, graph_root))
 }
@@ -128,7 +138,7 @@ pub fn traverse_meta_context(
 
     let lock = read_lock!(meta_context);
     let graph_root = lock.graph_root.clone().ok_or(Errors::GraphRootNotProvided)?;
-    let basis_graph = lock.basis_graph.clone().unwrap();
+    let basis_graph = lock.basis_graph.clone().ok_or(Errors::BasisGraphNotProvided)?;
 
 
 
@@ -150,14 +160,10 @@ pub fn traverse_meta_context(
         &mut result,
         &mut inner_schema,
         &root_schema_node.lineage,
+        None,
     )?;
 
-    let data = {
-        match serde_json::to_string(&result) {
-            Ok(json_string) => json_string,
-            Err(e) => panic!("Error serializing to JSON: {}", e),
-        }
-    };
+    let data = serde_json::to_string(&result)?;
 
     root_schema_node.properties = inner_schema;
 
@@ -183,12 +189,13 @@ fn process_network(
     result: &mut HashMap<String, Value>,
     schema: &mut HashMap<String, SchemaNode>,
     schema_lineage: &Lineage,
+    recursion: Option<&Graph>,
 ) -> Result<(), Errors> {
     log::trace!("In process_network");
 
     let contexts = {
         let lock = read_lock!(meta_context);
-        lock.contexts.clone().unwrap()
+        lock.contexts.clone().ok_or(Errors::ContextsNotProvided)?
     };
 
     let mut queue = VecDeque::new();
@@ -202,15 +209,17 @@ fn process_network(
             (read_lock.id.clone(), read_lock.children.clone())
         };
 
-        let context = contexts.get(&context_id).unwrap().clone();
+        let context = contexts.get(&context_id).ok_or(Errors::ContextsNotProvided)?.clone();
 
-        process_node(
+        if let Err(err) = process_node(
             meta_context.clone(),
             context.clone(),
             result,
             schema,
             schema_lineage,
-        )?;
+        ) {
+            record_diagnostic(&meta_context, Err(err).lineage(Stage::Traversal, &context.lineage));
+        }
 
         for (index, child) in children.iter().enumerate() {
             let child_id = {
@@ -222,16 +231,33 @@ fn process_network(
                 continue;
             }
 
+            if let Some(recursion) = recursion {
+                if is_nested_instance(recursion, child) {
+                    log::trace!("Found nested instance of recursive network");
+
+                    if let Err(err) = process_recursion(
+                        meta_context.clone(),
+                        child.clone(),
+                        result,
+                        schema,
+                        schema_lineage,
+                    ) {
+                        record_diagnostic(&meta_context, Err(err).stage(Stage::Traversal));
+                    }
+
+                    processed_child_ids.insert(child_id);
+                    continue;
+                }
+            }
+
             let child_subgraph_hash = {
                 let child_lock = read_lock!(child);
-                child_lock.subgraph_hash.clone()
+                child_lock.subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?
             };
 
             let maybe_basis_network = {
                 let lock = read_lock!(meta_context);
-                lock.get_basis_network_by_subgraph_hash(
-                    &child_subgraph_hash.to_string().unwrap()
-                ).expect("Could not get basis network by subgraph hash")
+                lock.get_basis_network_by_subgraph_hash(&child_subgraph_hash)?
             };
 
             if let Some(basis_network) = maybe_basis_network {
@@ -255,6 +281,11 @@ fn process_network(
                         _ => Vec::new(),
                     };
 
+                    let inner_recursion = match &basis_network.relationship {
+                        NetworkRelationship::Recursion(_) => Some(child),
+                        _ => recursion,
+                    };
+
                     for subsequent_child in children.iter().skip(index + 1) {
                         let subsequent_child_id = {
                             let subsequent_lock = read_lock!(subsequent_child);
@@ -267,33 +298,44 @@ fn process_network(
 
                         let subsequent_subgraph_hash = {
                             let subsequent_lock = read_lock!(subsequent_child);
-                            subsequent_lock.subgraph_hash.clone()
+                            subsequent_lock.subgraph_hash.to_string().ok_or(Errors::HashNotFinalized)?
                         };
 
-                        if associated_graphs.contains(&subsequent_subgraph_hash.to_string().unwrap()) {
-                            process_network(
+                        if associated_graphs.contains(&subsequent_subgraph_hash) {
+                            if let Err(err) = process_network(
                                 meta_context.clone(),
                                 subsequent_child.clone(),
                                 &mut inner_result,
                                 &mut inner_schema,
                                 &schema_node.lineage,
-                            )?;
-
-                            associated_graphs.retain(|item| item != &subsequent_subgraph_hash.to_string().unwrap());
+                                inner_recursion,
+                            ) {
+                                record_diagnostic(
+                                    &meta_context,
+                                    Err(err).subgraph_hash(Stage::Traversal, &subsequent_subgraph_hash)
+                                );
+                            }
+
+                            associated_graphs.retain(|item| item != &subsequent_subgraph_hash);
                             processed_child_ids.insert(subsequent_child_id);
                         }
                     }
 
-                    process_network(
+                    if let Err(err) = process_network(
                         meta_context.clone(),
                         child.clone(),
                         &mut inner_result,
                         &mut inner_schema,
                         &schema_node.lineage,
-                    )?;
+                        inner_recursion,
+                    ) {
+                        record_diagnostic(
+                            &meta_context,
+                            Err(err).subgraph_hash(Stage::Traversal, &child_subgraph_hash)
+                        );
+                    }
 
-                    let inner_result_value = serde_json::to_value(inner_result)
-                        .expect("Failed to serialize inner result");
+                    let inner_result_value = serde_json::to_value(inner_result)?;
 
                     if let Some(existing_object) = result.get_mut(&object_name) {
                         if let Value::Array(ref mut arr) = existing_object {
@@ -305,8 +347,9 @@ fn process_network(
                             ]);
                         }
 
-                        let mut existing_schema_node = schema.get_mut(&object_name).unwrap();
-                        existing_schema_node.data_type = "array".to_string();
+                        if let Some(existing_schema_node) = schema.get_mut(&object_name) {
+                            existing_schema_node.data_type = "array".to_string();
+                        }
                     } else {
                         schema_node.properties = inner_schema;
                         schema.insert(object_name.clone(), schema_node);
@@ -326,6 +369,54 @@ fn process_network(
     Ok(())
 }
 
+fn process_recursion(
+    meta_context: Arc<RwLock<MetaContext>>,
+    graph: Graph,
+    result: &mut HashMap<String, Value>,
+    schema: &mut HashMap<String, SchemaNode>,
+    schema_lineage: &Lineage,
+) -> Result<(), Errors> {
+    log::trace!("In process_recursion");
+
+    let schema_node = schema.entry("children".to_string()).or_insert_with(|| {
+        SchemaNode::new(
+            "children",
+            "Nested items of the same type as the containing object",
+            schema_lineage,
+            "array"
+        )
+    });
+    let children_lineage = schema_node.lineage.clone();
+
+    let mut inner_result: HashMap<String, Value> = HashMap::new();
+    let mut inner_schema: HashMap<String, SchemaNode> = HashMap::new();
+
+    // Instances nested further are compared with this one
+    process_network(
+        meta_context.clone(),
+        graph.clone(),
+        &mut inner_result,
+        &mut inner_schema,
+        &children_lineage,
+        Some(&graph),
+    )?;
+
+    let inner_result_value = serde_json::to_value(inner_result)?;
+
+    if let Some(schema_node) = schema.get_mut("children") {
+        schema_node.properties.extend(inner_schema);
+    }
+
+    match result.get_mut("children") {
+        Some(Value::Array(ref mut arr)) => arr.push(inner_result_value),
+        _ => {
+            result.insert("children".to_string(), json!(vec![inner_result_value]));
+        }
+    }
+
+    Ok(())
+}
+
 fn process_node(
     meta_context: Arc<RwLock<MetaContext>>,
     context: Arc<Context>,
@@ -337,19 +428,23 @@ fn process_node(
 
     let maybe_basis_node = {
         let lock = read_lock!(meta_context);
-        lock.get_basis_node_by_lineage(&context.lineage)
-            .expect("Could not get basis node by lineage")
+        lock.get_basis_node_by_lineage(&context.lineage)?
     };
 
     if let Some(basis_node) = maybe_basis_node {
-        let json_nodes: Vec<JsonNode> = basis_node.transformations
-            .clone()
-            .into_iter()
-            .map(|transformation| {
-                transformation.transform(Arc::clone(&context.data_node))
-                    .expect("Could not transform data node")
-            })
-            .collect();
+        let mut json_nodes: Vec<JsonNode> = Vec::new();
+
+        for transformation in basis_node.transformations.iter() {
+            match transformation.transform(Arc::clone(&context.data_node)) {
+                Ok(json_node) => json_nodes.push(json_node),
+                Err(err) => {
+                    record_diagnostic(
+                        &meta_context,
+                        Err(err).lineage(Stage::Traversal, &context.lineage)
+                    );
+                }
+            }
+        }
 
         for json_node in json_nodes.into_iter() {
             let json = json_node.json;
@@ -388,3 +483,9 @@ fn process_node(
 
     Ok(())
 }
+
+fn record_diagnostic(meta_context: &Arc<RwLock<MetaContext>>, result: Result<(), Errors>) {
+    if let Err(err) = result {
+        read_lock!(meta_context).add_diagnostic(Diagnostic::new(Stage::Traversal, err));
+    }
+}
@@ -1,5 +1,38 @@
+use std::fmt;
+use std::sync::Arc;
 use tokio::task::JoinError;
 
+use crate::lineage::Lineage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Analysis,
+    Traversal,
+    BasisGraph,
+    BasisNode,
+    BasisNetwork,
+    LLM,
+    Provider,
+    Normalization,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Analysis => "analysis",
+            Stage::Traversal => "traversal",
+            Stage::BasisGraph => "basis graph",
+            Stage::BasisNode => "basis node",
+            Stage::BasisNetwork => "basis network",
+            Stage::LLM => "LLM",
+            Stage::Provider => "provider",
+            Stage::Normalization => "normalization",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Errors {
     FileReadError,
@@ -17,11 +50,212 @@ pub enum Errors {
     ProfileNotProvided,
     ContextsNotProvided,
     BasisGraphNotProvided,
+    ExtractionPlanParseError,
+    ExtractionPlanMismatch,
+    TaskJoinError(Arc<JoinError>),
+    IoError(Arc<std::io::Error>),
+    YamlError(Arc<serde_yaml::Error>),
+    JsonError(Arc<serde_json::Error>),
+    LLMError(String),
+    BudgetExceeded(String),
+    TransformationError(String),
+    IncompleteProfile(String),
+    BasisNodesNotProvided,
+    BasisNetworksNotProvided,
+    HashNotFinalized,
+    Context {
+        stage: Stage,
+        lineage: Option<String>,
+        subgraph_hash: Option<String>,
+        source: Box<Errors>,
+    },
+}
+
+impl Errors {
+    pub fn stage(&self) -> Option<Stage> {
+        match self {
+            Errors::Context { stage, .. } => Some(*stage),
+            _ => None,
+        }
+    }
+
+    /// The innermost error, with all pipeline context removed
+    pub fn root_cause(&self) -> &Errors {
+        match self {
+            Errors::Context { source, .. } => source.root_cause(),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Errors::FileReadError => write!(f, "could not read file"),
+            Errors::FileInputError => write!(f, "could not read input file"),
+            Errors::FileOutputError => write!(f, "could not write output file"),
+            Errors::DocumentNotProvided => write!(f, "document not provided"),
+            Errors::UnexpectedDocumentType => write!(f, "unexpected document type"),
+            Errors::UnexpectedError => write!(f, "unexpected error"),
+            Errors::XmlParseError => write!(f, "could not parse XML"),
+            Errors::PathConversionError => write!(f, "could not convert path"),
+            Errors::YamlParseError => write!(f, "could not parse YAML"),
+            Errors::FetchUrlError(message) => write!(f, "could not fetch URL: {}", message),
+            Errors::FieldTransformationFieldNotFound => write!(f, "field transformation refers to a missing field"),
+            Errors::GraphRootNotProvided => write!(f, "graph root not provided"),
+            Errors::ProfileNotProvided => write!(f, "profile not provided"),
+            Errors::ContextsNotProvided => write!(f, "contexts not provided"),
+            Errors::BasisGraphNotProvided => write!(f, "basis graph not provided"),
+            Errors::ExtractionPlanParseError => write!(f, "could not parse extraction plan"),
+            Errors::ExtractionPlanMismatch => write!(f, "extraction plan does not match document"),
+            Errors::TaskJoinError(err) => write!(f, "task failed: {}", err),
+            Errors::IoError(err) => write!(f, "I/O error: {}", err),
+            Errors::YamlError(err) => write!(f, "YAML error: {}", err),
+            Errors::JsonError(err) => write!(f, "JSON error: {}", err),
+            Errors::LLMError(message) => write!(f, "LLM request failed: {}", message),
+            Errors::BudgetExceeded(message) => write!(f, "LLM budget exceeded: {}", message),
+            Errors::TransformationError(message) => write!(f, "transformation failed: {}", message),
+            Errors::IncompleteProfile(message) => write!(f, "profile is incomplete: {}", message),
+            Errors::BasisNodesNotProvided => write!(f, "basis nodes not provided"),
+            Errors::BasisNetworksNotProvided => write!(f, "basis networks not provided"),
+            Errors::HashNotFinalized => write!(f, "hash has not been finalized"),
+            Errors::Context { stage, lineage, subgraph_hash, source } => {
+                write!(f, "{} failed", stage)?;
+
+                if let Some(lineage) = lineage {
+                    write!(f, " (lineage {})", lineage)?;
+                }
+
+                if let Some(subgraph_hash) = subgraph_hash {
+                    write!(f, " (subgraph {})", subgraph_hash)?;
+                }
+
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Errors {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Errors::TaskJoinError(err) => Some(err.as_ref()),
+            Errors::IoError(err) => Some(err.as_ref()),
+            Errors::YamlError(err) => Some(err.as_ref()),
+            Errors::JsonError(err) => Some(err.as_ref()),
+            Errors::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
 }
 
 impl From<JoinError> for Errors {
-    fn from(_: JoinError) -> Self {
-        Errors::UnexpectedError
+    fn from(err: JoinError) -> Self {
+        Errors::TaskJoinError(Arc::new(err))
+    }
+}
+
+impl From<std::io::Error> for Errors {
+    fn from(err: std::io::Error) -> Self {
+        Errors::IoError(Arc::new(err))
+    }
+}
+
+impl From<serde_yaml::Error> for Errors {
+    fn from(err: serde_yaml::Error) -> Self {
+        Errors::YamlError(Arc::new(err))
+    }
+}
+
+impl From<serde_json::Error> for Errors {
+    fn from(err: serde_json::Error) -> Self {
+        Errors::JsonError(Arc::new(err))
+    }
+}
+
+/// A failure that was recorded and skipped so that the rest of the run
+/// could continue
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub stage: Stage,
+    pub error: Errors,
+}
+
+impl Diagnostic {
+    pub fn new(stage: Stage, error: Errors) -> Self {
+        Diagnostic {
+            stage,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error.stage() {
+            Some(_) => write!(f, "{}", self.error),
+            None => write!(f, "{} failed: {}", self.stage, self.error),
+        }
+    }
+}
+
+/// Attaches pipeline context to errors as they propagate
+pub trait ErrorContext<T> {
+    fn stage(self, stage: Stage) -> Result<T, Errors>;
+    fn lineage(self, stage: Stage, lineage: &Lineage) -> Result<T, Errors>;
+    fn subgraph_hash(self, stage: Stage, subgraph_hash: &str) -> Result<T, Errors>;
+}
+
+impl<T> ErrorContext<T> for Result<T, Errors> {
+    fn stage(self, stage: Stage) -> Result<T, Errors> {
+        self.map_err(|err| wrap_error(err, stage, None, None))
+    }
+
+    fn lineage(self, stage: Stage, lineage: &Lineage) -> Result<T, Errors> {
+        self.map_err(|err| wrap_error(err, stage, Some(lineage.to_string()), None))
+    }
+
+    fn subgraph_hash(self, stage: Stage, subgraph_hash: &str) -> Result<T, Errors> {
+        self.map_err(|err| wrap_error(err, stage, None, Some(subgraph_hash.to_string())))
+    }
+}
+
+fn wrap_error(
+    err: Errors,
+    stage: Stage,
+    lineage: Option<String>,
+    subgraph_hash: Option<String>,
+) -> Errors {
+    // An error already attributed to this stage is only enriched, so that
+    // nested calls within a stage don't repeat it.
+    if let Errors::Context { stage: existing, lineage: existing_lineage, subgraph_hash: existing_hash, source } = err {
+        if existing == stage {
+            return Errors::Context {
+                stage,
+                lineage: existing_lineage.or(lineage),
+                subgraph_hash: existing_hash.or(subgraph_hash),
+                source,
+            };
+        }
+
+        return Errors::Context {
+            stage,
+            lineage,
+            subgraph_hash,
+            source: Box::new(Errors::Context {
+                stage: existing,
+                lineage: existing_lineage,
+                subgraph_hash: existing_hash,
+                source,
+            }),
+        };
+    }
+
+    Errors::Context {
+        stage,
+        lineage,
+        subgraph_hash,
+        source: Box::new(err),
     }
 }
 
@@ -0,0 +1,271 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+
+// Workers beyond this many are stopped, least recently used first
+const MAX_WORKERS: usize = 16;
+
+/// A long-lived subprocess that answers each line written to its stdin with
+/// a line on its stdout. Its pipes are served by threads of their own, so a
+/// worker that stops reading or writing cannot block past the timeout.
+struct Worker {
+    program: String,
+    child: Child,
+    input: Sender<String>,
+    output: Receiver<String>,
+    last_error: Arc<Mutex<String>>,
+    stderr_reader: Option<JoinHandle<()>>,
+    last_used: Instant,
+}
+
+impl Worker {
+    fn start(program: &str, args: &[String]) -> Result<Self, Errors> {
+        log::trace!("In start");
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                Errors::TransformationError(format!("Could not start {}: {}", program, err))
+            })?;
+
+        let mut stdin = child.stdin.take().ok_or(Errors::UnexpectedError)?;
+        let stdout = child.stdout.take().ok_or(Errors::UnexpectedError)?;
+        let stderr = child.stderr.take().ok_or(Errors::UnexpectedError)?;
+
+        let (input, input_receiver) = mpsc::channel::<String>();
+        let (output_sender, output) = mpsc::channel::<String>();
+        let last_error = Arc::new(Mutex::new(String::new()));
+
+        // Dropping the sender ends this thread, which closes stdin and lets
+        // the worker exit
+        thread::spawn(move || {
+            for line in input_receiver {
+                if let Err(err) = stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush()) {
+                    log::debug!("Could not write input to worker: {}", err);
+                    break;
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if output_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr_program = program.to_string();
+        let stderr_last_error = Arc::clone(&last_error);
+        let stderr_reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                log::debug!("{} wrote to stderr: {}", stderr_program, line);
+                *stderr_last_error.lock().unwrap() = line;
+            }
+        });
+
+        Ok(Worker {
+            program: program.to_string(),
+            child,
+            input,
+            output,
+            last_error,
+            stderr_reader: Some(stderr_reader),
+            last_used: Instant::now(),
+        })
+    }
+
+    fn request(&mut self, line: &str, timeout: Duration) -> Result<String, Errors> {
+        log::trace!("In request");
+
+        self.last_used = Instant::now();
+
+        if self.input.send(format!("{}\n", line)).is_err() {
+            return Err(self.exited());
+        }
+
+        match self.output.recv_timeout(timeout) {
+            Ok(line) => Ok(line),
+            Err(RecvTimeoutError::Timeout) => Err(Errors::TransformationError(format!(
+                "{} did not finish within {}ms",
+                self.program,
+                timeout.as_millis()
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(self.exited()),
+        }
+    }
+
+    fn exited(&mut self) -> Errors {
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        let status = loop {
+            match self.child.try_wait() {
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                Ok(status) => break status,
+                Err(_) => break None,
+            }
+        };
+
+        let reason = match status {
+            Some(status) => {
+                // Everything the worker wrote to stderr has been read once
+                // it has exited
+                if let Some(stderr_reader) = self.stderr_reader.take() {
+                    let _ = stderr_reader.join();
+                }
+
+                format!("exited with {}", status)
+            },
+            None => "closed its output".to_string(),
+        };
+
+        Errors::TransformationError(format!(
+            "{} {}: {}",
+            self.program,
+            reason,
+            self.last_error.lock().unwrap()
+        ))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+type SharedWorker = Arc<Mutex<Option<Worker>>>;
+
+lazy_static! {
+    static ref WORKERS: Mutex<HashMap<(String, Vec<String>), SharedWorker>> = Mutex::new(HashMap::new());
+}
+
+fn get_worker(program: &str, args: &[String]) -> SharedWorker {
+    let mut workers = WORKERS.lock().unwrap();
+    let key = (program.to_string(), args.to_vec());
+
+    if let Some(worker) = workers.get(&key) {
+        return Arc::clone(worker);
+    }
+
+    if workers.len() >= MAX_WORKERS {
+        let least_recently_used = workers
+            .iter()
+            .filter_map(|(key, worker)| {
+                // A worker busy with a request counts as in use
+                let last_used = worker.try_lock().ok()?.as_ref().map(|worker| worker.last_used);
+
+                Some((key.clone(), last_used))
+            })
+            .min_by_key(|(_, last_used)| *last_used)
+            .map(|(key, _)| key);
+
+        if let Some(key) = least_recently_used {
+            log::debug!("Stopping the least recently used worker for {}", key.0);
+            workers.remove(&key);
+        }
+    }
+
+    let worker: SharedWorker = Arc::new(Mutex::new(None));
+    workers.insert(key, Arc::clone(&worker));
+
+    worker
+}
+
+/// Sends a line to the worker running a program with its arguments, starting
+/// it if need be, and returns the line it answers with. Requests to the same
+/// worker are answered one at a time. A worker that times out or exits is
+/// stopped, and started again by the next request.
+pub fn request_from_worker(
+    program: &str,
+    args: &[String],
+    line: &str,
+    timeout: Duration,
+) -> Result<String, Errors> {
+    log::trace!("In request_from_worker");
+
+    let worker = get_worker(program, args);
+    let mut worker = worker.lock().unwrap();
+
+    if worker.is_none() {
+        *worker = Some(Worker::start(program, args)?);
+    }
+
+    let result = worker.as_mut().ok_or(Errors::UnexpectedError)?.request(line, timeout);
+
+    if result.is_err() {
+        *worker = None;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn workers_answer_each_line() {
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(request_from_worker("cat", &[], "first", timeout).unwrap(), "first");
+        assert_eq!(request_from_worker("cat", &[], "second", timeout).unwrap(), "second");
+    }
+
+    #[test]
+    fn workers_are_reused_between_requests() {
+        let args = sh("count=0; while read line; do count=$((count + 1)); echo \"$count $line\"; done");
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(request_from_worker("sh", &args, "a", timeout).unwrap(), "1 a");
+        assert_eq!(request_from_worker("sh", &args, "b", timeout).unwrap(), "2 b");
+    }
+
+    #[test]
+    fn workers_that_time_out_are_restarted() {
+        let args = sh("while read line; do if [ \"$line\" = slow ]; then sleep 5; fi; echo \"$line\"; done");
+        let timeout = Duration::from_millis(200);
+
+        match request_from_worker("sh", &args, "slow", timeout) {
+            Err(Errors::TransformationError(message)) => assert_eq!(message, "sh did not finish within 200ms"),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+
+        assert_eq!(request_from_worker("sh", &args, "fast", timeout).unwrap(), "fast");
+    }
+
+    #[test]
+    fn workers_that_exit_report_their_errors() {
+        let args = sh("read line; echo \"cannot handle $line\" >&2; exit 3");
+
+        match request_from_worker("sh", &args, "input", Duration::from_secs(5)) {
+            Err(Errors::TransformationError(message)) => {
+                assert_eq!(message, "sh exited with exit status: 3: cannot handle input");
+            },
+            other => panic!("Expected the worker to exit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_programs_are_reported() {
+        let result = request_from_worker("parversion-missing-program", &[], "input", Duration::from_secs(1));
+
+        assert!(matches!(result, Err(Errors::TransformationError(message)) if message.starts_with("Could not start")));
+    }
+}